@@ -0,0 +1,71 @@
+// Instructions per second of `Rv32iProcessor::step`, on the example firmware
+// and on a synthetic ALU/load/store/branch loop, with and without the decode
+// cache.
+//
+//     cargo bench --bench step
+
+use rv32i_rs::modules::asm;
+use rv32i_rs::modules::rv32i_icache::DecodeCache;
+use rv32i_rs::modules::rv32i_processor::{Rv32iProcessor, StopConditions, StopReason};
+
+use std::time::{Duration, Instant};
+
+const LOOP: &str = "
+        li s0, 200000
+        la s1, buffer
+    loop:
+        lw t0, 0(s1)
+        addi t0, t0, 3
+        xor t1, t0, s0
+        slli t2, t1, 2
+        sw t2, 4(s1)
+        lbu t3, 5(s1)
+        sltu t4, t3, t0
+        add t0, t0, t4
+        sw t0, 0(s1)
+        addi s0, s0, -1
+        bnez s0, loop
+        li a7, 93
+        ecall
+    buffer:
+        .zero 16
+";
+
+fn measure(name: &str, mut make: impl FnMut() -> Rv32iProcessor) {
+    for cached in [false, true] {
+        let mut retired = 0u64;
+        let mut elapsed = Duration::ZERO;
+
+        while elapsed < Duration::from_secs(2) {
+            let mut processor = make();
+            if !cached {
+                processor.icache = DecodeCache::disabled();
+            }
+            let start = Instant::now();
+            let summary = processor.run(&StopConditions::default());
+            elapsed += start.elapsed();
+
+            assert!(matches!(
+                summary.reason,
+                StopReason::IdleLoop | StopReason::Exit(_)
+            ));
+            retired += summary.retired;
+        }
+
+        let per_second = retired as f64 / elapsed.as_secs_f64();
+        let cache = if cached { "cache" } else { "no cache" };
+        println!(
+            "{:<10} {:<10} {:>12.0} instructions/s",
+            name, cache, per_second
+        );
+    }
+}
+
+fn main() {
+    measure("example", || {
+        Rv32iProcessor::new_from_elf("example/riscv_asm.elf").unwrap()
+    });
+
+    let program = asm::assemble(LOOP).unwrap();
+    measure("loop", || Rv32iProcessor::new(program.words(), vec![]));
+}
@@ -1,42 +1,840 @@
-use rv32i_rs::modules::rv32i_processor::Rv32iProcessor;
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut cpu = Rv32iProcessor::new_from_elf("example/riscv_asm.elf")?;
+use rv32i_rs::modules::clint::{Clint, CLINT_SIZE};
+use rv32i_rs::modules::coredump;
+use rv32i_rs::modules::disasm::{self, ABI_NAMES};
+use rv32i_rs::modules::framebuffer::{Framebuffer, PixelFormat, FRAMEBUFFER_BASE};
+use rv32i_rs::modules::io_page::{self, IoPage, DEFAULT_LEDS, IO_BASE, IO_SIZE};
+use rv32i_rs::modules::loader::{self, ElfImage, DEFAULT_MEMORY_SIZE};
+use rv32i_rs::modules::plic::{Plic, PLIC_SIZE};
+use rv32i_rs::modules::rv32i_isa::Extensions;
+use rv32i_rs::modules::rv32i_processor::{Rv32iProcessor, StopConditions, StopReason};
+use rv32i_rs::modules::rv32i_timer::{CycleTime, TimeSource, WallClockTime};
+use rv32i_rs::modules::trace::CommitLog;
+use rv32i_rs::modules::uart::{Uart, UART_IRQ};
 
-    let n_instr = cpu.program.len();
-    // Some headroom is needed to run the program.
-    // The number of instructions don't take into account
-    // the instructions needed to perform a multiplication or a division for example
-    for _ in 0..n_instr * 10 {
-        cpu.exec();
+use std::cell::RefCell;
+use std::env;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::process::ExitCode;
+use std::rc::Rc;
+
+const USAGE: &str = "\
+Usage: rv32i <command> <image>[@ADDR] [options]
+
+Commands:
+  run      Run the program until it exits, faults or spins in an idle loop
+  disasm   Print an objdump-like listing of the executable segments
+  trace    Like run, logging every instruction in Spike's commit log format
+
+Images are ELF files, Intel HEX (.hex), S-records (.srec, .s19, .s28, .s37),
+or flat binaries (.bin) and readmemh word dumps (.prog, .mem) loaded at ADDR
+(default 0).
+
+Options:
+  -n, --max-instructions <N>  Stop after N instructions (trap entries count too)
+  -m, --memory <SIZE>         RAM size in bytes, K and M suffixes allowed (default 1M)
+  -e, --entry <ADDR|SYMBOL>   Start there instead of at the image entry point
+  -l, --load <FILE>[@ADDR]    Load another image, e.g. the .mem of a .prog
+      --isa <rv32i|rv32im>    Instruction set to decode (default rv32i)
+      --traps                 Deliver exceptions, ecall and ebreak through mtvec
+      --uart <ADDR>           Map a 16550 UART at ADDR (0x10000000 on QEMU's virt),
+                              connected to stdin and stdout
+      --uart-input <FILE>     Feed the UART from FILE instead of stdin
+      --uart-output <FILE>    Write the UART output to FILE instead of stdout
+      --clint <ADDR>          Map a CLINT at ADDR (0x2000000 on QEMU's virt) and
+                              deliver its timer and software interrupts
+      --mtime-cycles <N>      Advance mtime once every N cycles (default 1)
+      --mtime-hz <N>          Advance mtime N times per second of host time instead
+      --plic <ADDR>           Map a PLIC at ADDR (0xc000000 on QEMU's virt) driving
+                              external interrupts, with the UART on source 10
+      --io-page               Map learn-fpga's FemtoSOC IO page (LEDs, UART, buttons)
+                              at 0x400000
+      --leds                  Show the LEDs on stderr when they change (implies --io-page)
+      --buttons <FILE>        Set the buttons from FILE's `CYCLE VALUE` lines
+                              (implies --io-page)
+      --framebuffer <W>x<H>[:FORMAT][@ADDR]
+                              Map a W by H framebuffer at ADDR (default 0x30000000),
+                              FORMAT is rgb565 (default), xrgb8888 or gray8
+      --snapshot <FILE>       Save the framebuffer as PNG (or .ppm) when the run stops
+      --regs                  Print the registers when the run stops
+  -d, --dump <FILE>@<ADDR>:<LEN>
+                              Save memory when the run stops, as Intel HEX (.hex),
+                              readmemh (.mem, .prog) or a flat binary (.bin)
+      --core <FILE>           Save an ELF core dump when the run stops
+  -o, --output <FILE>         Write the trace to FILE instead of stdout
+  -h, --help                  Print this help
+
+Exit status: the guest's exit code (a0 of an exit ecall, a7 = 93), 0 when it
+stops in an idle loop or on ebreak, 124 when the instruction limit is hit,
+1 on faults and load errors, 2 on usage errors.";
+
+// Guest exit codes use the full 0-255 range, these can collide with them
+const EXIT_FAILURE: u8 = 1;
+const EXIT_USAGE: u8 = 2;
+const EXIT_LIMIT: u8 = 124;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Command {
+    Run,
+    Disasm,
+    Trace,
+}
+
+// How mtime advances
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Timebase {
+    // One tick every N cycles, deterministic
+    Cycles(u64),
+    // N ticks per second of host time
+    WallClock(u64),
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+struct FramebufferSpec {
+    width: u32,
+    height: u32,
+    format: PixelFormat,
+    addr: u32,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Options {
+    command: Command,
+    image: String,
+    load: Vec<String>,
+    max_instructions: Option<u64>,
+    memory_size: usize,
+    entry: Option<String>,
+    extensions: Extensions,
+    trap_exceptions: bool,
+    uart: Option<u32>,
+    uart_input: Option<String>,
+    uart_output: Option<String>,
+    clint: Option<u32>,
+    mtime: Timebase,
+    plic: Option<u32>,
+    io_page: bool,
+    show_leds: bool,
+    buttons: Option<String>,
+    framebuffer: Option<FramebufferSpec>,
+    snapshot: Option<String>,
+    dump_registers: bool,
+    dumps: Vec<String>,
+    core: Option<String>,
+    output: Option<String>,
+}
+
+fn main() -> ExitCode {
+    let args: Vec<String> = env::args().skip(1).collect();
+    if args.iter().any(|arg| arg == "-h" || arg == "--help") {
+        println!("{}", USAGE);
+        return ExitCode::SUCCESS;
+    }
+
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            eprintln!("rv32i: {}\n\n{}", message, USAGE);
+            return ExitCode::from(EXIT_USAGE);
+        }
+    };
+    match execute(&options) {
+        Ok(code) => ExitCode::from(code),
+        Err(e) => {
+            eprintln!("rv32i: {}", e);
+            ExitCode::from(EXIT_FAILURE)
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Options, String> {
+    let mut args = args.iter();
+    let command = match args.next().map(String::as_str) {
+        Some("run") => Command::Run,
+        Some("disasm") => Command::Disasm,
+        Some("trace") => Command::Trace,
+        Some(other) => return Err(format!("unknown command `{}`", other)),
+        None => return Err("missing command".to_string()),
+    };
+
+    let mut options = Options {
+        command,
+        image: String::new(),
+        load: Vec::new(),
+        max_instructions: None,
+        memory_size: DEFAULT_MEMORY_SIZE,
+        entry: None,
+        extensions: Extensions::rv32i(),
+        trap_exceptions: false,
+        uart: None,
+        uart_input: None,
+        uart_output: None,
+        clint: None,
+        mtime: Timebase::Cycles(1),
+        plic: None,
+        io_page: false,
+        show_leds: false,
+        buttons: None,
+        framebuffer: None,
+        snapshot: None,
+        dump_registers: false,
+        dumps: Vec::new(),
+        core: None,
+        output: None,
+    };
+
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .cloned()
+                .ok_or_else(|| format!("`{}` needs a value", arg))
+        };
+        match arg.as_str() {
+            "-n" | "--max-instructions" => {
+                let count = value()?;
+                options.max_instructions = Some(
+                    parse_number(&count)
+                        .ok_or_else(|| format!("invalid instruction count `{}`", count))?,
+                );
+            }
+            "-m" | "--memory" => {
+                let size = value()?;
+                options.memory_size =
+                    parse_size(&size).ok_or_else(|| format!("invalid memory size `{}`", size))?;
+            }
+            "-e" | "--entry" => options.entry = Some(value()?),
+            "-l" | "--load" => options.load.push(value()?),
+            "--isa" => {
+                options.extensions = match value()?.as_str() {
+                    "rv32i" => Extensions::rv32i(),
+                    "rv32im" => Extensions::rv32im(),
+                    other => return Err(format!("unsupported ISA `{}`", other)),
+                }
+            }
+            "--traps" => options.trap_exceptions = true,
+            "--uart" => {
+                let addr = value()?;
+                options.uart = Some(
+                    parse_number(&addr)
+                        .and_then(|addr| u32::try_from(addr).ok())
+                        .ok_or_else(|| format!("invalid UART address `{}`", addr))?,
+                );
+            }
+            "--uart-input" => options.uart_input = Some(value()?),
+            "--clint" => {
+                let addr = value()?;
+                options.clint = Some(
+                    parse_number(&addr)
+                        .and_then(|addr| u32::try_from(addr).ok())
+                        .ok_or_else(|| format!("invalid CLINT address `{}`", addr))?,
+                );
+            }
+            "--plic" => {
+                let addr = value()?;
+                options.plic = Some(
+                    parse_number(&addr)
+                        .and_then(|addr| u32::try_from(addr).ok())
+                        .ok_or_else(|| format!("invalid PLIC address `{}`", addr))?,
+                );
+            }
+            "--io-page" => options.io_page = true,
+            "--leds" => {
+                options.io_page = true;
+                options.show_leds = true;
+            }
+            "--buttons" => {
+                options.io_page = true;
+                options.buttons = Some(value()?);
+            }
+            "--framebuffer" => {
+                let spec = value()?;
+                options.framebuffer = Some(
+                    parse_framebuffer(&spec)
+                        .ok_or_else(|| format!("invalid framebuffer `{}`", spec))?,
+                );
+            }
+            "--snapshot" => options.snapshot = Some(value()?),
+            "--mtime-cycles" => {
+                let cycles = value()?;
+                options.mtime = Timebase::Cycles(
+                    parse_number(&cycles)
+                        .filter(|&cycles| cycles > 0)
+                        .ok_or_else(|| format!("invalid cycle count `{}`", cycles))?,
+                );
+            }
+            "--mtime-hz" => {
+                let hz = value()?;
+                options.mtime = Timebase::WallClock(
+                    parse_number(&hz)
+                        .filter(|&hz| hz > 0)
+                        .ok_or_else(|| format!("invalid frequency `{}`", hz))?,
+                );
+            }
+            "--uart-output" => options.uart_output = Some(value()?),
+            "--regs" => options.dump_registers = true,
+            "-d" | "--dump" => options.dumps.push(value()?),
+            "--core" => options.core = Some(value()?),
+            "-o" | "--output" => options.output = Some(value()?),
+            _ if arg.starts_with('-') => return Err(format!("unknown option `{}`", arg)),
+            _ if options.image.is_empty() => options.image = arg.clone(),
+            _ => return Err(format!("unexpected argument `{}`", arg)),
+        }
+    }
+
+    if options.image.is_empty() {
+        return Err("missing image file".to_string());
     }
+    if options.snapshot.is_some() && options.framebuffer.is_none() {
+        return Err("`--snapshot` needs a `--framebuffer`".to_string());
+    }
+    Ok(options)
+}
 
-    // The program calculates the 10th number of the Fibonacci sequence and the factorial of 9.
-    // The result is stored in registers x23
-    let mut fib = (0, 1);
-    let mut result = 0;
-    for _ in 0..10 {
-        let (a, b) = fib;
-        result = {
-            let sum = a + b;
-            fib = (b, sum);
-            sum
+fn execute(options: &Options) -> Result<u8, Box<dyn std::error::Error>> {
+    let mut image = load_image(&options.image)?;
+    for spec in &options.load {
+        image.segments.extend(load_image(spec)?.segments);
+    }
+    if options.command == Command::Disasm {
+        return match disasm::dump(&image, &mut io::stdout().lock()) {
+            // The listing was piped into `head` or similar
+            Err(e) if e.kind() == io::ErrorKind::BrokenPipe => Ok(0),
+            result => result.map(|_| 0).map_err(Into::into),
+        };
+    }
+
+    let mut bus = image.default_bus(options.memory_size)?;
+    let uart = match options.uart {
+        Some(addr) => {
+            let uart = Rc::new(RefCell::new(make_uart(options)?));
+            let size = uart.borrow().size();
+            bus.map_device(addr, size, Box::new(uart.clone()))
+                .map_err(|e| format!("can't map the UART: {}", e))?;
+            Some(uart)
+        }
+        None => None,
+    };
+    let plic = match options.plic {
+        Some(addr) => {
+            // Sources and contexts (M-mode, S-mode) of a single hart `virt`
+            let mut plic = Plic::new(95, 2);
+            if let Some(uart) = uart {
+                plic.connect(UART_IRQ, Box::new(uart));
+            }
+            let plic = Rc::new(RefCell::new(plic));
+            bus.map_device(addr, PLIC_SIZE, Box::new(plic.clone()))
+                .map_err(|e| format!("can't map the PLIC: {}", e))?;
+            Some(plic)
+        }
+        None => None,
+    };
+    let clint = options.clint.map(|_| {
+        let time: Box<dyn TimeSource> = match options.mtime {
+            Timebase::Cycles(cycles_per_tick) => Box::new(CycleTime { cycles_per_tick }),
+            Timebase::WallClock(hz) => Box::new(WallClockTime::new(hz)),
+        };
+        Rc::new(RefCell::new(Clint::new(time)))
+    });
+    if let (Some(addr), Some(clint)) = (options.clint, &clint) {
+        bus.map_device(addr, CLINT_SIZE, Box::new(clint.clone()))
+            .map_err(|e| format!("can't map the CLINT: {}", e))?;
+    }
+    let io_page = if options.io_page {
+        let io_page = Rc::new(RefCell::new(make_io_page(options)?));
+        bus.map_device(IO_BASE, IO_SIZE, Box::new(io_page.clone()))
+            .map_err(|e| format!("can't map the IO page: {}", e))?;
+        Some(io_page)
+    } else {
+        None
+    };
+    let framebuffer = match options.framebuffer {
+        Some(spec) => {
+            let framebuffer = Rc::new(RefCell::new(Framebuffer::new(
+                spec.width,
+                spec.height,
+                spec.format,
+            )));
+            let size = framebuffer.borrow().size();
+            bus.map_device(spec.addr, size, Box::new(framebuffer.clone()))
+                .map_err(|e| format!("can't map the framebuffer: {}", e))?;
+            Some(framebuffer)
         }
+        None => None,
+    };
+    let mut cpu = Rv32iProcessor::from_elf_image(&image, bus)?;
+    if let Some(clint) = clint {
+        // The time CSR reads mtime
+        cpu.time_source = Box::new(clint.clone());
+        cpu.interrupt_sources.push(Box::new(clint));
+    }
+    if let Some(plic) = plic {
+        cpu.interrupt_sources.push(Box::new(plic));
+    }
+    if let Some(io_page) = io_page {
+        // Sampled every step for the cycle count, it never interrupts
+        cpu.interrupt_sources.push(Box::new(io_page));
+    }
+    cpu.extensions = options.extensions;
+    cpu.trap_exceptions = options.trap_exceptions;
+    if let Some(entry) = &options.entry {
+        cpu.pc = match (image.symbol(entry), parse_number(entry)) {
+            (Some(addr), _) => addr,
+            (None, Some(addr)) => {
+                u32::try_from(addr).map_err(|_| format!("entry point out of range `{}`", entry))?
+            }
+            (None, None) => return Err(format!("unknown entry point `{}`", entry).into()),
+        };
+    }
+    if options.command == Command::Trace {
+        let out: Box<dyn io::Write> = match &options.output {
+            Some(path) => Box::new(BufWriter::new(
+                File::create(path).map_err(|e| format!("{}: {}", path, e))?,
+            )),
+            None => Box::new(BufWriter::new(io::stdout())),
+        };
+        cpu.tracer = Some(CommitLog::new(out));
     }
 
-    assert_eq!(result, cpu.registers[23]);
+    let stop = StopConditions {
+        max_instructions: options.max_instructions,
+        ..StopConditions::default()
+    };
+    let summary = cpu.run(&stop);
+    // Flushes the trace before anything else is printed
+    cpu.tracer = None;
 
-    // Then, the program calculates several multiplications.
-    // The result is stored in registers x24
-    let mut result = 1;
-    for i in 1..10 {
-        result *= i;
+    eprintln!(
+        "rv32i: {} after {} instructions",
+        describe(&summary.reason),
+        summary.retired
+    );
+    if options.dump_registers {
+        eprint!("{}", format_registers(&cpu));
+    }
+    for spec in &options.dumps {
+        save_memory(&mut cpu, spec).map_err(|e| format!("{}: {}", spec, e))?;
+    }
+    if let (Some(path), Some(framebuffer)) = (&options.snapshot, &framebuffer) {
+        framebuffer
+            .borrow()
+            .save(path)
+            .map_err(|e| format!("{}: {}", path, e))?;
     }
+    if let Some(path) = &options.core {
+        let mut out = BufWriter::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?);
+        coredump::write(&mut out, &cpu)?;
+        out.flush()?;
+    }
+    Ok(exit_code(&summary.reason))
+}
 
-    assert_eq!(result, cpu.registers[24]);
+fn make_io_page(options: &Options) -> Result<IoPage, Box<dyn std::error::Error>> {
+    let ram_size = options.memory_size.min(u32::MAX as usize) as u32;
+    let mut io_page = IoPage::new(Box::new(io::stdout()), ram_size);
+    if options.show_leds {
+        io_page.on_change(|change| {
+            eprintln!(
+                "rv32i: leds {} at cycle {}",
+                io_page::render_leds(change.new, DEFAULT_LEDS),
+                change.cycle
+            )
+        });
+    }
+    if let Some(path) = &options.buttons {
+        let text = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        for (cycle, buttons) in
+            parse_button_script(&text).map_err(|e| format!("{}: {}", path, e))?
+        {
+            io_page.schedule_buttons(cycle, buttons);
+        }
+    }
+    Ok(io_page)
+}
 
-    // Finally, the program loads two global variables and stores them in registers x25 and x26.
-    assert_eq!(777u32, cpu.registers[25]);
-    assert_eq!(1737u32, cpu.registers[26]);
-    println!("Demo successful!");
-    Ok(())
+// `<W>x<H>[:FORMAT][@ADDR]`, e.g. `128x128:rgb565@0x30000000` for the
+// learn-fpga demos' OLED
+fn parse_framebuffer(spec: &str) -> Option<FramebufferSpec> {
+    let (spec, addr) = match spec.split_once('@') {
+        Some((spec, addr)) => (spec, u32::try_from(parse_number(addr)?).ok()?),
+        None => (spec, FRAMEBUFFER_BASE),
+    };
+    let (size, format) = match spec.split_once(':') {
+        Some((size, format)) => (size, PixelFormat::from_name(format)?),
+        None => (spec, PixelFormat::Rgb565),
+    };
+    let (width, height) = size.split_once('x')?;
+    let (width, height) = (width.parse::<u32>().ok()?, height.parse::<u32>().ok()?);
+    // The pixels have to fit above ADDR
+    let bytes = width as u64 * height as u64 * format.bytes_per_pixel() as u64;
+    (width > 0 && height > 0 && addr as u64 + bytes <= 1 << 32).then_some(FramebufferSpec {
+        width,
+        height,
+        format,
+        addr,
+    })
+}
+
+// `CYCLE VALUE` lines, in increasing cycle order, with `#` comments.
+fn parse_button_script(text: &str) -> Result<Vec<(u64, u32)>, String> {
+    let mut script: Vec<(u64, u32)> = Vec::new();
+    for (index, line) in text.lines().enumerate() {
+        let error = |message: &str| format!("line {}: {}", index + 1, message);
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        let (cycle, value) = match fields[..] {
+            [] => continue,
+            [cycle, value] => (
+                parse_number(cycle).ok_or_else(|| error("invalid cycle"))?,
+                parse_number(value)
+                    .and_then(|value| u32::try_from(value).ok())
+                    .ok_or_else(|| error("invalid button value"))?,
+            ),
+            _ => return Err(error("expected `CYCLE VALUE`")),
+        };
+        if script.last().is_some_and(|&(last, _)| cycle < last) {
+            return Err(error("cycles must be in increasing order"));
+        }
+        script.push((cycle, value));
+    }
+    Ok(script)
+}
+
+fn make_uart(options: &Options) -> Result<Uart, Box<dyn std::error::Error>> {
+    let output: Box<dyn Write> = match &options.uart_output {
+        Some(path) => Box::new(File::create(path).map_err(|e| format!("{}: {}", path, e))?),
+        None => Box::new(io::stdout()),
+    };
+    let mut uart = Uart::new(output);
+    match &options.uart_input {
+        Some(path) => {
+            uart.push_input(&std::fs::read(path).map_err(|e| format!("{}: {}", path, e))?)
+        }
+        None => uart.connect_stdin(),
+    }
+    Ok(uart)
+}
+
+// `path@addr:len`
+fn save_memory(cpu: &mut Rv32iProcessor, spec: &str) -> Result<(), Box<dyn std::error::Error>> {
+    let (path, region) = spec.rsplit_once('@').ok_or("expected FILE@ADDR:LEN")?;
+    let (addr, len) = region.split_once(':').ok_or("expected FILE@ADDR:LEN")?;
+    let addr = parse_number(addr)
+        .and_then(|addr| u32::try_from(addr).ok())
+        .ok_or_else(|| format!("invalid address `{}`", addr))?;
+    let len = parse_size(len)
+        .filter(|len| addr as u64 + *len as u64 <= 1 << 32)
+        .ok_or_else(|| format!("invalid length `{}`", len))?;
+
+    let bytes = cpu.read_memory(addr, len as u32)?;
+    loader::save_image(path, &ElfImage::from_binary(&bytes, addr)?)
+}
+
+// `path[@addr]`, the address being where flat binaries and readmemh files
+// are loaded
+fn load_image(spec: &str) -> Result<ElfImage, Box<dyn std::error::Error>> {
+    let (path, base) = match spec.rsplit_once('@') {
+        Some((path, addr)) => (
+            path,
+            Some(
+                parse_number(addr)
+                    .and_then(|addr| u32::try_from(addr).ok())
+                    .ok_or_else(|| format!("{}: invalid load address `{}`", spec, addr))?,
+            ),
+        ),
+        None => (spec, None),
+    };
+    loader::load_image(path, base).map_err(|e| format!("{}: {}", path, e).into())
+}
+
+fn describe(reason: &StopReason) -> String {
+    match reason {
+        StopReason::Exit(code) => format!("exited with code {}", code),
+        StopReason::Ebreak => "stopped on ebreak".to_string(),
+        StopReason::IdleLoop => "stopped in an idle loop".to_string(),
+        StopReason::Breakpoint(pc) => format!("stopped on breakpoint {:#010x}", pc),
+        StopReason::InstructionLimit => "reached the instruction limit".to_string(),
+        StopReason::Fault(e) => format!("stopped on {}", e),
+    }
+}
+
+fn exit_code(reason: &StopReason) -> u8 {
+    match reason {
+        StopReason::Exit(code) => *code as u8,
+        StopReason::Ebreak | StopReason::IdleLoop | StopReason::Breakpoint(_) => 0,
+        StopReason::InstructionLimit => EXIT_LIMIT,
+        StopReason::Fault(_) => EXIT_FAILURE,
+    }
+}
+
+// pc, then the registers four per line with their ABI names
+fn format_registers(cpu: &Rv32iProcessor) -> String {
+    let mut out = format!("  pc {:#010x}\n", cpu.pc);
+    for (n, name) in ABI_NAMES.iter().enumerate() {
+        out.push_str(&format!("{:>4} {:#010x}", name, cpu.registers[n]));
+        out.push_str(if n % 4 == 3 { "\n" } else { "  " });
+    }
+    out
+}
+
+// Decimal or 0x-prefixed hexadecimal
+fn parse_number(text: &str) -> Option<u64> {
+    match text.strip_prefix("0x").or(text.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+// A number of bytes with an optional K or M suffix
+fn parse_size(text: &str) -> Option<usize> {
+    let (digits, unit) = match text.char_indices().last()? {
+        (i, 'k' | 'K') => (&text[..i], 1024),
+        (i, 'm' | 'M') => (&text[..i], 1024 * 1024),
+        _ => (text, 1),
+    };
+    let size = (parse_number(digits)? as usize).checked_mul(unit)?;
+    // Everything has to fit in the 32-bit address space
+    (size <= 1 << 32).then_some(size)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args(
+            "trace prog.elf -n 100 --memory 64K -e main -l a.mem@0x800 --isa rv32im --traps \
+             --uart 0x10000000 --uart-input in.txt --uart-output out.txt \
+             --clint 0x2000000 --mtime-hz 10000000 --plic 0xc000000 --leds --buttons b.txt \
+             --framebuffer 128x64:gray8@0x20000000 --snapshot fb.png \
+             --regs -d ram.hex@0:1K --core core -o log",
+        ))
+        .unwrap();
+        assert_eq!(
+            options,
+            Options {
+                command: Command::Trace,
+                image: "prog.elf".to_string(),
+                load: vec!["a.mem@0x800".to_string()],
+                max_instructions: Some(100),
+                memory_size: 64 * 1024,
+                entry: Some("main".to_string()),
+                extensions: Extensions::rv32im(),
+                trap_exceptions: true,
+                uart: Some(0x1000_0000),
+                uart_input: Some("in.txt".to_string()),
+                uart_output: Some("out.txt".to_string()),
+                clint: Some(0x200_0000),
+                mtime: Timebase::WallClock(10_000_000),
+                plic: Some(0xc00_0000),
+                io_page: true,
+                show_leds: true,
+                buttons: Some("b.txt".to_string()),
+                framebuffer: Some(FramebufferSpec {
+                    width: 128,
+                    height: 64,
+                    format: PixelFormat::Gray8,
+                    addr: 0x2000_0000,
+                }),
+                snapshot: Some("fb.png".to_string()),
+                dump_registers: true,
+                dumps: vec!["ram.hex@0:1K".to_string()],
+                core: Some("core".to_string()),
+                output: Some("log".to_string()),
+            }
+        );
+
+        let options = parse_args(&args("run prog.elf")).unwrap();
+        assert_eq!(options.memory_size, DEFAULT_MEMORY_SIZE);
+        assert_eq!(options.max_instructions, None);
+        assert_eq!(options.mtime, Timebase::Cycles(1));
+        let options = parse_args(&args("run prog.elf --mtime-cycles 8")).unwrap();
+        assert_eq!(options.mtime, Timebase::Cycles(8));
+    }
+
+    #[test]
+    fn test_parse_args_errors() {
+        assert_eq!(parse_args(&[]).unwrap_err(), "missing command");
+        assert_eq!(
+            parse_args(&args("debug a.elf")).unwrap_err(),
+            "unknown command `debug`"
+        );
+        assert_eq!(parse_args(&args("run")).unwrap_err(), "missing image file");
+        assert_eq!(
+            parse_args(&args("run a.elf -n")).unwrap_err(),
+            "`-n` needs a value"
+        );
+        assert_eq!(
+            parse_args(&args("run a.elf --memory 5G")).unwrap_err(),
+            "invalid memory size `5G`"
+        );
+        assert_eq!(
+            parse_args(&args("run a.elf b.elf")).unwrap_err(),
+            "unexpected argument `b.elf`"
+        );
+        assert_eq!(
+            parse_args(&args("run a.elf --snapshot a.png")).unwrap_err(),
+            "`--snapshot` needs a `--framebuffer`"
+        );
+        assert_eq!(
+            parse_args(&args("run a.elf --framebuffer 64x64:rgb")).unwrap_err(),
+            "invalid framebuffer `64x64:rgb`"
+        );
+        assert_eq!(
+            parse_args(&args("run a.elf --mtime-cycles 0")).unwrap_err(),
+            "invalid cycle count `0`"
+        );
+    }
+
+    #[test]
+    fn test_parse_framebuffer() {
+        assert_eq!(
+            parse_framebuffer("96x64"),
+            Some(FramebufferSpec {
+                width: 96,
+                height: 64,
+                format: PixelFormat::Rgb565,
+                addr: FRAMEBUFFER_BASE,
+            })
+        );
+        assert_eq!(
+            parse_framebuffer("640x480:xrgb8888@0x80000000").map(|spec| spec.format),
+            Some(PixelFormat::Xrgb8888)
+        );
+        assert_eq!(parse_framebuffer("0x64"), None);
+        assert_eq!(parse_framebuffer("64"), None);
+        assert_eq!(parse_framebuffer("1024x1024:xrgb8888@0xffc00004"), None);
+    }
+
+    #[test]
+    fn test_parse_button_script() {
+        assert_eq!(
+            parse_button_script("# cycle buttons\n1000 0x1\n\n5000 0  # released\n"),
+            Ok(vec![(1000, 1), (5000, 0)])
+        );
+        assert_eq!(
+            parse_button_script("10 1\n5 0"),
+            Err("line 2: cycles must be in increasing order".to_string())
+        );
+        assert_eq!(
+            parse_button_script("10"),
+            Err("line 1: expected `CYCLE VALUE`".to_string())
+        );
+        assert_eq!(
+            parse_button_script("x 1"),
+            Err("line 1: invalid cycle".to_string())
+        );
+    }
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(parse_size("4096"), Some(4096));
+        assert_eq!(parse_size("0x1000"), Some(4096));
+        assert_eq!(parse_size("2M"), Some(2 * 1024 * 1024));
+        assert_eq!(parse_size("8k"), Some(8 * 1024));
+        assert_eq!(parse_size("M"), None);
+        assert_eq!(parse_size("8000M"), None);
+    }
+
+    #[test]
+    fn test_execute_example() {
+        let mut options = parse_args(&args("run example/riscv_asm.elf")).unwrap();
+        assert_eq!(execute(&options).unwrap(), 0);
+        // Interrupts stay disabled, the idle loop still ends the run
+        options.clint = Some(0x200_0000);
+        options.plic = Some(0xc00_0000);
+        options.io_page = true;
+        assert_eq!(execute(&options).unwrap(), 0);
+
+        options.max_instructions = Some(10);
+        assert_eq!(execute(&options).unwrap(), EXIT_LIMIT);
+
+        // Starting in the middle of the .data section hits an illegal instruction
+        options.entry = Some("0x800".to_string());
+        assert_eq!(execute(&options).unwrap(), EXIT_FAILURE);
+
+        options.entry = Some("no_such_symbol".to_string());
+        assert!(execute(&options).is_err());
+        options.entry = Some("0x100000000".to_string());
+        assert_eq!(
+            execute(&options).unwrap_err().to_string(),
+            "entry point out of range `0x100000000`"
+        );
+    }
+
+    #[test]
+    fn test_load_image() {
+        let dir = std::env::temp_dir().join(format!("rv32i-cli-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mem = dir.join("data.mem");
+        std::fs::write(&mem, "00000309\n").unwrap();
+        let mem = mem.to_str().unwrap();
+
+        let image = load_image(&format!("{}@0x800", mem)).unwrap();
+        assert_eq!(image.segments[0].addr, 0x800);
+        assert_eq!(image.entry, 0x800);
+        assert_eq!(
+            load_image(&format!("{}@0x1_0000_0000", mem))
+                .unwrap_err()
+                .to_string(),
+            format!(
+                "{}@0x1_0000_0000: invalid load address `0x1_0000_0000`",
+                mem
+            )
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(
+            load_image("example/riscv_asm.elf@0x100")
+                .unwrap_err()
+                .to_string(),
+            "example/riscv_asm.elf: ELF files are loaded at their own addresses"
+        );
+    }
+
+    #[test]
+    fn test_save_memory() {
+        let dir = std::env::temp_dir().join(format!("rv32i-dump-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let data = dir.join("data.mem").to_str().unwrap().to_string();
+        let core = dir.join("core").to_str().unwrap().to_string();
+
+        let mut options = parse_args(&args("run example/riscv_asm.elf")).unwrap();
+        options.dumps = vec![format!("{}@0x800:4", data)];
+        options.core = Some(core.clone());
+        assert_eq!(execute(&options).unwrap(), 0);
+        // The first global of .data, 1737
+        assert_eq!(std::fs::read_to_string(&data).unwrap(), "000006c9\n");
+        assert!(std::fs::read(&core).unwrap().starts_with(b"\x7fELF"));
+
+        let mut cpu = Rv32iProcessor::default();
+        let mut error = |spec: &str| save_memory(&mut cpu, spec).unwrap_err().to_string();
+        assert_eq!(error("ram.bin"), "expected FILE@ADDR:LEN");
+        assert_eq!(error("ram.bin@0x800"), "expected FILE@ADDR:LEN");
+        assert_eq!(error("ram.bin@0xFFFFFFFF:2"), "invalid length `2`");
+        assert_eq!(error("ram.bin@0:4"), "no memory mapped at 0x00000000");
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_exit_code() {
+        assert_eq!(exit_code(&StopReason::Exit(3)), 3);
+        assert_eq!(exit_code(&StopReason::Exit(0x100)), 0);
+        assert_eq!(exit_code(&StopReason::IdleLoop), 0);
+    }
+
+    #[test]
+    fn test_format_registers() {
+        let mut cpu = Rv32iProcessor::default();
+        cpu.registers[10] = 42;
+        let dump = format_registers(&cpu);
+
+        assert!(dump.starts_with("  pc 0x00000000\nzero 0x00000000    ra 0x00000000"));
+        assert!(dump.contains("  a0 0x0000002a"));
+        assert_eq!(dump.lines().count(), 9);
+    }
 }
@@ -0,0 +1,1134 @@
+// A small two-pass assembler for RV32I(M) + Zicsr, meant for tests and
+// teaching snippets rather than as a replacement for GNU as.
+//
+// Supported syntax:
+// - labels (`loop:`), comments starting with `#`, `//` or `;`
+// - every base/M/Zicsr/Zifencei instruction with ABI or `xN` register names
+// - pseudo-instructions: nop, li, la, mv, not, neg, seqz, snez, sltz, sgtz,
+//   j, jal <label>, jr, jalr <rs>, ret, call, tail, beqz, bnez, blez, bgez,
+//   bltz, bgtz, bgt, ble, bgtu, bleu, csrr, csrw, csrs, csrc, csrwi, csrsi,
+//   csrci, rdcycle, rdtime, rdinstret (and their `h` halves), unimp
+// - directives: .word, .half, .byte, .ascii, .asciz/.string, .zero/.space,
+//   .align (power of two), .balign, .equ/.set; .text, .data, .section,
+//   .globl and .global are accepted and ignored (one flat image)
+// - expressions: numbers (decimal, 0x, 0b, 'c'), symbols, `.`, `+`/`-`,
+//   and %hi()/%lo() around any of them
+//
+// Branch and jump targets given as a bare number are offsets from the
+// instruction (as in llvm-mc); anything involving a symbol is an address.
+
+use crate::modules::disasm;
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AsmError {
+    // 1-based source line
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for AsmError {}
+
+// The assembled image, `bytes[0]` being at `origin`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Program {
+    pub origin: u32,
+    pub bytes: Vec<u8>,
+    pub symbols: BTreeMap<String, u32>,
+}
+
+impl Program {
+    // Little-endian words, zero-padded to a multiple of 4 bytes, ready for
+    // `Rv32iProcessor::new`.
+    pub fn words(&self) -> Vec<u32> {
+        self.bytes
+            .chunks(4)
+            .map(|chunk| {
+                let mut word = [0u8; 4];
+                word[..chunk.len()].copy_from_slice(chunk);
+                u32::from_le_bytes(word)
+            })
+            .collect()
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Program, AsmError> {
+    assemble_at(source, 0)
+}
+
+pub fn assemble_at(source: &str, origin: u32) -> Result<Program, AsmError> {
+    let mut symbols = BTreeMap::new();
+    let items = first_pass(source, origin, &mut symbols)?;
+
+    let mut bytes = Vec::new();
+    for item in &items {
+        let (addr, data) = match item {
+            Item::Instr {
+                line,
+                addr,
+                mnemonic,
+                operands,
+                size,
+            } => {
+                let words =
+                    encode(mnemonic, operands, *addr, *size, &symbols).map_err(|message| {
+                        AsmError {
+                            line: *line,
+                            message,
+                        }
+                    })?;
+                let data: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+                (*addr, data)
+            }
+            Item::Values {
+                line,
+                addr,
+                width,
+                exprs,
+            } => {
+                let mut data = Vec::new();
+                for expr in exprs {
+                    let value = eval(expr, &symbols, *addr).map_err(|message| AsmError {
+                        line: *line,
+                        message,
+                    })?;
+                    data.extend_from_slice(&value.to_le_bytes()[..*width]);
+                }
+                (*addr, data)
+            }
+            Item::Bytes { addr, bytes } => (*addr, bytes.clone()),
+        };
+
+        // Items lie between origin and the end of the address space, where
+        // an empty one can wrap to 0
+        let offset = addr.wrapping_sub(origin) as usize;
+        if bytes.len() < offset + data.len() {
+            bytes.resize(offset + data.len(), 0);
+        }
+        bytes[offset..offset + data.len()].copy_from_slice(&data);
+    }
+
+    Ok(Program {
+        origin,
+        bytes,
+        symbols,
+    })
+}
+
+enum Item<'a> {
+    Instr {
+        line: usize,
+        addr: u32,
+        mnemonic: String,
+        operands: Vec<&'a str>,
+        size: u32,
+    },
+    Values {
+        line: usize,
+        addr: u32,
+        width: usize,
+        exprs: Vec<&'a str>,
+    },
+    Bytes {
+        addr: u32,
+        bytes: Vec<u8>,
+    },
+}
+
+// Collects labels and constants and lays out every line, so the second pass
+// only has to encode.
+fn first_pass<'a>(
+    source: &'a str,
+    origin: u32,
+    symbols: &mut BTreeMap<String, u32>,
+) -> Result<Vec<Item<'a>>, AsmError> {
+    let mut items = Vec::new();
+    // One past the end of the address space once the program fills it, so
+    // that running past it is an error rather than a wrap to 0
+    let mut next = origin as u64;
+
+    for (index, raw) in source.lines().enumerate() {
+        let line = index + 1;
+        let error = |message: String| AsmError { line, message };
+        let advance = |size: u32| {
+            let end = next + size as u64;
+            if end > 1 << 32 {
+                return Err(error("program doesn't fit in the address space".into()));
+            }
+            Ok(end)
+        };
+        let addr = next as u32;
+        let mut text = strip_comment(raw).trim();
+
+        // Any number of labels can precede the statement
+        while let Some(colon) = text.find(':') {
+            let label = text[..colon].trim();
+            if !is_identifier(label) {
+                break;
+            }
+            if next == 1 << 32 {
+                return Err(error("program doesn't fit in the address space".into()));
+            }
+            if symbols.insert(label.to_string(), addr).is_some() {
+                return Err(error(format!("label `{}` defined twice", label)));
+            }
+            text = text[colon + 1..].trim();
+        }
+        if text.is_empty() {
+            continue;
+        }
+
+        let (head, rest) = match text.find(char::is_whitespace) {
+            Some(split) => (&text[..split], text[split..].trim()),
+            None => (text, ""),
+        };
+
+        if let Some(directive) = head.strip_prefix('.') {
+            let args = split_operands(rest);
+            let data = match directive {
+                "word" | "half" | "byte" => {
+                    let width = match directive {
+                        "word" => 4,
+                        "half" => 2,
+                        _ => 1,
+                    };
+                    let size = (width * args.len()) as u32;
+                    items.push(Item::Values {
+                        line,
+                        addr,
+                        width,
+                        exprs: args,
+                    });
+                    next = advance(size)?;
+                    continue;
+                }
+                "ascii" | "asciz" | "string" => {
+                    let mut bytes = parse_string(rest).map_err(error)?;
+                    if directive != "ascii" {
+                        bytes.push(0);
+                    }
+                    bytes
+                }
+                "zero" | "space" => {
+                    let count = eval_const(rest, symbols, addr).map_err(error)?;
+                    // Checked before allocating the zeroes
+                    let count = u32::try_from(count)
+                        .ok()
+                        .filter(|&count| advance(count).is_ok())
+                        .ok_or_else(|| error("program doesn't fit in the address space".into()))?;
+                    vec![0; count as usize]
+                }
+                "align" | "balign" | "p2align" => {
+                    let value = eval_const(rest, symbols, addr).map_err(error)?;
+                    let value =
+                        u32::try_from(value).map_err(|_| error("alignment out of range".into()))?;
+                    let alignment = if directive == "balign" {
+                        value.max(1)
+                    } else {
+                        1u32.checked_shl(value)
+                            .ok_or_else(|| error("alignment out of range".into()))?
+                    };
+                    let padding = addr.wrapping_neg() % alignment;
+                    vec![0; padding as usize]
+                }
+                "equ" | "set" => {
+                    if args.len() != 2 || !is_identifier(args[0]) {
+                        return Err(error(format!(".{} expects `name, value`", directive)));
+                    }
+                    let value = eval_const(args[1], symbols, addr).map_err(error)?;
+                    symbols.insert(args[0].to_string(), value as u32);
+                    continue;
+                }
+                "text" | "data" | "bss" | "rodata" | "section" | "globl" | "global" => continue,
+                _ => return Err(error(format!("unknown directive `.{}`", directive))),
+            };
+            let size = u32::try_from(data.len())
+                .map_err(|_| error("program doesn't fit in the address space".into()))?;
+            next = advance(size)?;
+            items.push(Item::Bytes { addr, bytes: data });
+            continue;
+        }
+
+        let mnemonic = head.to_lowercase();
+        let operands = split_operands(rest);
+        let size = instruction_size(&mnemonic, &operands, symbols, addr);
+        items.push(Item::Instr {
+            line,
+            addr,
+            mnemonic,
+            operands,
+            size,
+        });
+        next = advance(size)?;
+    }
+
+    Ok(items)
+}
+
+// Pseudo-instructions that may expand to two instructions. `li` only does so
+// when its value doesn't fit in 12 bits (or isn't known yet).
+fn instruction_size(
+    mnemonic: &str,
+    operands: &[&str],
+    symbols: &BTreeMap<String, u32>,
+    addr: u32,
+) -> u32 {
+    match mnemonic {
+        "la" | "call" | "tail" => 8,
+        "li" => match operands.get(1).map(|expr| eval(expr, symbols, addr)) {
+            Some(Ok(value)) if (-2048..=2047).contains(&value) => 4,
+            _ => 8,
+        },
+        _ => 4,
+    }
+}
+
+fn encode(
+    mnemonic: &str,
+    ops: &[&str],
+    pc: u32,
+    size: u32,
+    symbols: &BTreeMap<String, u32>,
+) -> Result<Vec<u32>, String> {
+    let expect = |count: usize| {
+        if ops.len() == count {
+            Ok(())
+        } else {
+            Err(format!(
+                "`{}` expects {} operand(s), got {}",
+                mnemonic,
+                count,
+                ops.len()
+            ))
+        }
+    };
+    let reg = |i: usize| parse_reg(ops[i]);
+    let value = |i: usize| eval(ops[i], symbols, pc);
+    // Branch/jump target operand as an offset from pc
+    let offset = |i: usize| -> Result<i64, String> {
+        let expr = ops[i];
+        let target = eval(expr, symbols, pc)?;
+        if parse_number(expr).is_some() {
+            Ok(target)
+        } else {
+            Ok(target - pc as i64)
+        }
+    };
+    let mem = |i: usize| -> Result<(i64, u8), String> {
+        let (offset, base) = split_mem(ops[i])?;
+        let offset = if offset.is_empty() {
+            0
+        } else {
+            eval(offset, symbols, pc)?
+        };
+        Ok((offset, parse_reg(base)?))
+    };
+
+    let word = match mnemonic {
+        // U/J-type
+        "lui" | "auipc" => {
+            expect(2)?;
+            let imm = value(1)?;
+            if !(-(1 << 19)..(1 << 20)).contains(&imm) {
+                return Err(format!("immediate {} out of range", imm));
+            }
+            let opcode = if mnemonic == "lui" { 0x37 } else { 0x17 };
+            u_type((imm as u32) << 12, reg(0)?, opcode)
+        }
+        "jal" if ops.len() == 1 => j_type(offset(0)?, 1)?,
+        "jal" => {
+            expect(2)?;
+            j_type(offset(1)?, reg(0)?)?
+        }
+        "j" => {
+            expect(1)?;
+            j_type(offset(0)?, 0)?
+        }
+        "jalr" if ops.len() == 1 => i_type(0, reg(0)?, 0x0, 1, 0x67)?,
+        "jalr" => match ops.len() {
+            2 => {
+                let (imm, rs1) = mem(1)?;
+                i_type(imm, rs1, 0x0, reg(0)?, 0x67)?
+            }
+            _ => {
+                expect(3)?;
+                i_type(value(2)?, reg(1)?, 0x0, reg(0)?, 0x67)?
+            }
+        },
+        "jr" => {
+            expect(1)?;
+            i_type(0, reg(0)?, 0x0, 0, 0x67)?
+        }
+        "ret" => {
+            expect(0)?;
+            i_type(0, 1, 0x0, 0, 0x67)?
+        }
+        "call" | "tail" => {
+            expect(1)?;
+            let (link, scratch) = if mnemonic == "call" { (1, 1) } else { (0, 6) };
+            let (hi, lo) = split_hi_lo(value(0)? - pc as i64);
+            return Ok(vec![
+                u_type(hi, scratch, 0x17),
+                i_type(lo, scratch, 0x0, link, 0x67)?,
+            ]);
+        }
+
+        // Branches
+        "beq" | "bne" | "blt" | "bge" | "bltu" | "bgeu" => {
+            expect(3)?;
+            b_type(offset(2)?, reg(1)?, reg(0)?, branch_funct3(mnemonic))?
+        }
+        "bgt" | "ble" | "bgtu" | "bleu" => {
+            expect(3)?;
+            let funct3 = match mnemonic {
+                "bgt" => 0x4,
+                "ble" => 0x5,
+                "bgtu" => 0x6,
+                _ => 0x7,
+            };
+            b_type(offset(2)?, reg(0)?, reg(1)?, funct3)?
+        }
+        "beqz" | "bnez" | "bltz" | "bgez" => {
+            expect(2)?;
+            let funct3 = branch_funct3(&mnemonic[..mnemonic.len() - 1]);
+            b_type(offset(1)?, 0, reg(0)?, funct3)?
+        }
+        "bgtz" | "blez" => {
+            expect(2)?;
+            let funct3 = if mnemonic == "bgtz" { 0x4 } else { 0x5 };
+            b_type(offset(1)?, reg(0)?, 0, funct3)?
+        }
+
+        // Loads and stores
+        "lb" | "lh" | "lw" | "lbu" | "lhu" => {
+            expect(2)?;
+            let funct3 = match mnemonic {
+                "lb" => 0x0,
+                "lh" => 0x1,
+                "lw" => 0x2,
+                "lbu" => 0x4,
+                _ => 0x5,
+            };
+            let (imm, rs1) = mem(1)?;
+            i_type(imm, rs1, funct3, reg(0)?, 0x03)?
+        }
+        "sb" | "sh" | "sw" => {
+            expect(2)?;
+            let funct3 = match mnemonic {
+                "sb" => 0x0,
+                "sh" => 0x1,
+                _ => 0x2,
+            };
+            let (imm, rs1) = mem(1)?;
+            s_type(imm, reg(0)?, rs1, funct3)?
+        }
+
+        // Immediate ALU
+        "addi" | "slti" | "sltiu" | "xori" | "ori" | "andi" => {
+            expect(3)?;
+            let funct3 = match mnemonic {
+                "addi" => 0x0,
+                "slti" => 0x2,
+                "sltiu" => 0x3,
+                "xori" => 0x4,
+                "ori" => 0x6,
+                _ => 0x7,
+            };
+            i_type(value(2)?, reg(1)?, funct3, reg(0)?, 0x13)?
+        }
+        "slli" | "srli" | "srai" => {
+            expect(3)?;
+            let shamt = value(2)?;
+            if !(0..32).contains(&shamt) {
+                return Err(format!("shift amount {} out of range", shamt));
+            }
+            let (funct3, funct7) = match mnemonic {
+                "slli" => (0x1, 0x00),
+                "srli" => (0x5, 0x00),
+                _ => (0x5, 0x20),
+            };
+            r_type(funct7, shamt as u8, reg(1)?, funct3, reg(0)?, 0x13)
+        }
+
+        // Register ALU, including M
+        "add" | "sub" | "sll" | "slt" | "sltu" | "xor" | "srl" | "sra" | "or" | "and" | "mul"
+        | "mulh" | "mulhsu" | "mulhu" | "div" | "divu" | "rem" | "remu" => {
+            expect(3)?;
+            let (funct7, funct3) = match mnemonic {
+                "add" => (0x00, 0x0),
+                "sub" => (0x20, 0x0),
+                "sll" => (0x00, 0x1),
+                "slt" => (0x00, 0x2),
+                "sltu" => (0x00, 0x3),
+                "xor" => (0x00, 0x4),
+                "srl" => (0x00, 0x5),
+                "sra" => (0x20, 0x5),
+                "or" => (0x00, 0x6),
+                "and" => (0x00, 0x7),
+                "mul" => (0x01, 0x0),
+                "mulh" => (0x01, 0x1),
+                "mulhsu" => (0x01, 0x2),
+                "mulhu" => (0x01, 0x3),
+                "div" => (0x01, 0x4),
+                "divu" => (0x01, 0x5),
+                "rem" => (0x01, 0x6),
+                _ => (0x01, 0x7),
+            };
+            r_type(funct7, reg(2)?, reg(1)?, funct3, reg(0)?, 0x33)
+        }
+
+        // ALU pseudo-instructions
+        "nop" => {
+            expect(0)?;
+            i_type(0, 0, 0x0, 0, 0x13)?
+        }
+        "li" => {
+            expect(2)?;
+            let rd = reg(0)?;
+            let imm = value(1)?;
+            if !(-(1i64 << 31)..(1i64 << 32)).contains(&imm) {
+                return Err(format!("immediate {} out of range", imm));
+            }
+            if size == 4 {
+                i_type(imm, 0, 0x0, rd, 0x13)?
+            } else {
+                let (hi, lo) = split_hi_lo(imm);
+                return Ok(vec![u_type(hi, rd, 0x37), i_type(lo, rd, 0x0, rd, 0x13)?]);
+            }
+        }
+        "la" => {
+            expect(2)?;
+            let rd = reg(0)?;
+            let (hi, lo) = split_hi_lo(value(1)? - pc as i64);
+            return Ok(vec![u_type(hi, rd, 0x17), i_type(lo, rd, 0x0, rd, 0x13)?]);
+        }
+        "mv" => {
+            expect(2)?;
+            i_type(0, reg(1)?, 0x0, reg(0)?, 0x13)?
+        }
+        "not" => {
+            expect(2)?;
+            i_type(-1, reg(1)?, 0x4, reg(0)?, 0x13)?
+        }
+        "seqz" => {
+            expect(2)?;
+            i_type(1, reg(1)?, 0x3, reg(0)?, 0x13)?
+        }
+        "neg" => {
+            expect(2)?;
+            r_type(0x20, reg(1)?, 0, 0x0, reg(0)?, 0x33)
+        }
+        "snez" => {
+            expect(2)?;
+            r_type(0x00, reg(1)?, 0, 0x3, reg(0)?, 0x33)
+        }
+        "sltz" => {
+            expect(2)?;
+            r_type(0x00, 0, reg(1)?, 0x2, reg(0)?, 0x33)
+        }
+        "sgtz" => {
+            expect(2)?;
+            r_type(0x00, reg(1)?, 0, 0x2, reg(0)?, 0x33)
+        }
+
+        // System
+        "ecall" | "ebreak" | "mret" | "wfi" => {
+            expect(0)?;
+            let funct12 = match mnemonic {
+                "ecall" => 0x000,
+                "ebreak" => 0x001,
+                "mret" => 0x302,
+                _ => 0x105,
+            };
+            funct12 << 20 | 0x73
+        }
+        "unimp" => {
+            expect(0)?;
+            0xc000_1073
+        }
+        "fence" if ops.is_empty() => 0x0ff0_000f,
+        "fence" => {
+            expect(2)?;
+            (parse_iorw(ops[0])? as u32) << 24 | (parse_iorw(ops[1])? as u32) << 20 | 0x0f
+        }
+        "fence.i" => {
+            expect(0)?;
+            0x0000_100f
+        }
+        "csrrw" | "csrrs" | "csrrc" | "csrrwi" | "csrrsi" | "csrrci" => {
+            expect(3)?;
+            let funct3 = csr_funct3(mnemonic);
+            let source = csr_source(ops[2], funct3, symbols, pc)?;
+            csr_type(parse_csr(ops[1], symbols)?, source, funct3, reg(0)?)
+        }
+        "csrr" => {
+            expect(2)?;
+            csr_type(parse_csr(ops[1], symbols)?, 0, 0x2, reg(0)?)
+        }
+        "csrw" | "csrs" | "csrc" | "csrwi" | "csrsi" | "csrci" => {
+            expect(2)?;
+            let funct3 = csr_funct3(&format!("csrr{}", &mnemonic[3..]));
+            let source = csr_source(ops[1], funct3, symbols, pc)?;
+            csr_type(parse_csr(ops[0], symbols)?, source, funct3, 0)
+        }
+        "rdcycle" | "rdtime" | "rdinstret" | "rdcycleh" | "rdtimeh" | "rdinstreth" => {
+            expect(1)?;
+            let csr = parse_csr(&mnemonic[2..], symbols)?;
+            csr_type(csr, 0, 0x2, reg(0)?)
+        }
+
+        _ => return Err(format!("unknown instruction `{}`", mnemonic)),
+    };
+    Ok(vec![word])
+}
+
+fn branch_funct3(mnemonic: &str) -> u32 {
+    match mnemonic {
+        "beq" => 0x0,
+        "bne" => 0x1,
+        "blt" => 0x4,
+        "bge" => 0x5,
+        "bltu" => 0x6,
+        _ => 0x7,
+    }
+}
+
+fn csr_funct3(mnemonic: &str) -> u32 {
+    match mnemonic {
+        "csrrw" => 0x1,
+        "csrrs" => 0x2,
+        "csrrc" => 0x3,
+        "csrrwi" => 0x5,
+        "csrrsi" => 0x6,
+        _ => 0x7,
+    }
+}
+
+// The rs1 field of a CSR instruction: a register, or a 5-bit immediate for
+// the `i` forms.
+fn csr_source(
+    operand: &str,
+    funct3: u32,
+    symbols: &BTreeMap<String, u32>,
+    pc: u32,
+) -> Result<u8, String> {
+    if funct3 & 0x4 == 0 {
+        return parse_reg(operand);
+    }
+    let uimm = eval(operand, symbols, pc)?;
+    if !(0..32).contains(&uimm) {
+        return Err(format!("CSR immediate {} out of range", uimm));
+    }
+    Ok(uimm as u8)
+}
+
+fn r_type(funct7: u32, rs2: u8, rs1: u8, funct3: u32, rd: u8, opcode: u32) -> u32 {
+    funct7 << 25
+        | (rs2 as u32) << 20
+        | (rs1 as u32) << 15
+        | funct3 << 12
+        | (rd as u32) << 7
+        | opcode
+}
+
+fn i_type(imm: i64, rs1: u8, funct3: u32, rd: u8, opcode: u32) -> Result<u32, String> {
+    check_range(imm, 12, 1)?;
+    Ok(
+        ((imm as u32) & 0xFFF) << 20
+            | (rs1 as u32) << 15
+            | funct3 << 12
+            | (rd as u32) << 7
+            | opcode,
+    )
+}
+
+fn s_type(imm: i64, rs2: u8, rs1: u8, funct3: u32) -> Result<u32, String> {
+    check_range(imm, 12, 1)?;
+    let imm = imm as u32;
+    Ok((imm >> 5 & 0x7F) << 25
+        | (rs2 as u32) << 20
+        | (rs1 as u32) << 15
+        | funct3 << 12
+        | (imm & 0x1F) << 7
+        | 0x23)
+}
+
+fn b_type(offset: i64, rs2: u8, rs1: u8, funct3: u32) -> Result<u32, String> {
+    check_range(offset, 13, 2)?;
+    let imm = offset as u32;
+    Ok((imm >> 12 & 0x1) << 31
+        | (imm >> 5 & 0x3F) << 25
+        | (rs2 as u32) << 20
+        | (rs1 as u32) << 15
+        | funct3 << 12
+        | (imm >> 1 & 0xF) << 8
+        | (imm >> 11 & 0x1) << 7
+        | 0x63)
+}
+
+fn u_type(imm: u32, rd: u8, opcode: u32) -> u32 {
+    (imm & 0xFFFF_F000) | (rd as u32) << 7 | opcode
+}
+
+fn j_type(offset: i64, rd: u8) -> Result<u32, String> {
+    check_range(offset, 21, 2)?;
+    let imm = offset as u32;
+    Ok((imm >> 20 & 0x1) << 31
+        | (imm >> 1 & 0x3FF) << 21
+        | (imm >> 11 & 0x1) << 20
+        | (imm >> 12 & 0xFF) << 12
+        | (rd as u32) << 7
+        | 0x6F)
+}
+
+fn csr_type(csr: u16, rs1: u8, funct3: u32, rd: u8) -> u32 {
+    (csr as u32) << 20 | (rs1 as u32) << 15 | funct3 << 12 | (rd as u32) << 7 | 0x73
+}
+
+// Checks that `value` is a signed `bits`-bit number and a multiple of `align`.
+fn check_range(value: i64, bits: u32, align: i64) -> Result<(), String> {
+    let limit = 1i64 << (bits - 1);
+    if !(-limit..limit).contains(&value) {
+        return Err(format!("immediate {} out of range", value));
+    }
+    if value % align != 0 {
+        return Err(format!("offset {} is not a multiple of {}", value, align));
+    }
+    Ok(())
+}
+
+// Splits a 32-bit value into a lui/auipc upper part and a sign-extended
+// 12-bit lower part, such that `hi + lo == value`.
+fn split_hi_lo(value: i64) -> (u32, i64) {
+    let value = value as u32;
+    let hi = value.wrapping_add(0x800) & 0xFFFF_F000;
+    let lo = value.wrapping_sub(hi) as i32 as i64;
+    (hi, lo)
+}
+
+fn parse_reg(name: &str) -> Result<u8, String> {
+    let name = name.trim();
+    if let Some(index) = disasm::ABI_NAMES.iter().position(|abi| *abi == name) {
+        return Ok(index as u8);
+    }
+    if name == "fp" {
+        return Ok(8);
+    }
+    name.strip_prefix('x')
+        .and_then(|n| n.parse::<u8>().ok())
+        .filter(|n| *n < 32)
+        .ok_or_else(|| format!("unknown register `{}`", name))
+}
+
+fn parse_csr(name: &str, symbols: &BTreeMap<String, u32>) -> Result<u16, String> {
+    let name = name.trim();
+    let by_name = (0..0x1000u16).find(|addr| disasm::csr_name(*addr) == Some(name));
+    if let Some(addr) = by_name {
+        return Ok(addr);
+    }
+    match eval(name, symbols, 0) {
+        Ok(addr) if (0..0x1000).contains(&addr) => Ok(addr as u16),
+        _ => Err(format!("unknown CSR `{}`", name)),
+    }
+}
+
+// A fence predecessor/successor set such as `rw` or `iorw`.
+fn parse_iorw(set: &str) -> Result<u8, String> {
+    let set = set.trim();
+    if set == "0" {
+        return Ok(0);
+    }
+    let mut bits = 0;
+    for c in set.chars() {
+        bits |= match c {
+            'i' => 0x8,
+            'o' => 0x4,
+            'r' => 0x2,
+            'w' => 0x1,
+            _ => return Err(format!("invalid fence set `{}`", set)),
+        };
+    }
+    Ok(bits)
+}
+
+// `offset(reg)`, where the offset may itself contain parentheses (%lo(x)).
+fn split_mem(operand: &str) -> Result<(&str, &str), String> {
+    let operand = operand.trim();
+    let open = operand
+        .rfind('(')
+        .filter(|_| operand.ends_with(')'))
+        .ok_or_else(|| format!("expected `offset(register)`, got `{}`", operand))?;
+    Ok((
+        operand[..open].trim(),
+        &operand[open + 1..operand.len() - 1],
+    ))
+}
+
+fn eval_const(expr: &str, symbols: &BTreeMap<String, u32>, pc: u32) -> Result<i64, String> {
+    let value = eval(expr, symbols, pc)?;
+    if value < 0 {
+        return Err(format!("expected a positive value, got {}", value));
+    }
+    Ok(value)
+}
+
+// Evaluates `[%hi(|%lo(] term {(+|-) term} [)]`, with `.` being `pc`.
+fn eval(expr: &str, symbols: &BTreeMap<String, u32>, pc: u32) -> Result<i64, String> {
+    let expr = expr.trim();
+    for (prefix, is_hi) in [("%hi(", true), ("%lo(", false)] {
+        if let Some(inner) = expr.strip_prefix(prefix).and_then(|e| e.strip_suffix(')')) {
+            let (hi, lo) = split_hi_lo(eval(inner, symbols, pc)?);
+            return Ok(if is_hi { (hi >> 12) as i64 } else { lo });
+        }
+    }
+
+    let mut total = 0i64;
+    let mut sign = 1;
+    let mut rest = expr;
+    loop {
+        rest = rest.trim_start();
+        if let Some(stripped) = rest.strip_prefix('-') {
+            sign = -sign;
+            rest = stripped;
+            continue;
+        }
+        if let Some(stripped) = rest.strip_prefix('+') {
+            rest = stripped;
+            continue;
+        }
+        // A char literal may contain an operator, so it is taken whole
+        let end = if let Some(literal) = rest.strip_prefix('\'') {
+            literal.find('\'').map(|i| i + 2).unwrap_or(rest.len())
+        } else {
+            rest.find(['+', '-']).unwrap_or(rest.len())
+        };
+        let term = rest[..end].trim();
+        let value = if term == "." {
+            pc as i64
+        } else if let Some(value) = parse_number(term) {
+            value
+        } else if let Some(value) = symbols.get(term) {
+            *value as i64
+        } else if term.is_empty() {
+            return Err(format!("malformed expression `{}`", expr));
+        } else {
+            return Err(format!("undefined symbol `{}`", term));
+        };
+        total += sign * value;
+        sign = 1;
+        rest = &rest[end..];
+        if rest.trim().is_empty() {
+            return Ok(total);
+        }
+    }
+}
+
+fn parse_number(text: &str) -> Option<i64> {
+    let text = text.trim();
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let value = if let Some(hex) = digits.strip_prefix("0x").or(digits.strip_prefix("0X")) {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits.strip_prefix("0b").or(digits.strip_prefix("0B")) {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if digits.len() == 3 && digits.starts_with('\'') && digits.ends_with('\'') {
+        digits.as_bytes()[1] as i64
+    } else {
+        digits.parse().ok()?
+    };
+    Some(if negative { -value } else { value })
+}
+
+fn parse_string(text: &str) -> Result<Vec<u8>, String> {
+    let inner = text
+        .trim()
+        .strip_prefix('"')
+        .and_then(|t| t.strip_suffix('"'))
+        .ok_or_else(|| format!("expected a quoted string, got `{}`", text))?;
+    let mut bytes = Vec::new();
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        bytes.push(match chars.next() {
+            Some('n') => b'\n',
+            Some('t') => b'\t',
+            Some('r') => b'\r',
+            Some('0') => 0,
+            Some('\\') => b'\\',
+            Some('"') => b'"',
+            other => return Err(format!("unknown escape `\\{}`", other.unwrap_or(' '))),
+        });
+    }
+    Ok(bytes)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            '"' => in_string = !in_string,
+            '#' | ';' if !in_string => return &line[..i],
+            '/' if !in_string && line[i..].starts_with("//") => return &line[..i],
+            _ => {}
+        }
+    }
+    line
+}
+
+fn split_operands(text: &str) -> Vec<&str> {
+    if text.is_empty() {
+        return Vec::new();
+    }
+    text.split(',').map(str::trim).collect()
+}
+
+fn is_identifier(text: &str) -> bool {
+    let mut chars = text.chars();
+    matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_processor::{Rv32iProcessor, StopConditions, StopReason};
+
+    fn words(source: &str) -> Vec<u32> {
+        assemble(source).unwrap().words()
+    }
+
+    #[test]
+    fn test_base_encodings() {
+        // Same encodings as the hand-written tests in rv32i_processor.rs
+        let program = words(
+            "
+            addi x1, x0, 5
+            sb x2, 1(x3)
+            lb x5, 1(x3)
+            add x6, x4, x5
+            sw x1, 4(x3)
+            lw x8, 4(x3)
+            jalr x5, 12(x0)
+            sub t0, ra, sp
+            srai ra, sp, 4
+            lui s0, 1339
+            mul gp, ra, sp
+            csrrw sp, mscratch, ra
+            csrrsi gp, mscratch, 4
+            ecall
+            mret
+            ",
+        );
+        assert_eq!(
+            program,
+            vec![
+                0x00500093, 0x002180a3, 0x00118283, 0x00520333, 0x0011a223, 0x0041a403, 0x00c002e7,
+                0x402082b3, 0x40415093, 0x0053b437, 0x022081b3, 0x34009173, 0x340261f3, 0x00000073,
+                0x30200073,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_labels_and_branches() {
+        let program = words(
+            "
+            start:
+                addi x3, x0, 15
+            loop: addi x1, x1, 1   # count up
+                bne x1, x3, loop
+                beq x0, x0, 8      // bare numbers are offsets
+                j start
+                jal done
+            done:
+            ",
+        );
+        assert_eq!(
+            program,
+            vec![0x00f00193, 0x00108093, 0xfe309ee3, 0x00000463, 0xff1ff06f, 0x004000ef]
+        );
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        let program = assemble(
+            "
+                nop
+                li a0, 22
+                li a1, 0x12345fff
+                li a2, -1
+                mv a3, a0
+                not a4, a0
+                neg a5, a0
+                seqz a6, a0
+                snez a7, a0
+                ret
+                jr t0
+                beqz s4, .
+                bgt a0, a1, .
+                csrr a0, mcause
+                csrw mtvec, ra
+                csrsi mstatus, 8
+                rdcycle a0
+                unimp
+                fence
+                fence r, rw
+                fence.i
+            ",
+        )
+        .unwrap();
+        assert_eq!(
+            program.words(),
+            vec![
+                0x00000013, 0x01600513, 0x123465b7, 0xfff58593, 0xfff00613, 0x00050693, 0xfff54713,
+                0x40a007b3, 0x00153813, 0x00a038b3, 0x00008067, 0x00028067, 0x000a0063, 0x00a5c063,
+                0x34202573, 0x30509073, 0x30046073, 0xc0002573, 0xc0001073, 0x0ff0000f, 0x0230000f,
+                0x0000100f,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_data_and_relocations() {
+        let program = assemble_at(
+            "
+                .equ UART, 0x10000000
+                lui t0, %hi(UART + 4)
+                addi t0, t0, %lo(UART + 4)
+                la a0, message
+                call func
+            func:
+                ret
+                .align 2
+            message:
+                .asciz \"hi, \\\"you\\\"\"
+                .byte 1, 2, 'x'
+                .half 0xBEEF
+                .balign 4
+            table:
+                .word message, table + 4, -1
+                .zero 2
+            ",
+            0x8000_0000,
+        )
+        .unwrap();
+
+        let message = program.symbol("message").unwrap();
+        assert_eq!(message, 0x8000_001C);
+        assert_eq!(program.symbol("table"), Some(0x8000_002C));
+        let words = program.words();
+        assert_eq!(words[0], 0x100002b7); // lui t0, 0x10000
+        assert_eq!(words[1], 0x00428293); // addi t0, t0, 4
+        assert_eq!(words[2], 0x00000517); // auipc a0, 0
+        assert_eq!(words[3], 0x01450513); // addi a0, a0, 20
+        assert_eq!(words[4], 0x00000097); // auipc ra, 0
+        assert_eq!(words[5], 0x008080e7); // jalr ra, 8(ra)
+        assert_eq!(&program.bytes[0x1C..0x27], b"hi, \"you\"\0\x01");
+        assert_eq!(&program.bytes[0x27..0x2C], &[2, b'x', 0xEF, 0xBE, 0]);
+        assert_eq!(&words[11..14], &[message, 0x8000_0030, 0xFFFF_FFFF]);
+        assert_eq!(program.bytes.len(), 0x3A);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = assemble("nop\n  addi x1, x0, 4096").unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(error.message, "immediate 4096 out of range");
+
+        assert_eq!(
+            assemble("foo x1").unwrap_err().message,
+            "unknown instruction `foo`"
+        );
+        assert_eq!(
+            assemble("add x1, x2, x32").unwrap_err().message,
+            "unknown register `x32`"
+        );
+        assert_eq!(
+            assemble("j nowhere").unwrap_err().message,
+            "undefined symbol `nowhere`"
+        );
+        assert_eq!(
+            assemble("a:\na:").unwrap_err().message,
+            "label `a` defined twice"
+        );
+        assert!(assemble("beq x0, x0, 3").is_err());
+        assert_eq!(
+            assemble(".align 32").unwrap_err().message,
+            "alignment out of range"
+        );
+        // Not wrapped around to .align 0 or .balign 0
+        assert_eq!(
+            assemble(".align 0x100000000").unwrap_err().message,
+            "alignment out of range"
+        );
+        assert!(assemble(".balign 0x100000000").is_err());
+    }
+
+    #[test]
+    fn test_end_of_address_space() {
+        let program = assemble_at("nop\nnop", 0xFFFF_FFF8).unwrap();
+        assert_eq!(program.bytes.len(), 8);
+        let error = assemble_at("nop\nnop\nnop", 0xFFFF_FFF8).unwrap_err();
+        assert_eq!(
+            (error.line, error.message.as_str()),
+            (3, "program doesn't fit in the address space")
+        );
+        assert!(assemble_at("nop\nnop\nend:", 0xFFFF_FFF8).is_err());
+        assert!(assemble_at(".zero 16", 0xFFFF_FFF8).is_err());
+        assert_eq!(assemble_at(".zero 8", 0xFFFF_FFF8).unwrap().bytes.len(), 8);
+        // Rejected before anything is allocated, not truncated to .zero 0
+        let error = assemble("nop\n.zero 0x100000000\nnop").unwrap_err();
+        assert_eq!(
+            (error.line, error.message.as_str()),
+            (2, "program doesn't fit in the address space")
+        );
+        assert!(assemble_at(".space 0xFFFFFFFF", 0x10).is_err());
+        assert!(assemble_at(".word 1, 2, 3", 0xFFFF_FFF8).is_err());
+    }
+
+    #[test]
+    fn test_run_assembled_program() {
+        let program = assemble(
+            "
+                li a0, 0        # sum
+                li t0, 1
+                li t1, 11
+            loop:
+                add a0, a0, t0
+                addi t0, t0, 1
+                bne t0, t1, loop
+                la t2, result
+                sw a0, 0(t2)
+                li a7, 93
+                ecall
+            result:
+                .word 0
+            ",
+        )
+        .unwrap();
+        let mut processor = Rv32iProcessor::new(program.words(), vec![]);
+
+        let summary = processor.run(&StopConditions::default());
+        assert_eq!(summary.reason, StopReason::Exit(55));
+        let result = program.symbol("result").unwrap();
+        assert_eq!(processor.bus.read32(result).unwrap(), 55);
+    }
+}
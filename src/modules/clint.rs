@@ -0,0 +1,204 @@
+// The SiFive-style core-local interruptor found on QEMU's `virt` machine and
+// most RISC-V SoCs, for a single hart:
+//
+//   0x0000  msip         bit 0 raises the machine software interrupt
+//   0x4000  mtimecmp     64-bit, the timer interrupt is pending while
+//   0xBFF8  mtime        mtime >= mtimecmp
+//
+// Registers are accessed one 32-bit word at a time. mtime comes from a
+// `TimeSource`, so it can follow the cycle count or the host's clock; the
+// CLINT also serves as the processor's `time_source` so that the `time` CSR
+// and mtime agree.
+
+use crate::modules::rv32i_bus::{AccessSize, BusError, Device};
+use crate::modules::rv32i_csr::{MSIP, MTIP};
+use crate::modules::rv32i_timer::{CycleTime, TimeSource};
+use crate::modules::rv32i_trap::InterruptSource;
+
+// Where QEMU's `virt` machine has its CLINT
+pub const CLINT_BASE: u32 = 0x0200_0000;
+pub const CLINT_SIZE: u32 = 0x1_0000;
+
+const MSIP_OFFSET: u32 = 0x0000;
+const MTIMECMP: u32 = 0x4000;
+const MTIMECMPH: u32 = 0x4004;
+const MTIME: u32 = 0xBFF8;
+const MTIMEH: u32 = 0xBFFC;
+
+pub struct Clint {
+    time: Box<dyn TimeSource>,
+    // Added to the source's time, set by writes to mtime
+    offset: u64,
+    // The mcycle value of the last sample, the current one for bus accesses
+    cycle: u64,
+    pub msip: bool,
+    pub mtimecmp: u64,
+}
+
+impl Clint {
+    pub fn new(time: Box<dyn TimeSource>) -> Self {
+        Self {
+            time,
+            offset: 0,
+            cycle: 0,
+            msip: false,
+            // No timer interrupt until software programs mtimecmp
+            mtimecmp: u64::MAX,
+        }
+    }
+
+    pub fn mtime(&self) -> u64 {
+        self.time(self.cycle)
+    }
+
+    fn set_mtime(&mut self, mtime: u64) {
+        self.offset = mtime.wrapping_sub(self.time.time(self.cycle));
+    }
+}
+
+// One mtime tick per cycle
+impl Default for Clint {
+    fn default() -> Self {
+        Self::new(Box::new(CycleTime::default()))
+    }
+}
+
+impl TimeSource for Clint {
+    fn time(&self, cycle: u64) -> u64 {
+        self.time.time(cycle).wrapping_add(self.offset)
+    }
+}
+
+impl InterruptSource for Clint {
+    fn pending(&mut self, cycle: u64) -> u32 {
+        self.cycle = cycle;
+        let software = if self.msip { MSIP } else { 0 };
+        let timer = if self.mtime() >= self.mtimecmp {
+            MTIP
+        } else {
+            0
+        };
+        software | timer
+    }
+}
+
+impl Device for Clint {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        if size != AccessSize::Word {
+            return Err(BusError::Device(offset));
+        }
+        match offset {
+            MSIP_OFFSET => Ok(self.msip as u32),
+            MTIMECMP => Ok(self.mtimecmp as u32),
+            MTIMECMPH => Ok((self.mtimecmp >> 32) as u32),
+            MTIME => Ok(self.mtime() as u32),
+            MTIMEH => Ok((self.mtime() >> 32) as u32),
+            _ => Err(BusError::Device(offset)),
+        }
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        if size != AccessSize::Word {
+            return Err(BusError::Device(offset));
+        }
+        let low = |old: u64| old & !0xFFFF_FFFF | value as u64;
+        let high = |old: u64| old & 0xFFFF_FFFF | (value as u64) << 32;
+        match offset {
+            MSIP_OFFSET => self.msip = value & 1 != 0,
+            MTIMECMP => self.mtimecmp = low(self.mtimecmp),
+            MTIMECMPH => self.mtimecmp = high(self.mtimecmp),
+            MTIME => self.set_mtime(low(self.mtime())),
+            MTIMEH => self.set_mtime(high(self.mtime())),
+            _ => return Err(BusError::Device(offset)),
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_processor::StopReason;
+    use crate::modules::test_utils::run_with_devices;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_registers() {
+        let mut clint = Clint::new(Box::new(CycleTime { cycles_per_tick: 2 }));
+        let word = AccessSize::Word;
+        assert_eq!(clint.pending(10), 0);
+        assert_eq!(clint.read(MTIME, word), Ok(5));
+
+        clint.write(MTIMECMPH, word, 0).unwrap();
+        clint.write(MTIMECMP, word, 8).unwrap();
+        assert_eq!(clint.pending(15), 0);
+        assert_eq!(clint.pending(16), MTIP);
+
+        // Writing mtime moves it, and it keeps counting from there
+        clint.write(MTIMEH, word, 1).unwrap();
+        assert_eq!(clint.read(MTIMEH, word), Ok(1));
+        assert_eq!(clint.pending(20), MTIP);
+        assert_eq!(clint.mtime(), (1 << 32) + 10);
+        clint.write(MTIMEH, word, 0).unwrap();
+        clint.write(MTIME, word, 0).unwrap();
+        assert_eq!(clint.pending(22), 0);
+        assert_eq!(clint.mtime(), 1);
+
+        clint.write(MSIP_OFFSET, word, 0xFFFF_FFFF).unwrap();
+        assert_eq!(clint.read(MSIP_OFFSET, word), Ok(1));
+        assert_eq!(clint.pending(22), MSIP);
+
+        assert_eq!(
+            clint.read(MTIME, AccessSize::Byte),
+            Err(BusError::Device(MTIME))
+        );
+        assert_eq!(clint.write(0x8, word, 0), Err(BusError::Device(0x8)));
+    }
+
+    #[test]
+    fn test_timer_interrupt_program() {
+        // Counts timer interrupts, rearming mtimecmp 100 ticks later each
+        // time, until the fourth one exits
+        let clint = Rc::new(RefCell::new(Clint::default()));
+        let (processor, summary) = run_with_devices(
+            "
+                la t0, handler
+                csrw mtvec, t0
+                li s0, 0x02000000
+                li s1, 0x4000
+                add s1, s0, s1
+                li t0, 100
+                sw zero, 4(s1)
+                sw t0, 0(s1)
+                li t0, 0x80
+                csrw mie, t0
+                csrsi mstatus, 8
+            idle:
+                wfi
+                j idle
+            handler:
+                addi s2, s2, 1
+                li t0, 4
+                beq s2, t0, done
+                lw t0, 0(s1)
+                addi t0, t0, 100
+                sw t0, 0(s1)
+                mret
+            done:
+                csrr a0, mcause
+                li a7, 93
+                ecall
+            ",
+            vec![(CLINT_BASE, CLINT_SIZE, Box::new(clint.clone()))],
+            |processor| {
+                processor.interrupt_sources.push(Box::new(clint.clone()));
+                processor.time_source = Box::new(clint.clone());
+            },
+        );
+        assert_eq!(summary.reason, StopReason::Exit(0x8000_0007));
+        assert_eq!(processor.registers[18], 4);
+        assert!(clint.borrow().mtime() >= 400);
+        assert!(clint.borrow().mtime() < 450);
+    }
+}
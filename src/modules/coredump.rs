@@ -0,0 +1,140 @@
+// ELF core dumps of a processor, for post-mortem inspection with the usual
+// tools (`readelf -n`, `gdb <elf> <core>`). The file has one PT_LOAD
+// segment per RAM or ROM region on the bus and a PT_NOTE with an
+// NT_PRSTATUS note laid out like riscv32 Linux's `elf_prstatus`, whose
+// register set is pc followed by x1..x31.
+
+use crate::modules::rv32i_processor::Rv32iProcessor;
+
+use object::elf::{
+    ELFCLASS32, ELFDATA2LSB, ELFMAG, ELFOSABI_NONE, EM_RISCV, ET_CORE, EV_CURRENT, NT_PRSTATUS,
+    PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE,
+};
+use std::io::{self, Write};
+
+const EI_NIDENT: usize = 16;
+const EHDR_SIZE: u32 = 52;
+const PHDR_SIZE: u32 = 32;
+// sizeof(struct elf_prstatus) on riscv32
+const PRSTATUS_SIZE: usize = 204;
+const PRSTATUS_REG_OFFSET: usize = 72;
+const NOTE_NAME: &[u8] = b"CORE\0\0\0\0";
+
+pub fn write(out: &mut dyn Write, cpu: &Rv32iProcessor) -> io::Result<()> {
+    let regions = cpu.bus.memory_regions();
+    let phnum = regions.len() as u32 + 1;
+
+    let mut prstatus = vec![0u8; PRSTATUS_SIZE];
+    let mut registers = cpu.registers.clone();
+    registers[0] = cpu.pc;
+    for (n, value) in registers.iter().enumerate() {
+        let offset = PRSTATUS_REG_OFFSET + n * 4;
+        prstatus[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+    }
+    let mut note = Vec::new();
+    note.extend_from_slice(&5u32.to_le_bytes()); // "CORE" and its NUL
+    note.extend_from_slice(&(PRSTATUS_SIZE as u32).to_le_bytes());
+    note.extend_from_slice(&NT_PRSTATUS.to_le_bytes());
+    note.extend_from_slice(NOTE_NAME);
+    note.extend_from_slice(&prstatus);
+
+    let mut ident = [0u8; EI_NIDENT];
+    ident[..4].copy_from_slice(&ELFMAG);
+    ident[4] = ELFCLASS32;
+    ident[5] = ELFDATA2LSB;
+    ident[6] = EV_CURRENT;
+    ident[7] = ELFOSABI_NONE;
+    out.write_all(&ident)?;
+    write_u16(out, ET_CORE)?;
+    write_u16(out, EM_RISCV)?;
+    write_u32(out, EV_CURRENT as u32)?;
+    write_u32(out, 0)?; // e_entry
+    write_u32(out, EHDR_SIZE)?; // e_phoff
+    write_u32(out, 0)?; // e_shoff
+    write_u32(out, 0)?; // e_flags
+    write_u16(out, EHDR_SIZE as u16)?;
+    write_u16(out, PHDR_SIZE as u16)?;
+    write_u16(out, phnum as u16)?;
+    write_u16(out, 0)?; // e_shentsize
+    write_u16(out, 0)?; // e_shnum
+    write_u16(out, 0)?; // e_shstrndx
+
+    // The note, then the memory, right after the program headers
+    let mut offset = EHDR_SIZE + phnum * PHDR_SIZE;
+    write_phdr(out, PT_NOTE, offset, 0, note.len() as u32, 0, 4)?;
+    offset += note.len() as u32;
+    for (base, data) in &regions {
+        let flags = PF_R | PF_W | PF_X;
+        write_phdr(out, PT_LOAD, offset, *base, data.len() as u32, flags, 4)?;
+        offset += data.len() as u32;
+    }
+
+    out.write_all(&note)?;
+    for (_, data) in &regions {
+        out.write_all(data)?;
+    }
+    Ok(())
+}
+
+fn write_phdr(
+    out: &mut dyn Write,
+    kind: u32,
+    offset: u32,
+    addr: u32,
+    size: u32,
+    flags: u32,
+    align: u32,
+) -> io::Result<()> {
+    for field in [kind, offset, addr, addr, size, size, flags, align] {
+        write_u32(out, field)?;
+    }
+    Ok(())
+}
+
+fn write_u16(out: &mut dyn Write, value: u16) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+fn write_u32(out: &mut dyn Write, value: u32) -> io::Result<()> {
+    out.write_all(&value.to_le_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::loader::ElfImage;
+    use crate::modules::rv32i_processor::StopConditions;
+    use object::read::elf::{ElfFile32, FileHeader, ProgramHeader};
+
+    #[test]
+    fn test_example_core() {
+        let mut cpu = Rv32iProcessor::new_from_elf("example/riscv_asm.elf").unwrap();
+        cpu.run(&StopConditions::default());
+        let mut core = Vec::new();
+        write(&mut core, &cpu).unwrap();
+
+        let file = ElfFile32::<object::Endianness>::parse(&*core).unwrap();
+        let endian = file.endian();
+        assert_eq!(file.raw_header().e_type(endian), ET_CORE);
+
+        let note_segment = &file.raw_segments()[0];
+        assert_eq!(note_segment.p_type(endian), PT_NOTE);
+        let mut notes = note_segment.notes(endian, &*core).unwrap().unwrap();
+        let note = notes.next().unwrap().unwrap();
+        assert_eq!(note.name(), b"CORE");
+        assert_eq!(note.n_type(endian), NT_PRSTATUS);
+        let reg = |n: usize| {
+            let offset = PRSTATUS_REG_OFFSET + n * 4;
+            u32::from_le_bytes(note.desc()[offset..offset + 4].try_into().unwrap())
+        };
+        assert_eq!(reg(0), cpu.pc);
+        assert_eq!(reg(2), cpu.registers[2]);
+        assert_eq!(reg(26), 1737);
+
+        // The memory reads back like any other ELF image
+        let image = ElfImage::parse(&core).unwrap();
+        assert_eq!(image.segments.len(), 1);
+        assert_eq!(image.segments[0].addr, 0);
+        assert_eq!(image.segments[0].data, cpu.bus.memory_regions()[0].1);
+    }
+}
@@ -0,0 +1,331 @@
+use crate::modules::loader::ElfImage;
+use crate::modules::rv32i_csr;
+use crate::modules::rv32i_instr::{decode, Instruction};
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+
+pub const ABI_NAMES: [&str; 32] = [
+    "zero", "ra", "sp", "gp", "tp", "t0", "t1", "t2", "s0", "s1", "a0", "a1", "a2", "a3", "a4",
+    "a5", "a6", "a7", "s2", "s3", "s4", "s5", "s6", "s7", "s8", "s9", "s10", "s11", "t3", "t4",
+    "t5", "t6",
+];
+
+pub fn reg_name(reg: u8) -> &'static str {
+    ABI_NAMES[reg as usize & 0x1F]
+}
+
+pub fn csr_name(addr: u16) -> Option<&'static str> {
+    Some(match addr {
+        rv32i_csr::MSTATUS => "mstatus",
+        rv32i_csr::MISA => "misa",
+        rv32i_csr::MIE => "mie",
+        rv32i_csr::MTVEC => "mtvec",
+        rv32i_csr::MSCRATCH => "mscratch",
+        rv32i_csr::MEPC => "mepc",
+        rv32i_csr::MCAUSE => "mcause",
+        rv32i_csr::MTVAL => "mtval",
+        rv32i_csr::MIP => "mip",
+        rv32i_csr::MVENDORID => "mvendorid",
+        rv32i_csr::MARCHID => "marchid",
+        rv32i_csr::MIMPID => "mimpid",
+        rv32i_csr::MHARTID => "mhartid",
+        rv32i_csr::MCYCLE => "mcycle",
+        rv32i_csr::MINSTRET => "minstret",
+        rv32i_csr::MCYCLEH => "mcycleh",
+        rv32i_csr::MINSTRETH => "minstreth",
+        rv32i_csr::CYCLE => "cycle",
+        rv32i_csr::TIME => "time",
+        rv32i_csr::INSTRET => "instret",
+        rv32i_csr::CYCLEH => "cycleh",
+        rv32i_csr::TIMEH => "timeh",
+        rv32i_csr::INSTRETH => "instreth",
+        _ => return None,
+    })
+}
+
+// Renders `instruction`, located at `pc`, as canonical assembly with ABI
+// register names and the usual pseudo-instructions (li, mv, ret, j, ...).
+// Branch and jump targets are absolute addresses.
+pub fn disassemble(instruction: u32, pc: u32) -> String {
+    use Instruction::*;
+
+    let target = |offset: i32| pc.wrapping_add(offset as u32);
+    let r = reg_name;
+
+    match decode(instruction) {
+        Lui { rd, imm } => format!("lui {}, {:#x}", r(rd), imm >> 12),
+        Auipc { rd, imm } => format!("auipc {}, {:#x}", r(rd), imm >> 12),
+        Jal { rd, offset } => match rd {
+            0 => format!("j {:#x}", target(offset)),
+            1 => format!("jal {:#x}", target(offset)),
+            _ => format!("jal {}, {:#x}", r(rd), target(offset)),
+        },
+        Jalr { rd, rs1, offset } => match (rd, rs1, offset) {
+            (0, 1, 0) => "ret".to_string(),
+            (0, _, 0) => format!("jr {}", r(rs1)),
+            (1, _, 0) => format!("jalr {}", r(rs1)),
+            _ => format!("jalr {}, {}({})", r(rd), offset, r(rs1)),
+        },
+
+        Beq { rs1, rs2, offset } => branch("beq", rs1, rs2, target(offset)),
+        Bne { rs1, rs2, offset } => branch("bne", rs1, rs2, target(offset)),
+        Blt { rs1, rs2, offset } => branch("blt", rs1, rs2, target(offset)),
+        Bge { rs1, rs2, offset } => branch("bge", rs1, rs2, target(offset)),
+        Bltu { rs1, rs2, offset } => branch("bltu", rs1, rs2, target(offset)),
+        Bgeu { rs1, rs2, offset } => branch("bgeu", rs1, rs2, target(offset)),
+
+        Lb { rd, rs1, offset } => format!("lb {}, {}({})", r(rd), offset, r(rs1)),
+        Lh { rd, rs1, offset } => format!("lh {}, {}({})", r(rd), offset, r(rs1)),
+        Lw { rd, rs1, offset } => format!("lw {}, {}({})", r(rd), offset, r(rs1)),
+        Lbu { rd, rs1, offset } => format!("lbu {}, {}({})", r(rd), offset, r(rs1)),
+        Lhu { rd, rs1, offset } => format!("lhu {}, {}({})", r(rd), offset, r(rs1)),
+        Sb { rs1, rs2, offset } => format!("sb {}, {}({})", r(rs2), offset, r(rs1)),
+        Sh { rs1, rs2, offset } => format!("sh {}, {}({})", r(rs2), offset, r(rs1)),
+        Sw { rs1, rs2, offset } => format!("sw {}, {}({})", r(rs2), offset, r(rs1)),
+
+        Addi { rd, rs1, imm } => match (rd, rs1, imm) {
+            (0, 0, 0) => "nop".to_string(),
+            (_, 0, _) => format!("li {}, {}", r(rd), imm),
+            (_, _, 0) => format!("mv {}, {}", r(rd), r(rs1)),
+            _ => format!("addi {}, {}, {}", r(rd), r(rs1), imm),
+        },
+        Slti { rd, rs1, imm } => format!("slti {}, {}, {}", r(rd), r(rs1), imm),
+        Sltiu { rd, rs1, imm: 1 } => format!("seqz {}, {}", r(rd), r(rs1)),
+        Sltiu { rd, rs1, imm } => format!("sltiu {}, {}, {}", r(rd), r(rs1), imm),
+        Xori { rd, rs1, imm: -1 } => format!("not {}, {}", r(rd), r(rs1)),
+        Xori { rd, rs1, imm } => format!("xori {}, {}, {}", r(rd), r(rs1), imm),
+        Ori { rd, rs1, imm } => format!("ori {}, {}, {}", r(rd), r(rs1), imm),
+        Andi { rd, rs1, imm } => format!("andi {}, {}, {}", r(rd), r(rs1), imm),
+        Slli { rd, rs1, shamt } => format!("slli {}, {}, {}", r(rd), r(rs1), shamt),
+        Srli { rd, rs1, shamt } => format!("srli {}, {}, {}", r(rd), r(rs1), shamt),
+        Srai { rd, rs1, shamt } => format!("srai {}, {}, {}", r(rd), r(rs1), shamt),
+
+        Sub { rd, rs1: 0, rs2 } => format!("neg {}, {}", r(rd), r(rs2)),
+        Sltu { rd, rs1: 0, rs2 } => format!("snez {}, {}", r(rd), r(rs2)),
+        Slt { rd, rs1, rs2: 0 } => format!("sltz {}, {}", r(rd), r(rs1)),
+        Slt { rd, rs1: 0, rs2 } => format!("sgtz {}, {}", r(rd), r(rs2)),
+        Add { rd, rs1, rs2 } => alu("add", rd, rs1, rs2),
+        Sub { rd, rs1, rs2 } => alu("sub", rd, rs1, rs2),
+        Sll { rd, rs1, rs2 } => alu("sll", rd, rs1, rs2),
+        Slt { rd, rs1, rs2 } => alu("slt", rd, rs1, rs2),
+        Sltu { rd, rs1, rs2 } => alu("sltu", rd, rs1, rs2),
+        Xor { rd, rs1, rs2 } => alu("xor", rd, rs1, rs2),
+        Srl { rd, rs1, rs2 } => alu("srl", rd, rs1, rs2),
+        Sra { rd, rs1, rs2 } => alu("sra", rd, rs1, rs2),
+        Or { rd, rs1, rs2 } => alu("or", rd, rs1, rs2),
+        And { rd, rs1, rs2 } => alu("and", rd, rs1, rs2),
+        Mul { rd, rs1, rs2 } => alu("mul", rd, rs1, rs2),
+        Mulh { rd, rs1, rs2 } => alu("mulh", rd, rs1, rs2),
+        Mulhsu { rd, rs1, rs2 } => alu("mulhsu", rd, rs1, rs2),
+        Mulhu { rd, rs1, rs2 } => alu("mulhu", rd, rs1, rs2),
+        Div { rd, rs1, rs2 } => alu("div", rd, rs1, rs2),
+        Divu { rd, rs1, rs2 } => alu("divu", rd, rs1, rs2),
+        Rem { rd, rs1, rs2 } => alu("rem", rd, rs1, rs2),
+        Remu { rd, rs1, rs2 } => alu("remu", rd, rs1, rs2),
+
+        Fence {
+            pred: 0xF,
+            succ: 0xF,
+        } => "fence".to_string(),
+        Fence { pred, succ } => format!("fence {}, {}", iorw(pred), iorw(succ)),
+        FenceI => "fence.i".to_string(),
+
+        Ecall => "ecall".to_string(),
+        Ebreak => "ebreak".to_string(),
+        Mret => "mret".to_string(),
+        Wfi => "wfi".to_string(),
+
+        // The canonical `unimp` is csrrw x0, cycle, x0
+        Csrrw {
+            rd: 0,
+            rs1: 0,
+            csr: rv32i_csr::CYCLE,
+        } => "unimp".to_string(),
+        Csrrw { rd, rs1, csr } => csr_op("csrrw", rd, csr, r(rs1).to_string()),
+        Csrrs { rd, rs1, csr } => csr_op("csrrs", rd, csr, r(rs1).to_string()),
+        Csrrc { rd, rs1, csr } => csr_op("csrrc", rd, csr, r(rs1).to_string()),
+        Csrrwi { rd, uimm, csr } => csr_op("csrrwi", rd, csr, uimm.to_string()),
+        Csrrsi { rd, uimm, csr } => csr_op("csrrsi", rd, csr, uimm.to_string()),
+        Csrrci { rd, uimm, csr } => csr_op("csrrci", rd, csr, uimm.to_string()),
+
+        Illegal(_) => format!(".word {:#010x}", instruction),
+    }
+}
+
+fn alu(name: &str, rd: u8, rs1: u8, rs2: u8) -> String {
+    format!(
+        "{} {}, {}, {}",
+        name,
+        reg_name(rd),
+        reg_name(rs1),
+        reg_name(rs2)
+    )
+}
+
+fn branch(name: &str, rs1: u8, rs2: u8, target: u32) -> String {
+    match (name, rs1, rs2) {
+        ("beq" | "bne" | "blt" | "bge", _, 0) => {
+            let alias = match name {
+                "beq" => "beqz",
+                "bne" => "bnez",
+                "blt" => "bltz",
+                _ => "bgez",
+            };
+            format!("{} {}, {:#x}", alias, reg_name(rs1), target)
+        }
+        ("blt", 0, _) => format!("bgtz {}, {:#x}", reg_name(rs2), target),
+        ("bge", 0, _) => format!("blez {}, {:#x}", reg_name(rs2), target),
+        _ => format!(
+            "{} {}, {}, {:#x}",
+            name,
+            reg_name(rs1),
+            reg_name(rs2),
+            target
+        ),
+    }
+}
+
+// fence predecessor/successor sets, e.g. 0b0011 -> "rw"
+fn iorw(set: u8) -> String {
+    if set == 0 {
+        return "0".to_string();
+    }
+    "iorw"
+        .chars()
+        .enumerate()
+        .filter(|(i, _)| set & (0x8 >> i) != 0)
+        .map(|(_, c)| c)
+        .collect()
+}
+
+fn csr_op(name: &str, rd: u8, csr_addr: u16, operand: String) -> String {
+    let rd_name = reg_name(rd);
+    let csr = csr_name(csr_addr)
+        .map(str::to_string)
+        .unwrap_or_else(|| format!("{:#x}", csr_addr));
+
+    // Reads of the counters have their own mnemonics
+    if name == "csrrs" && operand == "zero" {
+        let counter = matches!(
+            csr_addr,
+            rv32i_csr::CYCLE
+                | rv32i_csr::TIME
+                | rv32i_csr::INSTRET
+                | rv32i_csr::CYCLEH
+                | rv32i_csr::TIMEH
+                | rv32i_csr::INSTRETH
+        );
+        return if counter {
+            format!("rd{} {}", csr, rd_name)
+        } else {
+            format!("csrr {}, {}", rd_name, csr)
+        };
+    }
+    if rd == 0 {
+        // csrrw -> csrw, csrrsi -> csrsi, ...
+        return format!("csr{} {}, {}", &name[4..], csr, operand);
+    }
+    format!("{} {}, {}, {}", name, rd_name, csr, operand)
+}
+
+// Writes an objdump-like listing of the executable segments of `image`, with
+// a label line for every symbol found at an instruction address.
+pub fn dump(image: &ElfImage, out: &mut dyn Write) -> io::Result<()> {
+    let mut labels: BTreeMap<u32, Vec<&str>> = BTreeMap::new();
+    for (name, addr) in &image.symbols {
+        labels.entry(*addr).or_default().push(name);
+    }
+
+    for segment in image.segments.iter().filter(|s| s.executable) {
+        for (i, word) in segment.data.chunks_exact(4).enumerate() {
+            let pc = segment.addr.wrapping_add(4 * i as u32);
+            if let Some(names) = labels.get(&pc) {
+                writeln!(out)?;
+                for name in names {
+                    writeln!(out, "{:08x} <{}>:", pc, name)?;
+                }
+            }
+            let instruction = u32::from_le_bytes([word[0], word[1], word[2], word[3]]);
+            writeln!(
+                out,
+                "{:8x}: {:08x}  {}",
+                pc,
+                instruction,
+                disassemble(instruction, pc)
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_base_instructions() {
+        assert_eq!(disassemble(0x402082b3, 0), "sub t0, ra, sp");
+        assert_eq!(disassemble(0x07d10293, 0), "addi t0, sp, 125");
+        assert_eq!(disassemble(0xff010113, 0), "addi sp, sp, -16");
+        assert_eq!(disassemble(0x04512c23, 0), "sw t0, 88(sp)");
+        assert_eq!(disassemble(0x00c12083, 0), "lw ra, 12(sp)");
+        assert_eq!(disassemble(0x0053b437, 0), "lui s0, 0x53b");
+        assert_eq!(disassemble(0x00001117, 0), "auipc sp, 0x1");
+        assert_eq!(disassemble(0x40415093, 0), "srai ra, sp, 4");
+        assert_eq!(disassemble(0x04228563, 0x100), "beq t0, sp, 0x14a");
+        assert_eq!(disassemble(0x02c0046f, 0x100), "jal s0, 0x12c");
+        assert_eq!(disassemble(0x00c002e7, 0), "jalr t0, 12(zero)");
+        assert_eq!(disassemble(0x022081b3, 0), "mul gp, ra, sp");
+    }
+
+    #[test]
+    fn test_pseudo_instructions() {
+        assert_eq!(disassemble(0x00000013, 0), "nop");
+        assert_eq!(disassemble(0x01600b13, 0), "li s6, 22");
+        assert_eq!(disassemble(0x00050593, 0), "mv a1, a0");
+        assert_eq!(disassemble(0x00008067, 0), "ret");
+        assert_eq!(disassemble(0x0000006f, 0x2cc), "j 0x2cc");
+        assert_eq!(disassemble(0x00c000ef, 0), "jal 0xc");
+        assert_eq!(disassemble(0x00028067, 0), "jr t0");
+        assert_eq!(disassemble(0xfe0a1ee3, 0x20), "bnez s4, 0x1c");
+        assert_eq!(disassemble(0xfff54513, 0), "not a0, a0");
+        assert_eq!(disassemble(0x40a00533, 0), "neg a0, a0");
+        assert_eq!(disassemble(0x00153513, 0), "seqz a0, a0");
+        assert_eq!(disassemble(0x00a03533, 0), "snez a0, a0");
+    }
+
+    #[test]
+    fn test_system_instructions() {
+        assert_eq!(disassemble(0x00000073, 0), "ecall");
+        assert_eq!(disassemble(0x00100073, 0), "ebreak");
+        assert_eq!(disassemble(0x30200073, 0), "mret");
+        assert_eq!(disassemble(0xc0001073, 0), "unimp");
+        assert_eq!(disassemble(0x0ff0000f, 0), "fence");
+        assert_eq!(disassemble(0x0230000f, 0), "fence r, rw");
+        assert_eq!(disassemble(0x0000100f, 0), "fence.i");
+        assert_eq!(disassemble(0x34202573, 0), "csrr a0, mcause");
+        assert_eq!(disassemble(0x30509073, 0), "csrw mtvec, ra");
+        assert_eq!(disassemble(0x30046073, 0), "csrsi mstatus, 8");
+        assert_eq!(disassemble(0x34009173, 0), "csrrw sp, mscratch, ra");
+        assert_eq!(disassemble(0xc0002573, 0), "rdcycle a0");
+        assert_eq!(disassemble(0x7c0020f3, 0), "csrr ra, 0x7c0");
+    }
+
+    #[test]
+    fn test_unknown() {
+        assert_eq!(disassemble(0x00000000, 0), ".word 0x00000000");
+        assert_eq!(disassemble(0x0000f083, 0), ".word 0x0000f083");
+    }
+
+    #[test]
+    fn test_dump_example() {
+        let image = ElfImage::from_file("example/riscv_asm.elf").unwrap();
+        let mut out = Vec::new();
+        dump(&image, &mut out).unwrap();
+        let listing = String::from_utf8(out).unwrap();
+
+        assert!(listing.contains("00000000 <_start>:\n       0: 00001117  auipc sp, 0x1\n"));
+        assert!(listing.contains("     2cc: 0000006f  j 0x2cc\n"));
+    }
+}
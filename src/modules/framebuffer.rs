@@ -0,0 +1,364 @@
+// A memory-mapped framebuffer: `width * height` pixels in row-major order,
+// each `format.bytes_per_pixel()` bytes, little-endian like the rest of the
+// bus. There is no display; the picture is saved as a PPM or PNG file for
+// tests and demos to look at, so the output of the learn-fpga graphics demos
+// (OLED in RGB565, HDMI) can be checked without hardware.
+
+use crate::modules::rv32i_bus::{AccessSize, BusError, Device};
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+// Where the CLI maps it by default, clear of the RAM, the IO page and
+// QEMU `virt`'s devices
+pub const FRAMEBUFFER_BASE: u32 = 0x3000_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    // One byte of luminance
+    Gray8,
+    // rrrrrggg gggbbbbb, the SSD1351 OLED's format
+    Rgb565,
+    // 0x00RRGGBB words
+    Xrgb8888,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            PixelFormat::Gray8 => 1,
+            PixelFormat::Rgb565 => 2,
+            PixelFormat::Xrgb8888 => 4,
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<PixelFormat> {
+        match name {
+            "gray8" => Some(PixelFormat::Gray8),
+            "rgb565" => Some(PixelFormat::Rgb565),
+            "xrgb8888" => Some(PixelFormat::Xrgb8888),
+            _ => None,
+        }
+    }
+
+    // The 8-bit RGB value of the pixel stored in `bytes`
+    fn to_rgb(self, bytes: &[u8]) -> [u8; 3] {
+        match self {
+            PixelFormat::Gray8 => [bytes[0]; 3],
+            PixelFormat::Rgb565 => {
+                let pixel = u16::from_le_bytes([bytes[0], bytes[1]]);
+                let r = (pixel >> 11) as u8 & 0x1F;
+                let g = (pixel >> 5) as u8 & 0x3F;
+                let b = pixel as u8 & 0x1F;
+                // Replicate the top bits so that full scale maps to 255
+                [r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2]
+            }
+            PixelFormat::Xrgb8888 => [bytes[2], bytes[1], bytes[0]],
+        }
+    }
+}
+
+pub struct Framebuffer {
+    pub width: u32,
+    pub height: u32,
+    pub format: PixelFormat,
+    pixels: Vec<u8>,
+}
+
+impl Framebuffer {
+    // A black picture
+    pub fn new(width: u32, height: u32, format: PixelFormat) -> Self {
+        Self {
+            width,
+            height,
+            format,
+            pixels: vec![0; width as usize * height as usize * format.bytes_per_pixel()],
+        }
+    }
+
+    // Bytes to map on the bus
+    pub fn size(&self) -> u32 {
+        self.pixels.len() as u32
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // The picture as 8-bit RGB triples, row by row
+    pub fn to_rgb(&self) -> Vec<u8> {
+        self.pixels
+            .chunks(self.format.bytes_per_pixel())
+            .flat_map(|pixel| self.format.to_rgb(pixel))
+            .collect()
+    }
+
+    // A binary PPM (P6), the simplest format image tools read.
+    pub fn write_ppm(&self, out: &mut dyn Write) -> io::Result<()> {
+        write!(out, "P6\n{} {}\n255\n", self.width, self.height)?;
+        out.write_all(&self.to_rgb())
+    }
+
+    // An 8-bit RGB PNG. The image data is stored without compression, which
+    // every decoder reads and keeps this self-contained.
+    pub fn write_png(&self, out: &mut dyn Write) -> io::Result<()> {
+        out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+        let mut header = Vec::new();
+        header.extend_from_slice(&self.width.to_be_bytes());
+        header.extend_from_slice(&self.height.to_be_bytes());
+        // 8 bits per channel, truecolor, deflate, adaptive filters, no interlace
+        header.extend_from_slice(&[8, 2, 0, 0, 0]);
+        write_chunk(out, b"IHDR", &header)?;
+
+        // Every row starts with its filter type, 0 (none)
+        let rgb = self.to_rgb();
+        let mut raw = Vec::with_capacity(rgb.len() + self.height as usize);
+        for row in rgb.chunks((self.width as usize * 3).max(1)) {
+            raw.push(0);
+            raw.extend_from_slice(row);
+        }
+        write_chunk(out, b"IDAT", &zlib_stored(&raw))?;
+        write_chunk(out, b"IEND", &[])
+    }
+
+    // Writes a PNG or, for a `.ppm` path, a PPM.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = BufWriter::new(File::create(path)?);
+        match Path::new(path).extension().and_then(|ext| ext.to_str()) {
+            Some("ppm") => self.write_ppm(&mut out)?,
+            _ => self.write_png(&mut out)?,
+        }
+        out.flush()
+    }
+}
+
+impl Device for Framebuffer {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        let bytes = self
+            .pixels
+            .get(offset as usize..offset as usize + size as usize)
+            .ok_or(BusError::Device(offset))?;
+        Ok(bytes
+            .iter()
+            .rev()
+            .fold(0, |value, b| value << 8 | *b as u32))
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        let bytes = self
+            .pixels
+            .get_mut(offset as usize..offset as usize + size as usize)
+            .ok_or(BusError::Device(offset))?;
+        bytes.copy_from_slice(&value.to_le_bytes()[..size as usize]);
+        Ok(())
+    }
+}
+
+fn write_chunk(out: &mut dyn Write, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+    let crc = crc32(&[kind.as_slice(), data].concat());
+    out.write_all(&crc.to_be_bytes())
+}
+
+// A zlib stream of uncompressed deflate blocks, at most 65535 bytes each.
+fn zlib_stored(data: &[u8]) -> Vec<u8> {
+    // Deflate with a 32K window, no dictionary, check bits for 0x7801
+    let mut out = vec![0x78, 0x01];
+    let mut blocks = data.chunks(0xFFFF).peekable();
+    if blocks.peek().is_none() {
+        out.extend_from_slice(&[1, 0, 0, 0xFF, 0xFF]);
+    }
+    while let Some(block) = blocks.next() {
+        let last = blocks.peek().is_none() as u8;
+        let len = block.len() as u16;
+        out.push(last);
+        out.extend_from_slice(&len.to_le_bytes());
+        out.extend_from_slice(&(!len).to_le_bytes());
+        out.extend_from_slice(block);
+    }
+    out.extend_from_slice(&adler32(data).to_be_bytes());
+    out
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    !data.iter().fold(0xFFFF_FFFF, |crc, b| {
+        (0..8).fold(crc ^ *b as u32, |crc, _| {
+            if crc & 1 != 0 {
+                crc >> 1 ^ 0xEDB8_8320
+            } else {
+                crc >> 1
+            }
+        })
+    })
+}
+
+fn adler32(data: &[u8]) -> u32 {
+    let (a, b) = data.iter().fold((1u32, 0u32), |(a, b), byte| {
+        let a = (a + *byte as u32) % 65521;
+        (a, (b + a) % 65521)
+    });
+    b << 16 | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_processor::StopReason;
+    use crate::modules::test_utils::{run_with_devices, TempDir};
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_pixel_formats() {
+        assert_eq!(PixelFormat::Rgb565.to_rgb(&[0xFF, 0xFF]), [255, 255, 255]);
+        assert_eq!(PixelFormat::Rgb565.to_rgb(&[0x00, 0xF8]), [255, 0, 0]);
+        assert_eq!(PixelFormat::Rgb565.to_rgb(&[0xE0, 0x07]), [0, 255, 0]);
+        assert_eq!(
+            PixelFormat::Xrgb8888.to_rgb(&[0x33, 0x22, 0x11, 0xFF]),
+            [0x11, 0x22, 0x33]
+        );
+        assert_eq!(PixelFormat::Gray8.to_rgb(&[0x80]), [0x80; 3]);
+        assert_eq!(PixelFormat::from_name("rgb565"), Some(PixelFormat::Rgb565));
+        assert_eq!(PixelFormat::from_name("rgb"), None);
+    }
+
+    #[test]
+    fn test_device() {
+        let mut fb = Framebuffer::new(2, 2, PixelFormat::Rgb565);
+        assert_eq!(fb.size(), 8);
+        fb.write(0, AccessSize::Word, 0x07E0_F800).unwrap();
+        fb.write(6, AccessSize::Half, 0x001F).unwrap();
+        assert_eq!(fb.read(0, AccessSize::Half), Ok(0xF800));
+        assert_eq!(fb.read(3, AccessSize::Byte), Ok(0x07));
+        assert_eq!(fb.read(6, AccessSize::Word), Err(BusError::Device(6)));
+
+        let mut ppm = Vec::new();
+        fb.write_ppm(&mut ppm).unwrap();
+        assert_eq!(
+            ppm,
+            [
+                b"P6\n2 2\n255\n".as_slice(),
+                &[255, 0, 0, 0, 255, 0, 0, 0, 0, 0, 0, 255]
+            ]
+            .concat()
+        );
+    }
+
+    // Reads back what `write_png` produces: checks the chunk CRCs and
+    // unpacks the stored deflate blocks.
+    fn decode_png(png: &[u8]) -> (u32, u32, Vec<u8>) {
+        assert_eq!(&png[..8], b"\x89PNG\r\n\x1a\n");
+        let mut rest = &png[8..];
+        let (mut width, mut height, mut idat) = (0, 0, Vec::new());
+        while !rest.is_empty() {
+            let len = u32::from_be_bytes(rest[..4].try_into().unwrap()) as usize;
+            let (kind, data) = (&rest[4..8], &rest[8..8 + len]);
+            let crc = u32::from_be_bytes(rest[8 + len..12 + len].try_into().unwrap());
+            assert_eq!(crc, crc32(&rest[4..8 + len]));
+            match kind {
+                b"IHDR" => {
+                    width = u32::from_be_bytes(data[..4].try_into().unwrap());
+                    height = u32::from_be_bytes(data[4..8].try_into().unwrap());
+                    assert_eq!(&data[8..], [8, 2, 0, 0, 0]);
+                }
+                b"IDAT" => idat.extend_from_slice(data),
+                _ => assert_eq!(kind, b"IEND"),
+            }
+            rest = &rest[12 + len..];
+        }
+
+        assert_eq!(idat[..2], [0x78, 0x01]);
+        let mut raw = Vec::new();
+        let mut block = &idat[2..];
+        loop {
+            let len = u16::from_le_bytes([block[1], block[2]]) as usize;
+            assert_eq!(u16::from_le_bytes([block[3], block[4]]), !(len as u16));
+            raw.extend_from_slice(&block[5..5 + len]);
+            let last = block[0] & 1 != 0;
+            block = &block[5 + len..];
+            if last {
+                break;
+            }
+        }
+        assert_eq!(block, adler32(&raw).to_be_bytes());
+        (width, height, raw)
+    }
+
+    #[test]
+    fn test_png() {
+        assert_eq!(crc32(b"IEND"), 0xAE42_6082);
+        assert_eq!(adler32(b"Wikipedia"), 0x11E6_0398);
+
+        let mut fb = Framebuffer::new(3, 2, PixelFormat::Gray8);
+        fb.write(1, AccessSize::Byte, 0x80).unwrap();
+        let mut png = Vec::new();
+        fb.write_png(&mut png).unwrap();
+        let (width, height, raw) = decode_png(&png);
+        assert_eq!((width, height), (3, 2));
+        let mut expected = vec![0; 20];
+        expected[4..7].copy_from_slice(&[0x80; 3]);
+        assert_eq!(raw, expected);
+
+        // More than one deflate block
+        let fb = Framebuffer::new(200, 200, PixelFormat::Xrgb8888);
+        let mut png = Vec::new();
+        fb.write_png(&mut png).unwrap();
+        assert_eq!(decode_png(&png).2.len(), 200 * (1 + 200 * 3));
+    }
+
+    #[test]
+    fn test_gradient_program() {
+        // Fills a 16x16 RGB565 picture with a horizontal red gradient, one
+        // step every other column
+        let fb = Rc::new(RefCell::new(Framebuffer::new(16, 16, PixelFormat::Rgb565)));
+        let size = fb.borrow().size();
+        let (_, summary) = run_with_devices(
+            "
+                li s0, 0x30000000
+                li s1, 0
+            row:
+                li s2, 0
+            column:
+                srli t0, s2, 1
+                slli t0, t0, 12
+                slli t1, s1, 4
+                add t1, t1, s2
+                slli t1, t1, 1
+                add t1, s0, t1
+                sh t0, 0(t1)
+                addi s2, s2, 1
+                li t2, 16
+                bne s2, t2, column
+                addi s1, s1, 1
+                bne s1, t2, row
+                li a0, 0
+                li a7, 93
+                ecall
+            ",
+            vec![(FRAMEBUFFER_BASE, size, Box::new(fb.clone()))],
+            |_| {},
+        );
+        assert_eq!(summary.reason, StopReason::Exit(0));
+
+        let rgb = fb.borrow().to_rgb();
+        assert_eq!(rgb[..3], [0, 0, 0]);
+        // Last pixel of the first row: red 7 << 1 of 31
+        assert_eq!(rgb[15 * 3..16 * 3], [14 << 3 | 14 >> 2, 0, 0]);
+        assert_eq!(rgb[15 * 48..15 * 48 + 3], [0, 0, 0]);
+
+        let dir = TempDir::new("fb");
+        let ppm = dir.path("frame.ppm");
+        let png = dir.path("frame.png");
+        fb.borrow().save(&ppm).unwrap();
+        fb.borrow().save(&png).unwrap();
+        let ppm = std::fs::read(ppm).unwrap();
+        let png = std::fs::read(png).unwrap();
+        assert_eq!(&ppm[..13], b"P6\n16 16\n255\n");
+        assert_eq!(ppm[13..], rgb[..]);
+        assert_eq!(decode_png(&png).0, 16);
+    }
+}
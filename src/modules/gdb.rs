@@ -0,0 +1,842 @@
+// GDB remote serial protocol stub around `Rv32iProcessor`, so a stock
+// riscv32 GDB can attach with `target remote localhost:<port>`.
+//
+// Breakpoints are kept by the stub instead of being patched into memory, so
+// software (Z0) and hardware (Z1) breakpoints behave the same and also work
+// in ROM. Watchpoints (Z2 write, Z3 read, Z4 access) are checked against the
+// load or store about to execute and stop right after it.
+
+use crate::modules::disasm::ABI_NAMES;
+use crate::modules::loader::parse_hex_bytes;
+use crate::modules::rv32i_error::CpuError;
+use crate::modules::rv32i_instr::{decode_with, Instruction};
+use crate::modules::rv32i_processor::{Rv32iProcessor, StepOutcome, ECALL_EXIT};
+
+use std::collections::BTreeSet;
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream};
+
+// Largest packet we accept, advertised in qSupported
+const PACKET_SIZE: usize = 0x4000;
+
+// Steps between two checks for a Ctrl-C from GDB while continuing
+const INTERRUPT_POLL_STEPS: u64 = 4096;
+
+// A GDB connection. `poll_interrupt` must not block: it reports whether GDB
+// sent the 0x03 interrupt byte while the target is running.
+pub trait Connection: Read + Write {
+    fn poll_interrupt(&mut self) -> io::Result<bool>;
+}
+
+impl Connection for TcpStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        interrupt_from(result, byte[0])
+    }
+}
+
+#[cfg(unix)]
+impl Connection for std::os::unix::net::UnixStream {
+    fn poll_interrupt(&mut self) -> io::Result<bool> {
+        self.set_nonblocking(true)?;
+        let mut byte = [0];
+        let result = self.read(&mut byte);
+        self.set_nonblocking(false)?;
+        interrupt_from(result, byte[0])
+    }
+}
+
+fn interrupt_from(result: io::Result<usize>, byte: u8) -> io::Result<bool> {
+    match result {
+        Ok(1) => Ok(byte == 0x03),
+        Ok(_) => Ok(false),
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum WatchKind {
+    Write,
+    Read,
+    Access,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct Watchpoint {
+    pub addr: u32,
+    pub len: u32,
+    pub kind: WatchKind,
+}
+
+enum Incoming {
+    Packet(String),
+    Interrupt,
+    Eof,
+}
+
+pub struct GdbStub<'a> {
+    cpu: &'a mut Rv32iProcessor,
+    sw_breakpoints: BTreeSet<u32>,
+    hw_breakpoints: BTreeSet<u32>,
+    watchpoints: Vec<Watchpoint>,
+    no_ack: bool,
+    last_stop: String,
+}
+
+// Waits for one GDB connection on `addr` (e.g. "127.0.0.1:1234") and serves
+// it until GDB detaches or kills the target.
+pub fn serve_tcp(cpu: &mut Rv32iProcessor, addr: &str) -> io::Result<()> {
+    let listener = TcpListener::bind(addr)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    GdbStub::new(cpu).serve(stream)
+}
+
+// Same as `serve_tcp` on a Unix socket (`target remote /path/to/socket`).
+#[cfg(unix)]
+pub fn serve_unix(cpu: &mut Rv32iProcessor, path: &str) -> io::Result<()> {
+    let listener = std::os::unix::net::UnixListener::bind(path)?;
+    let (stream, _) = listener.accept()?;
+    GdbStub::new(cpu).serve(stream)
+}
+
+impl<'a> GdbStub<'a> {
+    pub fn new(cpu: &'a mut Rv32iProcessor) -> Self {
+        Self {
+            cpu,
+            sw_breakpoints: BTreeSet::new(),
+            hw_breakpoints: BTreeSet::new(),
+            watchpoints: Vec::new(),
+            no_ack: false,
+            // The target starts halted, as if on a SIGTRAP
+            last_stop: "S05".to_string(),
+        }
+    }
+
+    // Handles packets until the connection closes, or GDB sends `k` or `D`.
+    pub fn serve<C: Connection>(&mut self, mut conn: C) -> io::Result<()> {
+        loop {
+            let packet = match self.read_packet(&mut conn)? {
+                Incoming::Packet(packet) => packet,
+                // Only meaningful while running, the target is already halted
+                Incoming::Interrupt => {
+                    let stop = self.last_stop.clone();
+                    self.send(&mut conn, &stop)?;
+                    continue;
+                }
+                Incoming::Eof => return Ok(()),
+            };
+
+            if packet == "k" {
+                return Ok(());
+            }
+            let reply = self.handle(&packet, &mut conn)?;
+            self.send(&mut conn, &reply)?;
+
+            match packet.as_str() {
+                "QStartNoAckMode" => self.no_ack = true,
+                _ if packet.starts_with('D') => return Ok(()),
+                _ => {}
+            }
+        }
+    }
+
+    fn handle<C: Connection>(&mut self, packet: &str, conn: &mut C) -> io::Result<String> {
+        // An empty packet, or one starting with a non-ASCII character, has no
+        // command to run
+        let Some((command, args)) = packet.split_at_checked(1) else {
+            return Ok(String::new());
+        };
+        let reply = match command {
+            "?" => self.last_stop.clone(),
+            "g" => (0..33).map(|n| hex_u32(self.read_register(n))).collect(),
+            "G" => self.write_registers(args),
+            "p" => match u32::from_str_radix(args, 16) {
+                Ok(n) if n <= 32 => hex_u32(self.read_register(n)),
+                _ => "E01".to_string(),
+            },
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "c" | "s" => {
+                if let Some(addr) = parse_hex(args) {
+                    self.cpu.pc = addr;
+                }
+                let stop = self.resume(conn, command == "s")?;
+                self.last_stop = stop.clone();
+                stop
+            }
+            "Z" | "z" => self.update_breakpoint(command == "Z", args),
+            "D" | "H" | "T" => "OK".to_string(),
+            _ => self.handle_query(packet),
+        };
+        Ok(reply)
+    }
+
+    fn handle_query(&self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            return format!(
+                "PacketSize={:x};qXfer:features:read+;swbreak+;hwbreak+;QStartNoAckMode+",
+                PACKET_SIZE
+            );
+        }
+        if let Some(range) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            let Some((offset, length)) = parse_hex_pair(range) else {
+                return "E01".to_string();
+            };
+            let xml = target_xml();
+            let start = (offset as usize).min(xml.len());
+            let end = (start + length as usize).min(xml.len());
+            let marker = if end == xml.len() { 'l' } else { 'm' };
+            return format!("{}{}", marker, &xml[start..end]);
+        }
+        match packet {
+            "QStartNoAckMode" => "OK",
+            "qAttached" => "1",
+            "qC" => "QC1",
+            "qfThreadInfo" => "m1",
+            "qsThreadInfo" => "l",
+            // Anything else is unsupported, which GDB expects as an empty reply
+            _ => "",
+        }
+        .to_string()
+    }
+
+    // Runs until a breakpoint, watchpoint, fault, exit or Ctrl-C, or for a
+    // single instruction, and returns the stop reply. A breakpoint at the
+    // current pc doesn't stop the first step, so GDB can resume from it.
+    fn resume<C: Connection>(&mut self, conn: &mut C, single_step: bool) -> io::Result<String> {
+        let mut steps = 0u64;
+        loop {
+            let pc = self.cpu.pc;
+            if steps > 0 {
+                if self.sw_breakpoints.contains(&pc) {
+                    return Ok("T05swbreak:;".to_string());
+                }
+                if self.hw_breakpoints.contains(&pc) {
+                    return Ok("T05hwbreak:;".to_string());
+                }
+            }
+
+            let watch = self.watch_hit();
+            let outcome = match self.cpu.step() {
+                Ok(outcome) => outcome,
+                Err(e) => return Ok(format!("S{:02x}", fault_signal(e))),
+            };
+            steps += 1;
+
+            // Only for the access that actually happened: an interrupt taken
+            // instead, or a faulting load or store, never made it
+            if let (Some((kind, addr)), StepOutcome::Retired) = (watch, outcome) {
+                let name = match kind {
+                    WatchKind::Write => "watch",
+                    WatchKind::Read => "rwatch",
+                    WatchKind::Access => "awatch",
+                };
+                return Ok(format!("T05{}:{:x};", name, addr));
+            }
+            match outcome {
+                // A trapped ecall is the firmware's business
+                StepOutcome::Ecall if self.cpu.registers[17] == ECALL_EXIT => {
+                    return Ok(format!("W{:02x}", self.cpu.registers[10] as u8));
+                }
+                StepOutcome::Ebreak => return Ok("S05".to_string()),
+                _ => {}
+            }
+
+            if single_step {
+                return Ok("S05".to_string());
+            }
+            if steps.is_multiple_of(INTERRUPT_POLL_STEPS) && conn.poll_interrupt()? {
+                return Ok("S02".to_string());
+            }
+        }
+    }
+
+    // The watchpoint (if any) hit by the load or store at pc, with the
+    // accessed address.
+    fn watch_hit(&mut self) -> Option<(WatchKind, u32)> {
+        if self.watchpoints.is_empty() {
+            return None;
+        }
+        let instruction = self.cpu.bus.read32(self.cpu.pc).ok()?;
+        let (rs1, offset, width, is_store) = match decode_with(instruction, self.cpu.extensions) {
+            Instruction::Lb { rs1, offset, .. } | Instruction::Lbu { rs1, offset, .. } => {
+                (rs1, offset, 1, false)
+            }
+            Instruction::Lh { rs1, offset, .. } | Instruction::Lhu { rs1, offset, .. } => {
+                (rs1, offset, 2, false)
+            }
+            Instruction::Lw { rs1, offset, .. } => (rs1, offset, 4, false),
+            Instruction::Sb { rs1, offset, .. } => (rs1, offset, 1, true),
+            Instruction::Sh { rs1, offset, .. } => (rs1, offset, 2, true),
+            Instruction::Sw { rs1, offset, .. } => (rs1, offset, 4, true),
+            _ => return None,
+        };
+        let addr = self.cpu.registers[rs1 as usize].wrapping_add(offset as u32);
+
+        self.watchpoints
+            .iter()
+            .find(|w| {
+                let kind_matches = match w.kind {
+                    WatchKind::Write => is_store,
+                    WatchKind::Read => !is_store,
+                    WatchKind::Access => true,
+                };
+                let overlaps = (addr as u64) < w.addr as u64 + w.len as u64
+                    && (w.addr as u64) < addr as u64 + width;
+                kind_matches && overlaps
+            })
+            .map(|w| (w.kind, addr))
+    }
+
+    fn update_breakpoint(&mut self, insert: bool, args: &str) -> String {
+        let mut fields = args.split(',');
+        let (Some(kind), Some(addr), Some(len)) = (
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(parse_hex),
+            fields.next().and_then(parse_hex),
+        ) else {
+            return "E01".to_string();
+        };
+
+        let watch = |kind| Watchpoint { addr, len, kind };
+        let set = match kind {
+            0 => &mut self.sw_breakpoints,
+            1 => &mut self.hw_breakpoints,
+            2..=4 => {
+                let watchpoint = watch(match kind {
+                    2 => WatchKind::Write,
+                    3 => WatchKind::Read,
+                    _ => WatchKind::Access,
+                });
+                if insert {
+                    self.watchpoints.push(watchpoint);
+                } else {
+                    self.watchpoints.retain(|w| *w != watchpoint);
+                }
+                return "OK".to_string();
+            }
+            _ => return String::new(),
+        };
+        if insert {
+            set.insert(addr);
+        } else {
+            set.remove(&addr);
+        }
+        "OK".to_string()
+    }
+
+    // GDB numbers x0-x31 as 0-31 and pc as 32.
+    fn read_register(&self, n: u32) -> u32 {
+        match n {
+            0..=31 => self.cpu.registers[n as usize],
+            _ => self.cpu.pc,
+        }
+    }
+
+    fn set_register(&mut self, n: u32, value: u32) {
+        match n {
+            0 => {}
+            1..=31 => self.cpu.registers[n as usize] = value,
+            _ => self.cpu.pc = value,
+        }
+    }
+
+    fn write_registers(&mut self, hex: &str) -> String {
+        // Checked up front so that the slicing below stays on char boundaries
+        if hex.len() < 33 * 8 || !hex.is_ascii() {
+            return "E01".to_string();
+        }
+        for n in 0..33 {
+            let Some(value) = parse_hex_u32(&hex[n * 8..n * 8 + 8]) else {
+                return "E01".to_string();
+            };
+            self.set_register(n as u32, value);
+        }
+        "OK".to_string()
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let Some((n, value)) = args.split_once('=') else {
+            return "E01".to_string();
+        };
+        match (parse_hex(n), parse_hex_u32(value)) {
+            (Some(n), Some(value)) if n <= 32 => {
+                self.set_register(n, value);
+                "OK".to_string()
+            }
+            _ => "E01".to_string(),
+        }
+    }
+
+    fn read_memory(&mut self, args: &str) -> String {
+        let Some((addr, len)) = parse_hex_pair(args) else {
+            return "E01".to_string();
+        };
+        let len = len.min(PACKET_SIZE as u32 / 2);
+        // Only RAM and ROM: reading a device register to show it in the
+        // debugger could pop a UART byte or claim an interrupt
+        let regions = self.cpu.bus.memory_regions();
+        let peek = |addr: u32| {
+            regions.iter().find_map(|&(base, data)| {
+                data.get(addr.wrapping_sub(base) as usize)
+                    .filter(|_| addr >= base)
+                    .copied()
+            })
+        };
+        let mut reply = String::with_capacity(len as usize * 2);
+        for i in 0..len {
+            match peek(addr.wrapping_add(i)) {
+                Some(byte) => reply.push_str(&format!("{:02x}", byte)),
+                // Partial reads are allowed, as long as something was read
+                None if i > 0 => break,
+                None => return "E01".to_string(),
+            }
+        }
+        reply
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let Some((range, data)) = args.split_once(':') else {
+            return "E01".to_string();
+        };
+        let Some((addr, len)) = parse_hex_pair(range) else {
+            return "E01".to_string();
+        };
+        let Some(bytes) = parse_hex_bytes(data).filter(|bytes| bytes.len() == len as usize) else {
+            return "E01".to_string();
+        };
+        // RAM and ROM only, like `read_memory`: a device register written
+        // from the debugger would send a UART byte or complete an interrupt
+        if self.cpu.bus.poke(addr, &bytes).is_err() {
+            return "E01".to_string();
+        }
+        // The write may have patched code
+        self.cpu.icache.flush();
+        "OK".to_string()
+    }
+
+    fn read_packet<C: Connection>(&mut self, conn: &mut C) -> io::Result<Incoming> {
+        loop {
+            match read_byte(conn)? {
+                None => return Ok(Incoming::Eof),
+                Some(0x03) => return Ok(Incoming::Interrupt),
+                Some(b'$') => {}
+                // Acks from GDB and line noise
+                Some(_) => continue,
+            }
+
+            let mut data = Vec::new();
+            let mut sum = 0u8;
+            loop {
+                match read_byte(conn)? {
+                    None => return Ok(Incoming::Eof),
+                    Some(b'#') => break,
+                    Some(byte) => {
+                        sum = sum.wrapping_add(byte);
+                        data.push(byte);
+                    }
+                }
+            }
+            let mut checksum = [0u8; 2];
+            conn.read_exact(&mut checksum)?;
+            let valid = std::str::from_utf8(&checksum)
+                .ok()
+                .and_then(|c| u8::from_str_radix(c, 16).ok())
+                == Some(sum);
+
+            if !self.no_ack {
+                conn.write_all(if valid { b"+" } else { b"-" })?;
+            }
+            if valid {
+                return Ok(Incoming::Packet(unescape(&data)));
+            }
+        }
+    }
+
+    fn send<C: Connection>(&mut self, conn: &mut C, data: &str) -> io::Result<()> {
+        let mut body = Vec::with_capacity(data.len());
+        for &byte in data.as_bytes() {
+            if matches!(byte, b'$' | b'#' | b'}' | b'*') {
+                body.extend_from_slice(&[b'}', byte ^ 0x20]);
+            } else {
+                body.push(byte);
+            }
+        }
+        let sum = body.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte));
+
+        conn.write_all(b"$")?;
+        conn.write_all(&body)?;
+        conn.write_all(format!("#{:02x}", sum).as_bytes())?;
+        conn.flush()
+    }
+}
+
+// Target description: the 32 integer registers and pc, with GDB's names.
+pub fn target_xml() -> String {
+    let mut xml = String::from(
+        "<?xml version=\"1.0\"?>\n\
+         <!DOCTYPE target SYSTEM \"gdb-target.dtd\">\n\
+         <target version=\"1.0\">\n\
+         <architecture>riscv:rv32</architecture>\n\
+         <feature name=\"org.gnu.gdb.riscv.cpu\">\n",
+    );
+    for (n, name) in ABI_NAMES.iter().enumerate() {
+        let kind = match *name {
+            "ra" => "code_ptr",
+            "sp" | "gp" | "tp" | "s0" => "data_ptr",
+            _ => "int",
+        };
+        xml.push_str(&format!(
+            "<reg name=\"{}\" bitsize=\"32\" type=\"{}\" regnum=\"{}\"/>\n",
+            name, kind, n
+        ));
+    }
+    xml.push_str("<reg name=\"pc\" bitsize=\"32\" type=\"code_ptr\" regnum=\"32\"/>\n");
+    xml.push_str("</feature>\n</target>\n");
+    xml
+}
+
+// Unix signal numbers used in stop replies
+fn fault_signal(error: CpuError) -> u8 {
+    match error {
+        CpuError::IllegalInstruction { .. } => 4,
+        CpuError::MisalignedAccess { .. } => 7,
+        _ => 11,
+    }
+}
+
+fn read_byte<C: Connection>(conn: &mut C) -> io::Result<Option<u8>> {
+    let mut byte = [0];
+    loop {
+        match conn.read(&mut byte) {
+            Ok(0) => return Ok(None),
+            Ok(_) => return Ok(Some(byte[0])),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            // A reset connection ends the session like a clean close
+            Err(e) if e.kind() == io::ErrorKind::ConnectionReset => return Ok(None),
+            Err(e) => return Err(e),
+        }
+    }
+}
+
+fn unescape(data: &[u8]) -> String {
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter();
+    while let Some(&byte) = bytes.next() {
+        match byte {
+            b'}' => out.push(bytes.next().map_or(0, |b| b ^ 0x20)),
+            _ => out.push(byte),
+        }
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+// Registers travel as target-endian (little-endian) bytes
+fn hex_u32(value: u32) -> String {
+    format!("{:08x}", value.swap_bytes())
+}
+
+fn parse_hex_u32(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok().map(u32::swap_bytes)
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    u32::from_str_radix(text, 16).ok()
+}
+
+fn parse_hex_pair(text: &str) -> Option<(u32, u32)> {
+    let (a, b) = text.split_once(',')?;
+    Some((parse_hex(a)?, parse_hex(b)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::asm;
+    use crate::modules::rv32i_bus::{AccessSize, Device, Ram, SystemBus};
+    use crate::modules::test_utils::SharedBuffer;
+    use crate::modules::uart::{Uart, UART_BASE};
+    use std::cell::RefCell;
+    use std::io::Cursor;
+    use std::rc::Rc;
+    use std::thread;
+
+    // A scripted GDB: packets are read from `input`, replies collected in
+    // `output`. An 0x03 byte right after a packet interrupts a `c`.
+    struct Script {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl Read for Script {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for Script {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for &mut Script {
+        fn poll_interrupt(&mut self) -> io::Result<bool> {
+            let position = self.input.position() as usize;
+            if self.input.get_ref().get(position) == Some(&0x03) {
+                self.input.set_position(position as u64 + 1);
+                return Ok(true);
+            }
+            Ok(false)
+        }
+    }
+
+    fn packet(data: &str) -> String {
+        let sum = data.bytes().fold(0u8, |sum, b| sum.wrapping_add(b));
+        format!("${}#{:02x}", data, sum)
+    }
+
+    // Runs a session and returns the reply payloads
+    fn session(cpu: &mut Rv32iProcessor, input: &str) -> Vec<String> {
+        let mut script = Script {
+            input: Cursor::new(input.as_bytes().to_vec()),
+            output: Vec::new(),
+        };
+        GdbStub::new(cpu).serve(&mut script).unwrap();
+
+        let output = String::from_utf8(script.output).unwrap();
+        output
+            .split('$')
+            .skip(1)
+            .map(|reply| reply[..reply.find('#').unwrap()].to_string())
+            .collect()
+    }
+
+    fn script(packets: &[&str]) -> String {
+        packets.iter().map(|p| packet(p)).collect()
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let mut cpu = Rv32iProcessor::new_from_elf("example/riscv_asm.elf").unwrap();
+        cpu.registers[10] = 0x1234_5678;
+
+        let replies = session(
+            &mut cpu,
+            &script(&[
+                "qSupported:multiprocess+;swbreak+",
+                "?",
+                "g",
+                "pa",
+                "P5=efbeadde",
+                "m0,4",
+                "M800,2:aa55",
+                "m800,2",
+                "mfffffff0,4",
+                "qXfer:features:read:target.xml:0,40",
+                "qXfer:features:read:target.xml:0,4000",
+                "vMustReplyEmpty",
+                "k",
+            ]),
+        );
+
+        assert!(replies[0].contains("qXfer:features:read+"));
+        assert_eq!(replies[1], "S05");
+        assert_eq!(replies[2].len(), 33 * 8);
+        assert_eq!(&replies[2][80..88], "78563412");
+        assert_eq!(replies[3], "78563412");
+        assert_eq!(replies[4], "OK");
+        assert_eq!(cpu.registers[5], 0xdead_beef);
+        // auipc sp, 0x1
+        assert_eq!(replies[5], "17110000");
+        assert_eq!(replies[6], "OK");
+        assert_eq!(replies[7], "aa55");
+        assert_eq!(replies[8], "E01");
+        assert!(replies[9].starts_with("m<?xml"));
+        assert!(replies[10].starts_with("l<?xml"));
+        assert!(replies[10].contains("<architecture>riscv:rv32</architecture>"));
+        assert_eq!(replies[11], "");
+    }
+
+    #[test]
+    fn test_memory_skips_devices() {
+        let uart = Rc::new(RefCell::new(Uart::new(Box::new(io::sink()))));
+        uart.borrow_mut().push_input(b"x");
+        let mut bus = SystemBus::new();
+        bus.map_ram(Ram::from_bytes(0, vec![1, 2, 3, 4])).unwrap();
+        bus.map_device(UART_BASE, 8, Box::new(uart.clone()))
+            .unwrap();
+        let mut cpu = Rv32iProcessor::with_bus(Box::new(bus));
+
+        let replies = session(
+            &mut cpu,
+            &script(&["m2,4", &format!("m{:x},1", UART_BASE), "k"]),
+        );
+        assert_eq!(replies, ["0304", "E01"]);
+        // The received byte is still there for the firmware
+        assert_eq!(uart.borrow_mut().read(0, AccessSize::Byte), Ok(b'x' as u32));
+
+        // Writes don't reach the device either, and ROM is patchable
+        let output = SharedBuffer::default();
+        let uart = Rc::new(RefCell::new(Uart::new(Box::new(output.clone()))));
+        let mut bus = SystemBus::new();
+        bus.map_rom(Ram::from_bytes(0, vec![1, 2, 3, 4])).unwrap();
+        bus.map_device(UART_BASE, 8, Box::new(uart)).unwrap();
+        let mut cpu = Rv32iProcessor::with_bus(Box::new(bus));
+
+        let replies = session(
+            &mut cpu,
+            &script(&["M1,2:aa55", "m0,4", &format!("M{:x},1:41", UART_BASE), "k"]),
+        );
+        assert_eq!(replies, ["OK", "01aa5504", "E01"]);
+        assert!(output.0.borrow().is_empty());
+    }
+
+    #[test]
+    fn test_breakpoints_and_step() {
+        let mut cpu = Rv32iProcessor::new_from_elf("example/riscv_asm.elf").unwrap();
+        let entry = cpu.symbols["_rust_entry"];
+
+        let input = script(&[
+            &format!("Z0,{:x},4", entry),
+            "c",
+            "p20",
+            // Resuming from the breakpoint doesn't stop on it again
+            "s",
+            &format!("z0,{:x},4", entry),
+            "Z1,4,4",
+        ]) + &packet("c")
+            + "\x03"
+            + &packet("k");
+        let replies = session(&mut cpu, &input);
+
+        assert_eq!(replies[0], "OK");
+        assert_eq!(replies[1], "T05swbreak:;");
+        assert_eq!(replies[2], hex_u32(entry));
+        assert_eq!(replies[3], "S05");
+        assert_eq!(replies[4], "OK");
+        assert_eq!(replies[5], "OK");
+        // _start never runs again, so only Ctrl-C stops the idle loop
+        assert_eq!(replies[6], "S02");
+    }
+
+    #[test]
+    fn test_watchpoints_and_exit() {
+        let program = asm::assemble(
+            "
+                la t0, value
+                li t1, 5
+                sw t1, 0(t0)
+                lw t2, 0(t0)
+                addi a0, t2, 37
+                li a7, 93
+                ecall
+            value:
+                .word 0
+            ",
+        )
+        .unwrap();
+        let value = program.symbol("value").unwrap();
+        let mut cpu = Rv32iProcessor::new(program.words(), vec![]);
+
+        let replies = session(
+            &mut cpu,
+            &script(&[
+                &format!("Z2,{:x},4", value),
+                &format!("Z3,{:x},4", value),
+                "c",
+                "c",
+                "c",
+            ]),
+        );
+        assert_eq!(replies[2], format!("T05watch:{:x};", value));
+        assert_eq!(replies[3], format!("T05rwatch:{:x};", value));
+        assert_eq!(replies[4], "W2a");
+
+        // A store that faults into the trap handler never wrote anything
+        let program = asm::assemble(
+            "
+                la t0, handler
+                csrw mtvec, t0
+                li t0, 0x10000000
+                sw zero, 0(t0)
+            handler:
+                j handler
+            ",
+        )
+        .unwrap();
+        let handler = program.symbol("handler").unwrap();
+        let mut cpu = Rv32iProcessor::new(program.words(), vec![]);
+        cpu.trap_exceptions = true;
+
+        let replies = session(
+            &mut cpu,
+            &script(&["Z2,10000000,4", &format!("Z0,{:x},4", handler), "c"]),
+        );
+        assert_eq!(replies[2], "T05swbreak:;");
+    }
+
+    #[test]
+    fn test_fault_and_checksum() {
+        let mut cpu = Rv32iProcessor::new(vec![0xFFFF_FFFF], vec![]);
+
+        let input = "$?#00".to_string() + &script(&["QStartNoAckMode", "c", "k"]);
+        let mut conn = Script {
+            input: Cursor::new(input.into_bytes()),
+            output: Vec::new(),
+        };
+        GdbStub::new(&mut cpu).serve(&mut conn).unwrap();
+
+        // Bad checksum is nacked, then acks stop after QStartNoAckMode
+        let output = String::from_utf8(conn.output).unwrap();
+        assert_eq!(output, "-+$OK#9a$S04#b7");
+    }
+
+    #[test]
+    fn test_empty_packets() {
+        let mut cpu = Rv32iProcessor::new(vec![0x0000_0013], vec![]);
+        let input = "$#00".to_string() + &packet("\u{e9}t") + &packet("?") + &packet("k");
+        let replies = session(&mut cpu, &input);
+        assert_eq!(replies, ["", "", "S05"]);
+    }
+
+    #[test]
+    fn test_non_ascii_payloads() {
+        let mut cpu = Rv32iProcessor::new(vec![0x0000_0013], vec![]);
+        // Off by one byte, so that every 8-byte register field splits a char
+        let registers = format!("0{}", "\u{e9}".repeat(33 * 4));
+        let input = packet("M0,2:a\u{e9}b") + &packet(&format!("G{}", registers)) + &packet("k");
+        let replies = session(&mut cpu, &input);
+        assert_eq!(replies, ["E01", "E01"]);
+        assert_eq!(cpu.bus.read32(0), Ok(0x0000_0013));
+    }
+
+    #[test]
+    fn test_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            stream.write_all(packet("?").as_bytes()).unwrap();
+            let mut reply = [0u8; 8];
+            stream.read_exact(&mut reply).unwrap();
+            stream.write_all(packet("k").as_bytes()).unwrap();
+            reply
+        });
+
+        let mut cpu = Rv32iProcessor::new(vec![0x00000013], vec![]);
+        let (stream, _) = listener.accept().unwrap();
+        GdbStub::new(&mut cpu).serve(stream).unwrap();
+        assert_eq!(&client.join().unwrap(), b"+$S05#b8");
+    }
+}
@@ -0,0 +1,234 @@
+// Intel HEX images, as written by `objcopy -O ihex` and most flashing tools:
+//
+//   :020000048000 7A          extended linear address: upper half 0x8000
+//   :10000000 ... CS          16 data bytes at 0x8000_0000
+//   :0400000580000000 77      start linear address (the entry point)
+//   :00000001FF               end of file
+//
+// (spaces added for clarity). Both 32-bit linear addressing (records 04 and
+// 05) and the older 20-bit segmented addressing (02 and 03) are supported.
+
+use crate::modules::loader::{parse_hex_bytes, push_bytes, ElfImage, ImageError};
+
+use std::io::{self, Write};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+// The data records, in file order, with the start address record as the
+// entry point. Anything after the end-of-file record is ignored.
+pub fn parse(text: &str) -> Result<ElfImage, ImageError> {
+    let mut segments = Vec::new();
+    let mut entry = None;
+    // Added to the 16-bit offset of data records, set by 02 and 04 records
+    let mut base = 0u64;
+    let mut line_number = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        line_number = index + 1;
+        let error = |message: String| ImageError::new(line_number, message);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| error("missing `:` start code".into()))?;
+        let bytes = parse_hex_bytes(record).ok_or_else(|| error("invalid hex digits".into()))?;
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(error("record length doesn't match its byte count".into()));
+        }
+        let (body, found) = bytes.split_at(bytes.len() - 1);
+        let expected = body
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b))
+            .wrapping_neg();
+        if found[0] != expected {
+            return Err(error(format!(
+                "checksum mismatch: expected 0x{:02X}, found 0x{:02X}",
+                expected, found[0]
+            )));
+        }
+
+        let offset = u16::from_be_bytes([body[1], body[2]]) as u64;
+        let kind = body[3];
+        let data = &body[4..];
+        let field = |len: usize| {
+            if data.len() == len {
+                Ok(data.iter().fold(0u32, |value, b| value << 8 | *b as u32))
+            } else {
+                Err(error(format!(
+                    "record type 0x{:02X} needs {} data bytes",
+                    kind, len
+                )))
+            }
+        };
+        match kind {
+            DATA => {
+                let addr = base + offset;
+                if addr + data.len() as u64 > 1 << 32 {
+                    return Err(error("address out of range".into()));
+                }
+                push_bytes(&mut segments, addr as u32, data);
+            }
+            END_OF_FILE => return Ok(ElfImage::from_segments(segments, entry)),
+            EXTENDED_SEGMENT_ADDRESS => base = (field(2)? as u64) << 4,
+            EXTENDED_LINEAR_ADDRESS => base = (field(2)? as u64) << 16,
+            START_SEGMENT_ADDRESS => {
+                let cs_ip = field(4)?;
+                entry = Some(((cs_ip >> 16) << 4) + (cs_ip & 0xFFFF));
+            }
+            START_LINEAR_ADDRESS => entry = Some(field(4)?),
+            _ => return Err(error(format!("unknown record type 0x{:02X}", kind))),
+        }
+    }
+    Err(ImageError::new(line_number, "missing end-of-file record"))
+}
+
+// Writes `image` as 16-byte data records, the layout objcopy uses, with an
+// extended linear address record whenever the upper half of the address
+// changes. `.bss` is written out as zeroes.
+pub fn write(out: &mut dyn Write, image: &ElfImage) -> io::Result<()> {
+    let mut upper = 0;
+    for segment in &image.segments {
+        let mut bytes = segment.data.clone();
+        bytes.resize(segment.mem_size as usize, 0);
+
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let addr = segment.addr + offset as u32;
+            // Records don't cross 64K boundaries
+            let len = (bytes.len() - offset)
+                .min(16)
+                .min(0x10000 - (addr & 0xFFFF) as usize);
+            if addr >> 16 != upper {
+                upper = addr >> 16;
+                write_record(
+                    out,
+                    EXTENDED_LINEAR_ADDRESS,
+                    0,
+                    &(upper as u16).to_be_bytes(),
+                )?;
+            }
+            write_record(out, DATA, addr as u16, &bytes[offset..offset + len])?;
+            offset += len;
+        }
+    }
+    write_record(out, START_LINEAR_ADDRESS, 0, &image.entry.to_be_bytes())?;
+    write_record(out, END_OF_FILE, 0, &[])
+}
+
+fn write_record(out: &mut dyn Write, kind: u8, offset: u16, data: &[u8]) -> io::Result<()> {
+    let mut bytes = vec![data.len() as u8];
+    bytes.extend_from_slice(&offset.to_be_bytes());
+    bytes.push(kind);
+    bytes.extend_from_slice(data);
+    bytes.push(
+        bytes
+            .iter()
+            .fold(0u8, |sum, b| sum.wrapping_add(*b))
+            .wrapping_neg(),
+    );
+
+    write!(out, ":")?;
+    for b in bytes {
+        write!(out, "{:02X}", b)?;
+    }
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_linear_addresses() {
+        let image = parse(
+            "\
+:0200000480007A
+:08FFFC0017110000130101C000
+:040000058000000473
+:00000001FF
+ignored after the end of file
+",
+        )
+        .unwrap();
+
+        assert_eq!(image.entry, 0x8000_0004);
+        assert_eq!(image.segments.len(), 1);
+        // The record crosses into the next 64K page without a new 04 record
+        assert_eq!(image.segments[0].addr, 0x8000_FFFC);
+        assert_eq!(
+            image.segments[0].data,
+            [0x17, 0x11, 0, 0, 0x13, 0x01, 0x01, 0xc0]
+        );
+    }
+
+    #[test]
+    fn test_segment_addresses() {
+        let image = parse(":020000021000EC\n:0400000001020304F2\n:00000001FF").unwrap();
+
+        assert_eq!(image.segments[0].addr, 0x10000);
+        // No start address record: execution starts at the lowest address
+        assert_eq!(image.entry, 0x10000);
+    }
+
+    #[test]
+    fn test_write() {
+        let mut image = ElfImage::from_binary(&[0x55; 20], 0x8000_FFF8).unwrap();
+        image.segments[0].mem_size = 24;
+        let mut out = Vec::new();
+        write(&mut out, &image).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        assert_eq!(
+            text.lines().collect::<Vec<_>>(),
+            [
+                ":0200000480007A",
+                ":08FFF800555555555555555559",
+                ":02000004800179",
+                ":1000000055555555555555555555555500000000F4",
+                ":040000058000FFF880",
+                ":00000001FF",
+            ]
+        );
+        let parsed = parse(&text).unwrap();
+        assert_eq!(parsed.entry, image.entry);
+        assert_eq!(parsed.segments[0].data[..20], image.segments[0].data[..]);
+        assert_eq!(parsed.segments[0].mem_size, 24);
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| parse(text).unwrap_err().to_string();
+
+        assert_eq!(error("\n0400"), "line 2: missing `:` start code");
+        assert_eq!(error(":04000000010G0304F2"), "line 1: invalid hex digits");
+        assert_eq!(
+            error(":0400000001020304"),
+            "line 1: record length doesn't match its byte count"
+        );
+        assert_eq!(
+            error(":0400000001020304F3"),
+            "line 1: checksum mismatch: expected 0xF2, found 0xF3"
+        );
+        assert_eq!(
+            error(":0100000401FA"),
+            "line 1: record type 0x04 needs 2 data bytes"
+        );
+        assert_eq!(error(":00000006FA"), "line 1: unknown record type 0x06");
+        assert_eq!(
+            error(":02000004FFFFFC\n:08FFFC0017110000130101C000"),
+            "line 2: address out of range"
+        );
+        assert_eq!(
+            error(":0400000001020304F2\n"),
+            "line 1: missing end-of-file record"
+        );
+    }
+}
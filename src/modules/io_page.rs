@@ -0,0 +1,285 @@
+// The IO page of learn-fpga's FemtoSOC, the memory-mapped devices the
+// FemtoRV tutorials and demos use. Each device register is selected by one
+// bit of the word address, so register `bit` sits at `IO_BASE + (4 << bit)`:
+//
+//   bit 0   0x000004  LEDs, read/write
+//   bit 1   0x000008  UART data: write to send, read the received byte
+//   bit 2   0x000010  UART control: bit 8 received data valid, bit 9 busy
+//   bit 9   0x000800  buttons, read-only
+//   bit 17  0x080000  RAM size in bytes
+//   bit 18  0x100000  the devices present, one bit per register bit above
+//
+// Like on the FPGA, the registers of absent devices (OLED, LED matrix, SD
+// card...) read as 0 and ignore writes, so firmware built for a bigger board
+// still runs. LED changes are reported to a listener, e.g. to render them in
+// the terminal, and button changes are scripted by cycle.
+
+use crate::modules::rv32i_bus::{AccessSize, BusError, Device};
+use crate::modules::rv32i_trap::InterruptSource;
+use crate::modules::uart::send_to_host;
+
+use std::collections::VecDeque;
+use std::io::Write;
+
+pub const IO_BASE: u32 = 0x0040_0000;
+pub const IO_SIZE: u32 = 0x0040_0000;
+
+const LEDS: u32 = 0x00_0004;
+const UART_DAT: u32 = 0x00_0008;
+const UART_CNTL: u32 = 0x00_0010;
+const BUTTONS: u32 = 0x00_0800;
+const HW_CONFIG_RAM: u32 = 0x08_0000;
+const HW_CONFIG_DEVICES: u32 = 0x10_0000;
+
+const UART_CNTL_VALID: u32 = 1 << 8;
+
+// The IceStick of the tutorial has 5 LEDs
+pub const DEFAULT_LEDS: u32 = 5;
+
+// The LEDs went from `old` to `new` at `cycle`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LedChange {
+    pub cycle: u64,
+    pub old: u32,
+    pub new: u32,
+}
+
+type Listener = Box<dyn FnMut(&LedChange)>;
+
+pub struct IoPage {
+    num_leds: u32,
+    leds: u32,
+    buttons: u32,
+    // Button values to apply at a cycle, in cycle order
+    script: VecDeque<(u64, u32)>,
+    uart: Box<dyn Write>,
+    rx: VecDeque<u8>,
+    ram_size: u32,
+    cycle: u64,
+    listener: Option<Listener>,
+}
+
+impl IoPage {
+    // UART output goes to `uart`, `ram_size` is reported to the firmware.
+    pub fn new(uart: Box<dyn Write>, ram_size: u32) -> Self {
+        Self {
+            num_leds: DEFAULT_LEDS,
+            leds: 0,
+            buttons: 0,
+            script: VecDeque::new(),
+            uart,
+            rx: VecDeque::new(),
+            ram_size,
+            cycle: 0,
+            listener: None,
+        }
+    }
+
+    // Boards have 4 to 8 LEDs; bits beyond them aren't stored.
+    pub fn with_leds(mut self, num_leds: u32) -> Self {
+        self.num_leds = num_leds.min(32);
+        self
+    }
+
+    pub fn leds(&self) -> u32 {
+        self.leds
+    }
+
+    pub fn buttons(&self) -> u32 {
+        self.buttons
+    }
+
+    // Called on every change of the LEDs.
+    pub fn on_change(&mut self, listener: impl FnMut(&LedChange) + 'static) {
+        self.listener = Some(Box::new(listener));
+    }
+
+    // Sets the buttons right away.
+    pub fn set_buttons(&mut self, buttons: u32) {
+        self.buttons = buttons;
+    }
+
+    // Sets the buttons to `buttons` once mcycle reaches `cycle`. Changes
+    // must be queued in cycle order.
+    pub fn schedule_buttons(&mut self, cycle: u64, buttons: u32) {
+        self.script.push_back((cycle, buttons));
+    }
+
+    // Queues bytes for the firmware to read from the UART.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes);
+    }
+
+    fn set_leds(&mut self, value: u32) {
+        let mask = if self.num_leds == 32 {
+            u32::MAX
+        } else {
+            (1 << self.num_leds) - 1
+        };
+        let new = value & mask;
+        if new != self.leds {
+            let change = LedChange {
+                cycle: self.cycle,
+                old: self.leds,
+                new,
+            };
+            self.leds = new;
+            if let Some(listener) = &mut self.listener {
+                listener(&change);
+            }
+        }
+    }
+}
+
+// The LEDs as a row of lights, the highest numbered one first like on the
+// boards' silkscreen.
+pub fn render_leds(leds: u32, num_leds: u32) -> String {
+    (0..num_leds)
+        .rev()
+        .map(|led| if leds & (1 << led) != 0 { '●' } else { '○' })
+        .collect()
+}
+
+// Sampled before every step like the interrupt controllers, to keep track of
+// the cycle count for LED changes and scripted buttons. The FemtoSOC IO page
+// has no interrupt output, so nothing is ever pending.
+impl InterruptSource for IoPage {
+    fn pending(&mut self, cycle: u64) -> u32 {
+        self.cycle = cycle;
+        while let Some(&(at, buttons)) = self.script.front() {
+            if at > cycle {
+                break;
+            }
+            self.buttons = buttons;
+            self.script.pop_front();
+        }
+        0
+    }
+}
+
+impl Device for IoPage {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        if size != AccessSize::Word {
+            return Err(BusError::Device(offset));
+        }
+        Ok(match offset {
+            LEDS => self.leds,
+            UART_DAT => self.rx.pop_front().map_or(0, u32::from),
+            // Sending is instantaneous, the busy bit is never set
+            UART_CNTL if self.rx.is_empty() => 0,
+            UART_CNTL => UART_CNTL_VALID,
+            BUTTONS => self.buttons,
+            HW_CONFIG_RAM => self.ram_size,
+            HW_CONFIG_DEVICES => LEDS >> 2 | UART_DAT >> 2 | UART_CNTL >> 2 | BUTTONS >> 2,
+            _ => 0,
+        })
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        if size != AccessSize::Word {
+            return Err(BusError::Device(offset));
+        }
+        match offset {
+            LEDS => self.set_leds(value),
+            UART_DAT => send_to_host(&mut *self.uart, &[value as u8]),
+            _ => {}
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_processor::StopReason;
+    use crate::modules::test_utils::{run_with_devices, SharedBuffer};
+    use std::cell::RefCell;
+    use std::io;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_registers() {
+        let output = SharedBuffer::default();
+        let mut io = IoPage::new(Box::new(output.clone()), 6144).with_leds(4);
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let log = changes.clone();
+        io.on_change(move |change| log.borrow_mut().push(*change));
+        let word = AccessSize::Word;
+
+        io.pending(10);
+        io.write(LEDS, word, 0xFF).unwrap();
+        io.write(LEDS, word, 0x0F).unwrap();
+        assert_eq!(io.read(LEDS, word), Ok(0x0F));
+        io.pending(12);
+        io.write(LEDS, word, 0x05).unwrap();
+        assert_eq!(
+            *changes.borrow(),
+            [
+                LedChange {
+                    cycle: 10,
+                    old: 0,
+                    new: 0x0F
+                },
+                LedChange {
+                    cycle: 12,
+                    old: 0x0F,
+                    new: 0x05
+                },
+            ]
+        );
+        assert_eq!(render_leds(io.leds(), 4), "○●○●");
+
+        io.write(UART_DAT, word, b'A' as u32).unwrap();
+        assert_eq!(*output.0.borrow(), b"A");
+        assert_eq!(io.read(UART_CNTL, word), Ok(0));
+        io.push_input(b"z");
+        assert_eq!(io.read(UART_CNTL, word), Ok(UART_CNTL_VALID));
+        assert_eq!(io.read(UART_DAT, word), Ok(b'z' as u32));
+
+        assert_eq!(io.read(HW_CONFIG_RAM, word), Ok(6144));
+        assert_eq!(io.read(HW_CONFIG_DEVICES, word), Ok(0x207));
+        // Devices this board doesn't have
+        io.write(4 << 3, word, 1).unwrap();
+        assert_eq!(io.read(4 << 7, word), Ok(0));
+        assert_eq!(io.read(LEDS, AccessSize::Byte), Err(BusError::Device(LEDS)));
+    }
+
+    #[test]
+    fn test_button_program() {
+        // Mirrors the buttons on the LEDs, and blinks LED 4 on its own
+        // before exiting when button 0 is released again
+        let io = Rc::new(RefCell::new(IoPage::new(Box::new(io::sink()), 0x1000)));
+        let changes = Rc::new(RefCell::new(Vec::new()));
+        let log = changes.clone();
+        io.borrow_mut()
+            .on_change(move |change| log.borrow_mut().push(change.new));
+        io.borrow_mut().schedule_buttons(100, 0b11);
+        io.borrow_mut().schedule_buttons(200, 0);
+
+        let (processor, summary) = run_with_devices(
+            "
+                li gp, 0x400000
+                li s1, 0x400800         # buttons, out of reach of an offset
+            wait_press:
+                lw t0, 0(s1)
+                sw t0, 4(gp)
+                beqz t0, wait_press
+            wait_release:
+                lw t0, 0(s1)
+                sw t0, 4(gp)
+                bnez t0, wait_release
+                li t0, 0x10
+                sw t0, 4(gp)
+                sw zero, 4(gp)
+                li a0, 0
+                li a7, 93
+                ecall
+            ",
+            vec![(IO_BASE, IO_SIZE, Box::new(io.clone()))],
+            |processor| processor.interrupt_sources.push(Box::new(io)),
+        );
+        assert_eq!(summary.reason, StopReason::Exit(0));
+        assert_eq!(*changes.borrow(), [0b11, 0, 0x10, 0]);
+        assert!(processor.csr.mcycle > 200);
+    }
+}
@@ -0,0 +1,443 @@
+use crate::modules::rv32i_bus::{BusError, Ram, SystemBus};
+use crate::modules::{ihex, readmemh, srec};
+
+use object::elf::{EM_RISCV, PF_W, PF_X, PT_LOAD};
+use object::read::elf::{ElfFile32, FileHeader, ProgramHeader};
+use object::{Object, ObjectSymbol, SymbolKind};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+
+// RAM mapped by `ElfImage::default_bus` when the caller doesn't provide a
+// memory map: enough for the example's 3K layout and for the usual
+// text + data + bss + stack of a small bare-metal program.
+pub const DEFAULT_MEMORY_SIZE: usize = 1024 * 1024;
+
+// A PT_LOAD segment, placed at its physical (load) address.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Segment {
+    pub addr: u32,
+    pub data: Vec<u8>,
+    pub mem_size: u32,
+    pub writable: bool,
+    pub executable: bool,
+}
+
+impl Segment {
+    pub fn end(&self) -> u64 {
+        self.addr as u64 + self.mem_size as u64
+    }
+}
+
+// A syntax or checksum error in a text image format (readmemh, ...).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageError {
+    // 1-based line
+    pub line: usize,
+    pub message: String,
+}
+
+impl ImageError {
+    pub(crate) fn new(line: usize, message: impl Into<String>) -> Self {
+        Self {
+            line,
+            message: message.into(),
+        }
+    }
+}
+
+impl fmt::Display for ImageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for ImageError {}
+
+// Also holds images loaded from formats without symbols (readmemh, Intel
+// HEX, S-records, flat binaries), so they go through the same `default_bus`
+// and `load_into`.
+#[derive(Debug, Clone, Default)]
+pub struct ElfImage {
+    pub entry: u32,
+    pub segments: Vec<Segment>,
+    pub symbols: BTreeMap<String, u32>,
+}
+
+impl ElfImage {
+    pub fn from_file(elf_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
+        Self::parse(&fs::read(elf_path)?)
+    }
+
+    // An image without symbols. Execution starts at `entry`, or at the lowest
+    // address for formats that don't record one.
+    pub fn from_segments(segments: Vec<Segment>, entry: Option<u32>) -> Self {
+        let lowest = segments.iter().map(|s| s.addr).min().unwrap_or(0);
+        Self {
+            entry: entry.unwrap_or(lowest),
+            segments,
+            symbols: BTreeMap::new(),
+        }
+    }
+
+    // A flat binary (`objcopy -O binary`) placed at `base`, where execution
+    // starts.
+    pub fn from_binary(bytes: &[u8], base: u32) -> Result<Self, Box<dyn std::error::Error>> {
+        if base as u64 + bytes.len() as u64 > 1 << 32 {
+            return Err("binary doesn't fit in the address space".into());
+        }
+        let mut segments = Vec::new();
+        push_bytes(&mut segments, base, bytes);
+        Ok(Self::from_segments(segments, Some(base)))
+    }
+
+    pub fn parse(binary_data: &[u8]) -> Result<Self, Box<dyn std::error::Error>> {
+        let file = ElfFile32::<object::Endianness>::parse(binary_data)?;
+        let endian = file.endian();
+        let header = file.raw_header();
+        if header.e_machine(endian) != EM_RISCV {
+            return Err("not a RISC-V ELF file".into());
+        }
+
+        let mut segments = Vec::new();
+        for phdr in file.raw_segments() {
+            if phdr.p_type(endian) != PT_LOAD {
+                continue;
+            }
+            let data = phdr
+                .data(endian, binary_data)
+                .map_err(|_| "segment data out of the file bounds")?;
+            let mem_size = phdr.p_memsz(endian);
+            if (data.len() as u32) > mem_size {
+                return Err("segment file size is larger than its memory size".into());
+            }
+            let flags = phdr.p_flags(endian);
+            segments.push(Segment {
+                addr: phdr.p_paddr(endian),
+                data: data.to_vec(),
+                mem_size,
+                writable: flags & PF_W != 0,
+                executable: flags & PF_X != 0,
+            });
+        }
+
+        let symbols = file
+            .symbols()
+            .filter(|symbol| {
+                !symbol.is_undefined()
+                    && !matches!(symbol.kind(), SymbolKind::Section | SymbolKind::File)
+            })
+            .filter_map(|symbol| {
+                let name = symbol.name().ok().filter(|name| !name.is_empty())?;
+                Some((name.to_string(), symbol.address() as u32))
+            })
+            .collect();
+
+        Ok(Self {
+            entry: header.e_entry(endian),
+            segments,
+            symbols,
+        })
+    }
+
+    // A bus with RAM under every segment, in 4K pages, merging segments that
+    // share or touch pages into one region. The region holding the entry
+    // point (or else the lowest one) is grown to `memory_size` bytes for the
+    // heap and stack, so a boot vector far below the program doesn't map
+    // everything in between.
+    pub fn default_bus(&self, memory_size: usize) -> Result<SystemBus, BusError> {
+        const PAGE: u64 = 0x1000;
+        let mut ranges: Vec<(u64, u64)> = self
+            .segments
+            .iter()
+            .filter(|s| s.mem_size > 0)
+            .map(|s| (s.addr as u64 & !(PAGE - 1), s.end().next_multiple_of(PAGE)))
+            .collect();
+        ranges.sort_unstable();
+        if ranges.is_empty() {
+            ranges.push((0, 0));
+        }
+        let main = ranges
+            .iter()
+            .position(|&(start, end)| (start..end).contains(&(self.entry as u64)))
+            .unwrap_or(0);
+        let (start, end) = ranges[main];
+        ranges[main].1 = end.max(start + memory_size as u64).min(1 << 32);
+
+        let mut merged: Vec<(u64, u64)> = Vec::new();
+        for (start, end) in ranges {
+            match merged.last_mut() {
+                Some(last) if start <= last.1 => last.1 = last.1.max(end),
+                _ => merged.push((start, end)),
+            }
+        }
+
+        let mut bus = SystemBus::new();
+        for (start, end) in merged {
+            bus.map_ram(Ram::new(start as u32, (end - start) as usize))?;
+        }
+        Ok(bus)
+    }
+
+    // Copies every segment to its load address and zeroes the
+    // `mem_size - data.len()` tail (.bss and friends).
+    pub fn load_into(&self, bus: &mut SystemBus) -> Result<(), BusError> {
+        for segment in &self.segments {
+            let mut bytes = segment.data.clone();
+            bytes.resize(segment.mem_size as usize, 0);
+            if !bytes.is_empty() {
+                bus.load(segment.addr, &bytes)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn symbol(&self, name: &str) -> Option<u32> {
+        self.symbols.get(name).copied()
+    }
+}
+
+// Loads an image in any of the supported formats. ELF files are recognized
+// by their magic number, the others by extension:
+//
+//   .hex .ihex .ihx                  Intel HEX
+//   .srec .s19 .s28 .s37 .mot        Motorola S-records
+//   .prog .mem                       readmemh word dumps, at `base`
+//   .bin                             flat binary, at `base`
+//
+// `base` defaults to 0 and is rejected for formats that carry their own
+// addresses.
+pub fn load_image(path: &str, base: Option<u32>) -> Result<ElfImage, Box<dyn std::error::Error>> {
+    let bytes = fs::read(path)?;
+    if bytes.starts_with(b"\x7fELF") {
+        return match base {
+            Some(_) => Err("ELF files are loaded at their own addresses".into()),
+            None => ElfImage::parse(&bytes),
+        };
+    }
+
+    let extension = path.rsplit_once('.').map_or("", |(_, extension)| extension);
+    let extension = extension.to_ascii_lowercase();
+    match extension.as_str() {
+        "bin" => return ElfImage::from_binary(&bytes, base.unwrap_or(0)),
+        "prog" | "mem" | "hex" | "ihex" | "ihx" | "srec" | "s19" | "s28" | "s37" | "mot" => {}
+        _ => return Err("unknown image format (not ELF, no known extension)".into()),
+    }
+    let text = String::from_utf8(bytes).map_err(|_| "not a text file")?;
+    match (extension.as_str(), base) {
+        ("prog" | "mem", base) => Ok(ElfImage::from_segments(
+            readmemh::parse(&text, base.unwrap_or(0))?,
+            base,
+        )),
+        (_, Some(_)) => Err("hex images are loaded at the addresses they record".into()),
+        ("hex" | "ihex" | "ihx", None) => Ok(ihex::parse(&text)?),
+        _ => Ok(srec::parse(&text)?),
+    }
+}
+
+// Writes `image` in the format given by the extension of `path`: Intel HEX,
+// readmemh (starting at the lowest segment address) or a flat binary, gaps
+// between segments being zero-filled.
+pub fn save_image(path: &str, image: &ElfImage) -> Result<(), Box<dyn std::error::Error>> {
+    let start = image.segments.iter().map(|s| s.addr).min().unwrap_or(0);
+    let mut out = Vec::new();
+    match path.rsplit_once('.').map_or("", |(_, extension)| extension) {
+        "hex" | "ihex" | "ihx" => ihex::write(&mut out, image)?,
+        "prog" | "mem" => readmemh::write(&mut out, image, start)?,
+        "bin" => {
+            let end = image.segments.iter().map(Segment::end).max().unwrap_or(0);
+            out.resize((end - start as u64) as usize, 0);
+            for segment in &image.segments {
+                let offset = (segment.addr - start) as usize;
+                out[offset..offset + segment.data.len()].copy_from_slice(&segment.data);
+            }
+        }
+        _ => return Err("unknown output format, expected .hex, .prog, .mem or .bin".into()),
+    }
+    Ok(fs::write(path, out)?)
+}
+
+// Appends `bytes` at `addr`, growing the last segment when they directly
+// follow it. The caller checks that they fit in the address space.
+pub(crate) fn push_bytes(segments: &mut Vec<Segment>, addr: u32, bytes: &[u8]) {
+    match segments.last_mut() {
+        Some(segment) if segment.end() == addr as u64 => {
+            segment.data.extend_from_slice(bytes);
+            segment.mem_size += bytes.len() as u32;
+        }
+        _ => segments.push(Segment {
+            addr,
+            data: bytes.to_vec(),
+            mem_size: bytes.len() as u32,
+            writable: true,
+            executable: true,
+        }),
+    }
+}
+
+// Pairs of hex digits, like the body of an Intel HEX or S-record line.
+pub(crate) fn parse_hex_bytes(text: &str) -> Option<Vec<u8>> {
+    if !text.len().is_multiple_of(2) || !text.is_ascii() {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&text[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_bus::Bus;
+    use crate::modules::test_utils::TempDir;
+
+    #[test]
+    fn test_example_segments() {
+        let image = ElfImage::from_file("example/riscv_asm.elf").unwrap();
+
+        assert_eq!(image.entry, image.symbol("_start").unwrap());
+        assert_eq!(image.symbol("__sp"), Some(0xC00));
+        assert!(image.symbol("_rust_entry").is_some());
+
+        assert_eq!(image.segments.len(), 2);
+        let text = &image.segments[0];
+        assert_eq!((text.addr, text.mem_size), (0x0, 0x420));
+        assert!(text.executable && !text.writable);
+        let data = &image.segments[1];
+        assert_eq!((data.addr, data.mem_size), (0x800, 0x130));
+        assert!(data.writable && !data.executable);
+    }
+
+    #[test]
+    fn test_load_zeroes_bss() {
+        let image = ElfImage {
+            entry: 0x8000_0000,
+            segments: vec![Segment {
+                addr: 0x8000_0100,
+                data: vec![1, 2, 3, 4],
+                mem_size: 8,
+                writable: true,
+                executable: false,
+            }],
+            symbols: BTreeMap::new(),
+        };
+
+        let mut bus = image.default_bus(0x1000).unwrap();
+        bus.write32(0x8000_0104, 0xFFFF_FFFF).unwrap();
+        image.load_into(&mut bus).unwrap();
+
+        assert_eq!(bus.read32(0x8000_0100), Ok(0x0403_0201));
+        assert_eq!(bus.read32(0x8000_0104), Ok(0));
+        // The RAM starts at the 4K-aligned base below the segment
+        assert_eq!(bus.read8(0x8000_0000), Ok(0));
+        assert_eq!(bus.read8(0x8000_1000), Err(BusError::Unmapped(0x8000_1000)));
+    }
+
+    #[test]
+    fn test_default_bus_sparse_segments() {
+        let segment = |addr: u32, mem_size: u32| Segment {
+            addr,
+            data: vec![],
+            mem_size,
+            writable: true,
+            executable: false,
+        };
+        // A boot vector at 0 and the program at 0x8000_0000
+        let image = ElfImage {
+            entry: 0x8000_0000,
+            segments: vec![segment(0x8000_0000, 0x2400), segment(0, 0x10)],
+            symbols: BTreeMap::new(),
+        };
+        let bus = image.default_bus(0x1_0000).unwrap();
+        let regions: Vec<_> = bus
+            .memory_regions()
+            .iter()
+            .map(|&(base, data)| (base, data.len()))
+            .collect();
+        assert_eq!(regions, [(0, 0x1000), (0x8000_0000, 0x1_0000)]);
+
+        // Both ends of the address space, and segments sharing a page
+        let image = ElfImage {
+            entry: 0,
+            segments: vec![
+                segment(0, 0x10),
+                segment(0xFFFF_FFF0, 0x10),
+                segment(0x1_0000, 0x800),
+                segment(0x1_0800, 0x1000),
+            ],
+            symbols: BTreeMap::new(),
+        };
+        let bus = image.default_bus(0x4000).unwrap();
+        let regions: Vec<_> = bus
+            .memory_regions()
+            .iter()
+            .map(|&(base, data)| (base, data.len()))
+            .collect();
+        assert_eq!(
+            regions,
+            [(0, 0x4000), (0x1_0000, 0x2000), (0xFFFF_F000, 0x1000)]
+        );
+    }
+
+    #[test]
+    fn test_from_binary() {
+        let image = ElfImage::from_binary(&[0x13, 0, 0, 0], 0x8000_0000).unwrap();
+        assert_eq!(image.entry, 0x8000_0000);
+        assert_eq!(image.segments[0].addr, 0x8000_0000);
+        assert_eq!(image.segments[0].mem_size, 4);
+        assert!(ElfImage::from_binary(&[0; 8], 0xFFFF_FFFC).is_err());
+    }
+
+    #[test]
+    fn test_load_image_formats() {
+        let dir = TempDir::new("loader");
+        let file = |name: &str, contents: &[u8]| {
+            let path = dir.path(name);
+            fs::write(&path, contents).unwrap();
+            path
+        };
+
+        let elf = load_image("example/riscv_asm.elf", None).unwrap();
+        assert_eq!(elf.segments.len(), 2);
+        assert!(load_image("example/riscv_asm.elf", Some(0x100)).is_err());
+
+        let bin = load_image(&file("a.bin", &[1, 2, 3, 4]), Some(0x100)).unwrap();
+        assert_eq!((bin.entry, bin.segments[0].addr), (0x100, 0x100));
+        let mem = load_image(&file("a.mem", b"04030201"), Some(0x100)).unwrap();
+        assert_eq!(mem.segments, bin.segments);
+        let hex = load_image(&file("a.HEX", b":0400000001020304F2\n:00000001FF"), None).unwrap();
+        assert_eq!(hex.segments[0].data, [1, 2, 3, 4]);
+        let srec = load_image(&file("a.s19", b"S107000001020304EE\nS9030000FC"), None).unwrap();
+        assert_eq!(srec.segments, hex.segments);
+
+        assert!(load_image(&file("b.hex", b":00000001FF"), Some(0x100)).is_err());
+
+        // Saving and loading back gives the same image in every format
+        let mut image = ElfImage::from_binary(&[1, 2, 3, 4], 0x800).unwrap();
+        push_bytes(&mut image.segments, 0x808, &[5, 6, 7, 8]);
+        for name in ["c.hex", "c.mem", "c.bin"] {
+            let path = dir.path(name);
+            save_image(&path, &image).unwrap();
+            let saved = load_image(&path, (!name.ends_with("hex")).then_some(0x800)).unwrap();
+            let mut memory = image.default_bus(0x10).unwrap();
+            image.load_into(&mut memory).unwrap();
+            let mut reloaded = saved.default_bus(0x10).unwrap();
+            saved.load_into(&mut reloaded).unwrap();
+            assert_eq!(
+                memory.memory_regions(),
+                reloaded.memory_regions(),
+                "{}",
+                name
+            );
+        }
+        assert!(save_image(&file("c.txt", b""), &image).is_err());
+        assert!(load_image(&file("a.txt", b"hello"), None).is_err());
+    }
+
+    #[test]
+    fn test_rejects_non_elf() {
+        assert!(ElfImage::parse(&[0x7f, b'E', b'L', b'F']).is_err());
+        assert!(ElfImage::parse(b"not an elf").is_err());
+    }
+}
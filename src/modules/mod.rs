@@ -1,4 +1,26 @@
+pub mod asm;
+pub mod clint;
+pub mod coredump;
+pub mod disasm;
+pub mod framebuffer;
+pub mod gdb;
+pub mod ihex;
+pub mod io_page;
+pub mod loader;
+pub mod plic;
+pub mod readmemh;
 pub mod rv32i_alu;
+pub mod rv32i_bus;
+pub mod rv32i_csr;
+pub mod rv32i_error;
+pub mod rv32i_icache;
+pub mod rv32i_instr;
 pub mod rv32i_isa;
 pub mod rv32i_processor;
-pub mod utils;
+pub mod rv32i_timer;
+pub mod rv32i_trap;
+pub mod srec;
+#[cfg(test)]
+pub(crate) mod test_utils;
+pub mod trace;
+pub mod uart;
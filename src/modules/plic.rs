@@ -0,0 +1,387 @@
+// The SiFive-style platform-level interrupt controller of QEMU's `virt`
+// machine and most RISC-V SoCs. Sources are numbered from 1 (0 means "no
+// interrupt"), contexts are the interrupt targets of the harts, and context
+// 0 drives this hart's mip.MEIP:
+//
+//   0x000000 + 4 * source                    priority, 0 (never) to 7
+//   0x001000 + 4 * (source / 32)             pending bits, read-only
+//   0x002000 + 0x80 * context + 4 * word     enable bits
+//   0x200000 + 0x1000 * context              priority threshold
+//   0x200004 + 0x1000 * context              claim (read) / complete (write)
+//
+// Registers are accessed one 32-bit word at a time. Each source goes through
+// a level-triggered gateway: a high line makes it pending, and it can't
+// become pending again between its claim and its completion.
+
+use crate::modules::rv32i_bus::{AccessSize, BusError, Device};
+use crate::modules::rv32i_csr::MEIP;
+use crate::modules::rv32i_trap::InterruptSource;
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Where QEMU's `virt` machine has its PLIC
+pub const PLIC_BASE: u32 = 0x0C00_0000;
+pub const PLIC_SIZE: u32 = 0x0400_0000;
+// The most sources the register layout has room for
+pub const MAX_SOURCES: u32 = 1023;
+
+const PRIORITY: u32 = 0x00_0000;
+const PENDING: u32 = 0x00_1000;
+const ENABLE: u32 = 0x00_2000;
+const ENABLE_STRIDE: u32 = 0x80;
+const CONTEXT: u32 = 0x20_0000;
+const CONTEXT_STRIDE: u32 = 0x1000;
+const MAX_PRIORITY: u32 = 7;
+
+// An interrupt output of a device, e.g. a UART's, for the PLIC to sample.
+pub trait InterruptLine {
+    fn level(&mut self) -> bool;
+}
+
+// Lets a device be mapped on the bus and wired to the PLIC at the same time.
+impl<L: InterruptLine> InterruptLine for Rc<RefCell<L>> {
+    fn level(&mut self) -> bool {
+        self.borrow_mut().level()
+    }
+}
+
+struct Context {
+    enable: Vec<u32>,
+    threshold: u32,
+}
+
+pub struct Plic {
+    num_sources: u32,
+    // Indexed by source, entry 0 unused
+    priority: Vec<u32>,
+    pending: Vec<bool>,
+    claimed: Vec<bool>,
+    contexts: Vec<Context>,
+    lines: Vec<(u32, Box<dyn InterruptLine>)>,
+}
+
+impl Plic {
+    // Sources 1 to `num_sources` (at most MAX_SOURCES), for `num_contexts`
+    // targets. QEMU's `virt` has 2 contexts per hart: M-mode, then S-mode.
+    pub fn new(num_sources: u32, num_contexts: usize) -> Self {
+        let num_sources = num_sources.min(MAX_SOURCES);
+        let words = (num_sources as usize + 1).div_ceil(32);
+        Self {
+            num_sources,
+            priority: vec![0; num_sources as usize + 1],
+            pending: vec![false; num_sources as usize + 1],
+            claimed: vec![false; num_sources as usize + 1],
+            contexts: (0..num_contexts.max(1))
+                .map(|_| Context {
+                    enable: vec![0; words],
+                    threshold: 0,
+                })
+                .collect(),
+            lines: Vec::new(),
+        }
+    }
+
+    // Wires a device's interrupt output to `source`, sampled before every
+    // step of the processor.
+    pub fn connect(&mut self, source: u32, line: Box<dyn InterruptLine>) {
+        assert!(
+            (1..=self.num_sources).contains(&source),
+            "no PLIC source {}",
+            source
+        );
+        self.lines.push((source, line));
+    }
+
+    // Makes `source` pending, like a pulse on its line. Ignored while the
+    // source is claimed.
+    pub fn raise(&mut self, source: u32) {
+        if (1..=self.num_sources).contains(&source) && !self.claimed[source as usize] {
+            self.pending[source as usize] = true;
+        }
+    }
+
+    pub fn is_pending(&self, source: u32) -> bool {
+        self.pending.get(source as usize).copied().unwrap_or(false)
+    }
+
+    // The interrupt `context` would get from a claim, 0 if none: the
+    // enabled pending source with the highest priority above the threshold,
+    // the lowest numbered one on ties.
+    pub fn best(&self, context: usize) -> u32 {
+        let Some(context) = self.contexts.get(context) else {
+            return 0;
+        };
+        let mut best = (0, context.threshold);
+        for source in 1..=self.num_sources {
+            let enabled = context.enable[source as usize / 32] & (1 << (source % 32)) != 0;
+            let priority = self.priority[source as usize];
+            if enabled && self.pending[source as usize] && priority > best.1 {
+                best = (source, priority);
+            }
+        }
+        best.0
+    }
+
+    pub fn claim(&mut self, context: usize) -> u32 {
+        let source = self.best(context);
+        if source != 0 {
+            self.pending[source as usize] = false;
+            self.claimed[source as usize] = true;
+        }
+        source
+    }
+
+    pub fn complete(&mut self, source: u32) {
+        if let Some(claimed) = self.claimed.get_mut(source as usize) {
+            *claimed = false;
+        }
+    }
+
+    fn sample_lines(&mut self) {
+        for index in 0..self.lines.len() {
+            let (source, line) = &mut self.lines[index];
+            let source = *source;
+            if line.level() {
+                self.raise(source);
+            }
+        }
+    }
+
+    // The register at `offset`, as (context, register) for the per-context
+    // ones
+    fn context_register(&self, offset: u32, base: u32, stride: u32) -> Option<(usize, u32)> {
+        let context = ((offset - base) / stride) as usize;
+        (context < self.contexts.len()).then_some((context, (offset - base) % stride))
+    }
+
+    fn pending_word(&self, word: usize) -> u32 {
+        (0..32)
+            .filter(|bit| self.is_pending((word * 32 + bit) as u32))
+            .fold(0, |value, bit| value | 1 << bit)
+    }
+}
+
+impl InterruptSource for Plic {
+    fn pending(&mut self, _cycle: u64) -> u32 {
+        self.sample_lines();
+        if self.best(0) != 0 {
+            MEIP
+        } else {
+            0
+        }
+    }
+}
+
+impl Device for Plic {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        let words = self.contexts[0].enable.len();
+        let value = match offset {
+            _ if size != AccessSize::Word || !offset.is_multiple_of(4) => None,
+            PRIORITY..PENDING => self.priority.get(offset as usize / 4).copied(),
+            PENDING..ENABLE => {
+                let word = (offset - PENDING) as usize / 4;
+                (word < words).then(|| self.pending_word(word))
+            }
+            ENABLE..CONTEXT => self
+                .context_register(offset, ENABLE, ENABLE_STRIDE)
+                .and_then(|(context, register)| {
+                    self.contexts[context]
+                        .enable
+                        .get(register as usize / 4)
+                        .copied()
+                }),
+            _ => match self.context_register(offset, CONTEXT, CONTEXT_STRIDE) {
+                Some((context, 0)) => Some(self.contexts[context].threshold),
+                Some((context, 4)) => Some(self.claim(context)),
+                _ => None,
+            },
+        };
+        value.ok_or(BusError::Device(offset))
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        let words = self.contexts[0].enable.len();
+        let num_sources = self.num_sources;
+        let done = match offset {
+            _ if size != AccessSize::Word || !offset.is_multiple_of(4) => false,
+            PRIORITY..PENDING => match self.priority.get_mut(offset as usize / 4) {
+                // Source 0 doesn't exist, its priority is hardwired to 0
+                Some(_) if offset == PRIORITY => true,
+                Some(priority) => {
+                    *priority = value.min(MAX_PRIORITY);
+                    true
+                }
+                None => false,
+            },
+            // Pending bits are only changed by the gateways and claims
+            PENDING..ENABLE => ((offset - PENDING) as usize / 4) < words,
+            ENABLE..CONTEXT => match self.context_register(offset, ENABLE, ENABLE_STRIDE) {
+                Some((context, register)) => {
+                    let word = register as usize / 4;
+                    // Only the bits of existing sources, never source 0
+                    let valid = (0..32)
+                        .map(|bit| (word * 32 + bit) as u32)
+                        .filter(|&source| (1..=num_sources).contains(&source))
+                        .fold(0u32, |mask, source| mask | 1 << (source % 32));
+                    match self.contexts[context].enable.get_mut(word) {
+                        Some(enable) => {
+                            *enable = value & valid;
+                            true
+                        }
+                        None => false,
+                    }
+                }
+                None => false,
+            },
+            _ => match self.context_register(offset, CONTEXT, CONTEXT_STRIDE) {
+                Some((context, 0)) => {
+                    self.contexts[context].threshold = value.min(MAX_PRIORITY);
+                    true
+                }
+                Some((_, 4)) => {
+                    self.complete(value);
+                    true
+                }
+                _ => false,
+            },
+        };
+        if done {
+            Ok(())
+        } else {
+            Err(BusError::Device(offset))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_processor::StopReason;
+    use crate::modules::test_utils::{run_with_devices, SharedBuffer};
+    use crate::modules::uart::{Uart, UART_BASE, UART_IRQ};
+
+    struct Line(bool);
+
+    impl InterruptLine for Line {
+        fn level(&mut self) -> bool {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_claim_complete() {
+        let mut plic = Plic::new(40, 2);
+        let word = AccessSize::Word;
+        let line = Rc::new(RefCell::new(Line(true)));
+        plic.connect(3, Box::new(line.clone()));
+        plic.write(PRIORITY + 3 * 4, word, 2).unwrap();
+        plic.write(PRIORITY + 33 * 4, word, 9).unwrap();
+        assert_eq!(plic.read(PRIORITY + 33 * 4, word), Ok(MAX_PRIORITY));
+
+        // Pending but not enabled
+        assert_eq!(plic.pending(0), 0);
+        assert_eq!(plic.read(PENDING, word), Ok(1 << 3));
+        plic.write(ENABLE, word, 0xFFFF_FFFF).unwrap();
+        assert_eq!(plic.read(ENABLE, word), Ok(0xFFFF_FFFE));
+        assert_eq!(plic.pending(0), MEIP);
+
+        // The higher priority source is claimed first
+        plic.raise(33);
+        plic.write(ENABLE + 4, word, 0x1FF).unwrap();
+        assert_eq!(plic.read(ENABLE + 4, word), Ok(0x1FF));
+        assert_eq!(plic.read(CONTEXT + 4, word), Ok(33));
+        plic.write(CONTEXT + 4, word, 33).unwrap();
+
+        // A claimed source stays quiet while its line is high, until completed
+        assert_eq!(plic.read(CONTEXT + 4, word), Ok(3));
+        assert_eq!(plic.pending(0), 0);
+        assert_eq!(plic.read(CONTEXT + 4, word), Ok(0));
+        plic.write(CONTEXT + 4, word, 3).unwrap();
+        assert_eq!(plic.pending(0), MEIP);
+
+        // The threshold masks priorities up to its own
+        plic.write(CONTEXT, word, 2).unwrap();
+        assert_eq!(plic.pending(0), 0);
+        plic.write(CONTEXT, word, 1).unwrap();
+        line.borrow_mut().0 = false;
+        assert_eq!(plic.read(CONTEXT + 4, word), Ok(3));
+        plic.write(CONTEXT + 4, word, 3).unwrap();
+        assert_eq!(plic.pending(0), 0);
+
+        // Context 1 has its own enables and doesn't drive MEIP
+        plic.raise(3);
+        plic.write(ENABLE, word, 0).unwrap();
+        plic.write(ENABLE + ENABLE_STRIDE, word, 1 << 3).unwrap();
+        assert_eq!(plic.pending(0), 0);
+        assert_eq!(plic.read(CONTEXT + CONTEXT_STRIDE + 4, word), Ok(3));
+
+        assert_eq!(
+            plic.read(PRIORITY + 41 * 4, word),
+            Err(BusError::Device(164))
+        );
+        assert_eq!(
+            plic.read(CONTEXT + 2 * CONTEXT_STRIDE, word),
+            Err(BusError::Device(0x20_2000))
+        );
+        assert_eq!(
+            plic.read(PENDING, AccessSize::Byte),
+            Err(BusError::Device(PENDING))
+        );
+    }
+
+    #[test]
+    fn test_uart_interrupt_program() {
+        // Echoes received bytes from the UART interrupt handler, sleeping in
+        // between, until a newline
+        let output = SharedBuffer::default();
+        let uart = Rc::new(RefCell::new(Uart::new(Box::new(output.clone()))));
+        uart.borrow_mut().push_input(b"irq\n");
+        let plic = Rc::new(RefCell::new(Plic::new(31, 1)));
+        plic.borrow_mut().connect(UART_IRQ, Box::new(uart.clone()));
+
+        let (_, summary) = run_with_devices(
+            "
+                la t0, handler
+                csrw mtvec, t0
+                li s0, 0x10000000
+                li s1, 0x0c000000
+                li t0, 1
+                sw t0, 40(s1)           # priority of source 10
+                li t1, 0x2000
+                add t1, s1, t1
+                li t0, 0x400
+                sw t0, 0(t1)            # enable source 10 for context 0
+                li t0, 1
+                sb t0, 1(s0)            # UART: interrupt on received data
+                li t0, 0x800
+                csrw mie, t0
+                csrsi mstatus, 8
+            idle:
+                wfi
+                j idle
+            handler:
+                li t1, 0x200004
+                add t1, s1, t1
+                lw t2, 0(t1)            # claim
+                lbu a0, 0(s0)
+                sb a0, 0(s0)
+                sw t2, 0(t1)            # complete
+                li t0, 10
+                beq a0, t0, done
+                mret
+            done:
+                mv a0, t2
+                li a7, 93
+                ecall
+            ",
+            vec![
+                (UART_BASE, 8, Box::new(uart)),
+                (PLIC_BASE, PLIC_SIZE, Box::new(plic.clone())),
+            ],
+            |processor| processor.interrupt_sources.push(Box::new(plic)),
+        );
+        assert_eq!(summary.reason, StopReason::Exit(UART_IRQ));
+        assert_eq!(*output.0.borrow(), b"irq\n");
+    }
+}
@@ -0,0 +1,204 @@
+// Verilog `$readmemh` hex files with one 32-bit word per entry, like the
+// `.prog` and `.mem` dumps the Makefile produces for the FPGA design's
+// PROGROM and DATARAM:
+//
+//   @00000010      // word address, relative to the file's base
+//   00001117 c0010113
+//   dead_beef      /* underscores are ignored */
+//
+// Words are stored little-endian, so word `n` lands at `base + 4 * n`.
+
+use crate::modules::loader::{push_bytes, ElfImage, ImageError, Segment};
+
+use std::fs;
+use std::io::{self, Write};
+
+// Runs of consecutive words, in file order. Later runs overwrite earlier
+// ones where they overlap, like `$readmemh` does.
+pub fn parse(text: &str, base: u32) -> Result<Vec<Segment>, ImageError> {
+    let mut segments: Vec<Segment> = Vec::new();
+    let mut addr = base as u64;
+
+    for (index, line) in strip_block_comments(text).lines().enumerate() {
+        let line_number = index + 1;
+        let line = line.split("//").next().unwrap_or_default();
+
+        for token in line.split_whitespace() {
+            if let Some(word_addr) = token.strip_prefix('@') {
+                let word_addr = parse_hex(word_addr)
+                    .ok_or_else(|| ImageError::new(line_number, "invalid address"))?;
+                addr = base as u64 + word_addr as u64 * 4;
+                continue;
+            }
+
+            let word = parse_hex(token).ok_or_else(|| {
+                ImageError::new(line_number, format!("invalid hex word `{}`", token))
+            })?;
+            if addr + 4 > 1 << 32 {
+                return Err(ImageError::new(line_number, "address out of range"));
+            }
+            push_bytes(&mut segments, addr as u32, &word.to_le_bytes());
+            addr += 4;
+        }
+    }
+    Ok(segments)
+}
+
+// Loads a readmemh file at `base`, with execution starting there.
+pub fn load(path: &str, base: u32) -> Result<ElfImage, Box<dyn std::error::Error>> {
+    let segments = parse(&fs::read_to_string(path)?, base)?;
+    Ok(ElfImage::from_segments(segments, Some(base)))
+}
+
+// Writes `image` one word per line, like the Makefile's dumps, so that
+// `load(path, base)` reads it back. Segments are zero-padded to whole words
+// (`.bss` included) and get an `@` marker unless they directly follow the
+// previous one.
+pub fn write(out: &mut dyn Write, image: &ElfImage, base: u32) -> io::Result<()> {
+    let mut next = base as u64;
+    for segment in &image.segments {
+        if segment.addr < base {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("segment at {:#010x} is below the base", segment.addr),
+            ));
+        }
+        let start = segment.addr & !0x3;
+        if start as u64 != next {
+            writeln!(out, "@{:08x}", (start - base) / 4)?;
+        }
+
+        let mut bytes = vec![0; (segment.addr - start) as usize];
+        bytes.extend_from_slice(&segment.data);
+        bytes.resize(
+            ((segment.addr - start + segment.mem_size) as usize).next_multiple_of(4),
+            0,
+        );
+        for word in bytes.chunks(4) {
+            writeln!(out, "{:08x}", u32::from_le_bytes(word.try_into().unwrap()))?;
+        }
+        next = start as u64 + bytes.len() as u64;
+    }
+    Ok(())
+}
+
+fn parse_hex(text: &str) -> Option<u32> {
+    let digits = text.replace('_', "");
+    if digits.is_empty() || digits.len() > 8 {
+        return None;
+    }
+    u32::from_str_radix(&digits, 16).ok()
+}
+
+// Blanks out `/* ... */` comments, keeping their newlines so that errors
+// still point at the right line.
+fn strip_block_comments(text: &str) -> String {
+    let mut out = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("/*") {
+        out.push_str(&rest[..start]);
+        out.push(' ');
+        let comment = &rest[start..];
+        let end = comment.find("*/").map_or(comment.len(), |end| end + 2);
+        out.extend(comment[..end].chars().filter(|&c| c == '\n'));
+        rest = &comment[end..];
+    }
+    out.push_str(rest);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_processor::{Rv32iProcessor, StopConditions, StopReason};
+    use crate::modules::test_utils::TempDir;
+    use std::fmt::Write;
+
+    #[test]
+    fn test_parse() {
+        let segments = parse(
+            "
+                // PROGROM
+                00001117 c0010113
+                @4 dead_beef /* a
+                comment */ 00000001
+                @2 00000002
+            ",
+            0x800,
+        )
+        .unwrap();
+
+        assert_eq!(segments.len(), 3);
+        assert_eq!(segments[0].addr, 0x800);
+        assert_eq!(segments[0].data, [0x17, 0x11, 0, 0, 0x13, 0x01, 0x01, 0xc0]);
+        assert_eq!((segments[1].addr, segments[1].mem_size), (0x810, 8));
+        assert_eq!(&segments[1].data[..4], &0xdeadbeefu32.to_le_bytes());
+        assert_eq!(segments[2].addr, 0x808);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            parse("0\n/* two\nlines */ 12345678 xyz", 0),
+            Err(ImageError::new(3, "invalid hex word `xyz`"))
+        );
+        assert_eq!(
+            parse("123456789", 0),
+            Err(ImageError::new(1, "invalid hex word `123456789`"))
+        );
+        assert_eq!(parse("@", 0), Err(ImageError::new(1, "invalid address")));
+        assert_eq!(
+            parse("@3fffffff 0 0", 0),
+            Err(ImageError::new(1, "address out of range"))
+        );
+    }
+
+    #[test]
+    fn test_write() {
+        let image = ElfImage::from_segments(
+            parse("00001117 c0010113\n@4 deadbeef", 0x800).unwrap(),
+            None,
+        );
+        let mut out = Vec::new();
+        write(&mut out, &image, 0x800).unwrap();
+        let text = String::from_utf8(out).unwrap();
+
+        assert_eq!(text, "00001117\nc0010113\n@00000004\ndeadbeef\n");
+        assert_eq!(parse(&text, 0x800).unwrap(), image.segments);
+
+        // Unaligned segments are padded, .bss is written out as zeroes
+        let mut image = ElfImage::from_binary(&[0xAA], 0x102).unwrap();
+        image.segments[0].mem_size = 4;
+        let mut out = Vec::new();
+        write(&mut out, &image, 0x100).unwrap();
+        assert_eq!(out, b"00aa0000\n00000000\n");
+        assert!(write(&mut out, &image, 0x200).is_err());
+    }
+
+    // The .prog/.mem pair `make build_elf` dumps with `hexdump -ve '1/4 "%08x\n"'`
+    fn hexdump(data: &[u8]) -> String {
+        data.chunks(4).fold(String::new(), |mut out, chunk| {
+            let mut word = [0; 4];
+            word[..chunk.len()].copy_from_slice(chunk);
+            let _ = writeln!(out, "{:08x}", u32::from_le_bytes(word));
+            out
+        })
+    }
+
+    #[test]
+    fn test_example_hex_files() {
+        let image = ElfImage::from_file("example/riscv_asm.elf").unwrap();
+        let dir = TempDir::new("readmemh");
+        let prog = dir.path("riscv_asm.prog");
+        let mem = dir.path("riscv_asm.mem");
+        fs::write(&prog, hexdump(&image.segments[0].data)).unwrap();
+        fs::write(&mem, hexdump(&image.segments[1].data)).unwrap();
+
+        let mut processor = Rv32iProcessor::new_from_hex_files(&prog, &mem, 0x800).unwrap();
+
+        let summary = processor.run(&StopConditions::default());
+        assert_eq!(summary.reason, StopReason::IdleLoop);
+        assert_eq!(processor.registers[25], 777);
+        assert_eq!(processor.registers[26], 1737);
+    }
+}
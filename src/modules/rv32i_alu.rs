@@ -1,4 +1,3 @@
-use crate::modules::utils;
 pub struct Rv32iAlu {
     pub i_in1: u32,
     pub i_in2: u32,
@@ -30,14 +29,15 @@ impl Rv32iAlu {
     }
 
     pub fn exec(&mut self, in1: u32, in2: u32, funct3: u8, funct7: u8, instr: u32) {
-        let instr_bits = utils::u32_to_bitvec(instr);
+        // instr[5] tells the register (R-type) and immediate opcodes apart
+        let is_rtype = instr & (1 << 5) != 0;
 
         self.i_in1 = in1;
         self.i_in2 = in2;
-        let shamt = if instr_bits[5] == 1 {
-            utils::bitvec_to_u32(&(utils::u32_to_bitvec(self.i_in2)[0..=4]))
+        let shamt = if is_rtype {
+            self.i_in2 & 0x1F
         } else {
-            utils::bitvec_to_u32(&instr_bits[20..=24])
+            (instr >> 20) & 0x1F
         };
 
         let alu_add = self.i_in1.wrapping_add(self.i_in2);
@@ -48,28 +48,23 @@ impl Rv32iAlu {
         self.o_ltu = self.i_in1 < self.i_in2;
         self.o_alu_add = alu_add;
 
-        let mut zeroes = vec![0; 31];
+        // RV32M: R-type with funct7 = 0x01
+        if (funct7 == 0x01) && is_rtype {
+            self.o_out = Rv32iAlu::exec_muldiv(self.i_in1, self.i_in2, funct3);
+            return;
+        }
+
         match funct3 {
             0x0 => {
-                self.o_out = if (funct7 == 0x20) && (instr_bits[5] == 1) {
+                self.o_out = if (funct7 == 0x20) && is_rtype {
                     alu_sub
                 } else {
                     alu_add
                 }
             }
             0x1 => self.o_out = self.i_in1 << shamt,
-            0x2 => {
-                self.o_out = {
-                    zeroes.push(self.o_lt as u8);
-                    utils::bitvec_to_u32(&zeroes)
-                }
-            }
-            0x3 => {
-                self.o_out = {
-                    zeroes.push(self.o_ltu as u8);
-                    utils::bitvec_to_u32(&zeroes)
-                }
-            }
+            0x2 => self.o_out = self.o_lt as u32,
+            0x3 => self.o_out = self.o_ltu as u32,
             0x4 => self.o_out = self.i_in1 ^ self.i_in2,
             0x5 => {
                 self.o_out = {
@@ -87,6 +82,36 @@ impl Rv32iAlu {
             _ => self.o_out = 0,
         }
     }
+
+    // Division by zero and signed overflow don't trap, they return the
+    // values defined by the M extension spec.
+    fn exec_muldiv(in1: u32, in2: u32, funct3: u8) -> u32 {
+        let (s1, s2) = (in1 as i32, in2 as i32);
+        match funct3 {
+            // mul
+            0x0 => in1.wrapping_mul(in2),
+            // mulh
+            0x1 => ((s1 as i64 * s2 as i64) >> 32) as u32,
+            // mulhsu
+            0x2 => ((s1 as i64 * in2 as i64) >> 32) as u32,
+            // mulhu
+            0x3 => ((in1 as u64 * in2 as u64) >> 32) as u32,
+            // div
+            0x4 => match s2 {
+                0 => u32::MAX,
+                _ => s1.wrapping_div(s2) as u32,
+            },
+            // divu
+            0x5 => in1.checked_div(in2).unwrap_or(u32::MAX),
+            // rem
+            0x6 => match s2 {
+                0 => in1,
+                _ => s1.wrapping_rem(s2) as u32,
+            },
+            // remu
+            _ => in1.checked_rem(in2).unwrap_or(in1),
+        }
+    }
 }
 
 #[cfg(test)]
@@ -162,4 +187,61 @@ mod tests {
         alu.exec(in1, in2, 0x2, 0x00, 0x0000_0000);
         assert!(alu.o_lt);
     }
+
+    #[test]
+    fn test_mul() {
+        let mut alu = Rv32iAlu::new();
+        let minus_two = -2i32 as u32;
+
+        // mul x3, x1, x2
+        alu.exec(minus_two, 3, 0x0, 0x01, 0x022081b3);
+        assert_eq!(alu.o_out, -6i32 as u32);
+
+        // mulh x3, x1, x2
+        alu.exec(minus_two, 3, 0x1, 0x01, 0x022091b3);
+        assert_eq!(alu.o_out, 0xFFFF_FFFF);
+
+        // mulhsu x3, x1, x2
+        alu.exec(minus_two, 0xFFFF_FFFF, 0x2, 0x01, 0x0220a1b3);
+        assert_eq!(alu.o_out, 0xFFFF_FFFE);
+
+        // mulhu x3, x1, x2
+        alu.exec(minus_two, 0xFFFF_FFFF, 0x3, 0x01, 0x0220b1b3);
+        assert_eq!(alu.o_out, 0xFFFF_FFFD);
+    }
+
+    #[test]
+    fn test_div_rem() {
+        let mut alu = Rv32iAlu::new();
+        let int_min = i32::MIN as u32;
+        let minus_one = -1i32 as u32;
+
+        // div x3, x1, x2
+        alu.exec(-7i32 as u32, 2, 0x4, 0x01, 0x0220c1b3);
+        assert_eq!(alu.o_out, -3i32 as u32);
+        alu.exec(7, 0, 0x4, 0x01, 0x0220c1b3);
+        assert_eq!(alu.o_out, minus_one);
+        alu.exec(int_min, minus_one, 0x4, 0x01, 0x0220c1b3);
+        assert_eq!(alu.o_out, int_min);
+
+        // divu x3, x1, x2
+        alu.exec(minus_one, 2, 0x5, 0x01, 0x0220d1b3);
+        assert_eq!(alu.o_out, 0x7FFF_FFFF);
+        alu.exec(7, 0, 0x5, 0x01, 0x0220d1b3);
+        assert_eq!(alu.o_out, u32::MAX);
+
+        // rem x3, x1, x2
+        alu.exec(-7i32 as u32, 2, 0x6, 0x01, 0x0220e1b3);
+        assert_eq!(alu.o_out, minus_one);
+        alu.exec(7, 0, 0x6, 0x01, 0x0220e1b3);
+        assert_eq!(alu.o_out, 7);
+        alu.exec(int_min, minus_one, 0x6, 0x01, 0x0220e1b3);
+        assert_eq!(alu.o_out, 0);
+
+        // remu x3, x1, x2
+        alu.exec(7, 0, 0x7, 0x01, 0x0220f1b3);
+        assert_eq!(alu.o_out, 7);
+        alu.exec(minus_one, 10, 0x7, 0x01, 0x0220f1b3);
+        assert_eq!(alu.o_out, 5);
+    }
 }
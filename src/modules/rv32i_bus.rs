@@ -0,0 +1,531 @@
+// The bus is byte addressed: every address seen by the processor, for both
+// instruction fetch and load/store, is the one assigned by the linker.
+// Multi-byte accesses are little-endian, as in the RISC-V spec.
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BusError {
+    // Nothing is mapped at the address
+    Unmapped(u32),
+    // The address belongs to a ROM region
+    ReadOnly(u32),
+    // A device rejected the access (unsupported register or width)
+    Device(u32),
+    // A new region would overlap one that is already mapped
+    Overlap(u32),
+}
+
+impl BusError {
+    pub fn addr(&self) -> u32 {
+        match *self {
+            BusError::Unmapped(addr)
+            | BusError::ReadOnly(addr)
+            | BusError::Device(addr)
+            | BusError::Overlap(addr) => addr,
+        }
+    }
+
+    // Devices report errors relative to their own base, the bus rebases them.
+    fn offset_by(self, base: u32) -> Self {
+        match self {
+            BusError::Unmapped(addr) => BusError::Unmapped(addr.wrapping_add(base)),
+            BusError::ReadOnly(addr) => BusError::ReadOnly(addr.wrapping_add(base)),
+            BusError::Device(addr) => BusError::Device(addr.wrapping_add(base)),
+            BusError::Overlap(addr) => BusError::Overlap(addr.wrapping_add(base)),
+        }
+    }
+}
+
+impl std::fmt::Display for BusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            BusError::Unmapped(addr) => write!(f, "no memory mapped at {:#010x}", addr),
+            BusError::ReadOnly(addr) => write!(f, "write to read-only memory at {:#010x}", addr),
+            BusError::Device(addr) => write!(f, "device access fault at {:#010x}", addr),
+            BusError::Overlap(addr) => write!(f, "region at {:#010x} overlaps another", addr),
+        }
+    }
+}
+
+impl std::error::Error for BusError {}
+
+pub trait Bus {
+    fn read8(&mut self, addr: u32) -> Result<u8, BusError>;
+    fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError>;
+
+    fn read16(&mut self, addr: u32) -> Result<u16, BusError> {
+        let lo = self.read8(addr)?;
+        let hi = self.read8(addr.wrapping_add(1))?;
+        Ok(u16::from_le_bytes([lo, hi]))
+    }
+
+    fn read32(&mut self, addr: u32) -> Result<u32, BusError> {
+        let lo = self.read16(addr)?;
+        let hi = self.read16(addr.wrapping_add(2))?;
+        Ok(lo as u32 | (hi as u32) << 16)
+    }
+
+    fn write16(&mut self, addr: u32, value: u16) -> Result<(), BusError> {
+        let [lo, hi] = value.to_le_bytes();
+        self.write8(addr, lo)?;
+        self.write8(addr.wrapping_add(1), hi)
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), BusError> {
+        self.write16(addr, value as u16)?;
+        self.write16(addr.wrapping_add(2), (value >> 16) as u16)
+    }
+
+    // The RAM and ROM blocks behind the bus as (base, contents), for memory
+    // dumps. Devices are left out, since reading them can have side effects.
+    fn memory_regions(&self) -> Vec<(u32, &[u8])> {
+        Vec::new()
+    }
+
+    // Writes `bytes` straight into one RAM or ROM block, for debuggers. Like
+    // `memory_regions`, it never reaches a device.
+    fn poke(&mut self, addr: u32, _bytes: &[u8]) -> Result<(), BusError> {
+        Err(BusError::Unmapped(addr))
+    }
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AccessSize {
+    Byte = 1,
+    Half = 2,
+    Word = 4,
+}
+
+impl AccessSize {
+    pub fn bytes(self) -> u32 {
+        self as u32
+    }
+}
+
+// A memory-mapped peripheral. Offsets are relative to the base address the
+// device was mapped at, and values are zero-extended to 32 bits.
+pub trait Device {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError>;
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError>;
+}
+
+// Lets a device stay reachable from the host after being mapped on the bus.
+impl<D: Device> Device for Rc<RefCell<D>> {
+    fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+        self.borrow_mut().read(offset, size)
+    }
+
+    fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        self.borrow_mut().write(offset, size, value)
+    }
+}
+
+// A contiguous block of byte-addressed memory starting at `base`.
+pub struct Ram {
+    base: u32,
+    data: Vec<u8>,
+}
+
+impl Ram {
+    pub fn new(base: u32, size: usize) -> Self {
+        Self {
+            base,
+            data: vec![0; size],
+        }
+    }
+
+    pub fn from_bytes(base: u32, bytes: Vec<u8>) -> Self {
+        Self { base, data: bytes }
+    }
+
+    pub fn from_words(base: u32, words: &[u32]) -> Self {
+        let bytes = words.iter().flat_map(|word| word.to_le_bytes()).collect();
+        Self::from_bytes(base, bytes)
+    }
+
+    pub fn base(&self) -> u32 {
+        self.base
+    }
+
+    pub fn size(&self) -> usize {
+        self.data.len()
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.data
+    }
+
+    // Copies `bytes` into memory starting at the bus address `addr`.
+    pub fn load(&mut self, addr: u32, bytes: &[u8]) -> Result<(), BusError> {
+        let offset = self.offset(addr, bytes.len())?;
+        self.data[offset..offset + bytes.len()].copy_from_slice(bytes);
+        Ok(())
+    }
+
+    // Translates a bus address into an offset in `data`, checking that the
+    // whole `len`-byte access fits inside this block.
+    fn offset(&self, addr: u32, len: usize) -> Result<usize, BusError> {
+        let offset = addr.wrapping_sub(self.base) as usize;
+        if addr < self.base || offset + len > self.data.len() {
+            return Err(BusError::Unmapped(addr));
+        }
+        Ok(offset)
+    }
+}
+
+impl Bus for Ram {
+    fn read8(&mut self, addr: u32) -> Result<u8, BusError> {
+        let offset = self.offset(addr, 1)?;
+        Ok(self.data[offset])
+    }
+
+    fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+        let offset = self.offset(addr, 1)?;
+        self.data[offset] = value;
+        Ok(())
+    }
+
+    fn read16(&mut self, addr: u32) -> Result<u16, BusError> {
+        let offset = self.offset(addr, 2)?;
+        Ok(u16::from_le_bytes([
+            self.data[offset],
+            self.data[offset + 1],
+        ]))
+    }
+
+    fn read32(&mut self, addr: u32) -> Result<u32, BusError> {
+        let offset = self.offset(addr, 4)?;
+        Ok(u32::from_le_bytes([
+            self.data[offset],
+            self.data[offset + 1],
+            self.data[offset + 2],
+            self.data[offset + 3],
+        ]))
+    }
+
+    fn write16(&mut self, addr: u32, value: u16) -> Result<(), BusError> {
+        let offset = self.offset(addr, 2)?;
+        self.data[offset..offset + 2].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), BusError> {
+        let offset = self.offset(addr, 4)?;
+        self.data[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    fn memory_regions(&self) -> Vec<(u32, &[u8])> {
+        vec![(self.base, &self.data)]
+    }
+
+    fn poke(&mut self, addr: u32, bytes: &[u8]) -> Result<(), BusError> {
+        self.load(addr, bytes)
+    }
+}
+
+enum Region {
+    Ram(Ram),
+    Rom(Ram),
+    Device {
+        base: u32,
+        size: u32,
+        device: Box<dyn Device>,
+    },
+}
+
+impl Region {
+    fn base(&self) -> u32 {
+        match self {
+            Region::Ram(mem) | Region::Rom(mem) => mem.base,
+            Region::Device { base, .. } => *base,
+        }
+    }
+
+    fn size(&self) -> u32 {
+        match self {
+            Region::Ram(mem) | Region::Rom(mem) => mem.data.len() as u32,
+            Region::Device { size, .. } => *size,
+        }
+    }
+
+    fn contains(&self, addr: u32, len: u32) -> bool {
+        let offset = addr.wrapping_sub(self.base());
+        addr >= self.base() && offset as u64 + len as u64 <= self.size() as u64
+    }
+}
+
+// Routes each access to the RAM, ROM or device region mapped at its address.
+#[derive(Default)]
+pub struct SystemBus {
+    regions: Vec<Region>,
+}
+
+impl SystemBus {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn map_ram(&mut self, ram: Ram) -> Result<(), BusError> {
+        self.map(Region::Ram(ram))
+    }
+
+    pub fn map_rom(&mut self, rom: Ram) -> Result<(), BusError> {
+        self.map(Region::Rom(rom))
+    }
+
+    pub fn map_device(
+        &mut self,
+        base: u32,
+        size: u32,
+        device: Box<dyn Device>,
+    ) -> Result<(), BusError> {
+        self.map(Region::Device { base, size, device })
+    }
+
+    // Writes `bytes` at `addr`, ROM included, the way a programmer would
+    // flash the image before reset. Devices can't be preloaded.
+    pub fn load(&mut self, addr: u32, bytes: &[u8]) -> Result<(), BusError> {
+        let len = bytes.len() as u32;
+        match self.region_mut(addr, len.max(1))? {
+            Region::Ram(mem) | Region::Rom(mem) => mem.load(addr, bytes),
+            Region::Device { .. } => Err(BusError::Device(addr)),
+        }
+    }
+
+    fn map(&mut self, region: Region) -> Result<(), BusError> {
+        let (base, size) = (region.base() as u64, region.size() as u64);
+        let overlaps = self.regions.iter().any(|other| {
+            let (other_base, other_size) = (other.base() as u64, other.size() as u64);
+            base < other_base + other_size && other_base < base + size
+        });
+        if size == 0 || base + size > 1 << 32 || overlaps {
+            return Err(BusError::Overlap(base as u32));
+        }
+        self.regions.push(region);
+        Ok(())
+    }
+
+    fn region_mut(&mut self, addr: u32, len: u32) -> Result<&mut Region, BusError> {
+        self.regions
+            .iter_mut()
+            .find(|region| region.contains(addr, len))
+            .ok_or(BusError::Unmapped(addr))
+    }
+
+    fn read(&mut self, addr: u32, size: AccessSize) -> Result<u32, BusError> {
+        match self.region_mut(addr, size.bytes())? {
+            Region::Ram(mem) | Region::Rom(mem) => match size {
+                AccessSize::Byte => mem.read8(addr).map(|b| b as u32),
+                AccessSize::Half => mem.read16(addr).map(|h| h as u32),
+                AccessSize::Word => mem.read32(addr),
+            },
+            Region::Device { base, device, .. } => {
+                let base = *base;
+                device
+                    .read(addr - base, size)
+                    .map_err(|e| e.offset_by(base))
+            }
+        }
+    }
+
+    fn write(&mut self, addr: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+        match self.region_mut(addr, size.bytes())? {
+            Region::Ram(mem) => match size {
+                AccessSize::Byte => mem.write8(addr, value as u8),
+                AccessSize::Half => mem.write16(addr, value as u16),
+                AccessSize::Word => mem.write32(addr, value),
+            },
+            Region::Rom(_) => Err(BusError::ReadOnly(addr)),
+            Region::Device { base, device, .. } => {
+                let base = *base;
+                device
+                    .write(addr - base, size, value)
+                    .map_err(|e| e.offset_by(base))
+            }
+        }
+    }
+}
+
+impl Bus for SystemBus {
+    fn read8(&mut self, addr: u32) -> Result<u8, BusError> {
+        self.read(addr, AccessSize::Byte).map(|b| b as u8)
+    }
+
+    fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+        self.write(addr, AccessSize::Byte, value as u32)
+    }
+
+    fn read16(&mut self, addr: u32) -> Result<u16, BusError> {
+        self.read(addr, AccessSize::Half).map(|h| h as u16)
+    }
+
+    fn read32(&mut self, addr: u32) -> Result<u32, BusError> {
+        self.read(addr, AccessSize::Word)
+    }
+
+    fn write16(&mut self, addr: u32, value: u16) -> Result<(), BusError> {
+        self.write(addr, AccessSize::Half, value as u32)
+    }
+
+    fn write32(&mut self, addr: u32, value: u32) -> Result<(), BusError> {
+        self.write(addr, AccessSize::Word, value)
+    }
+
+    // In address order
+    fn memory_regions(&self) -> Vec<(u32, &[u8])> {
+        let mut regions: Vec<(u32, &[u8])> = self
+            .regions
+            .iter()
+            .filter_map(|region| match region {
+                Region::Ram(mem) | Region::Rom(mem) => Some((mem.base, mem.as_bytes())),
+                Region::Device { .. } => None,
+            })
+            .collect();
+        regions.sort_by_key(|(base, _)| *base);
+        regions
+    }
+
+    fn poke(&mut self, addr: u32, bytes: &[u8]) -> Result<(), BusError> {
+        self.load(addr, bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_little_endian() {
+        let mut ram = Ram::new(0, 8);
+        ram.write32(0, 0x1234_5678).unwrap();
+
+        assert_eq!(ram.read8(0), Ok(0x78));
+        assert_eq!(ram.read8(3), Ok(0x12));
+        assert_eq!(ram.read16(2), Ok(0x1234));
+        assert_eq!(ram.read32(0), Ok(0x1234_5678));
+    }
+
+    #[test]
+    fn test_base_address() {
+        // Same layout as DATARAM in example/riscv_asm/link.x
+        let mut ram = Ram::new(0x800, 1024);
+        ram.write16(0x802, 0xBEEF).unwrap();
+
+        assert_eq!(ram.read32(0x800), Ok(0xBEEF_0000));
+        assert_eq!(ram.read8(0x7FF), Err(BusError::Unmapped(0x7FF)));
+        assert_eq!(ram.read32(0xBFE), Err(BusError::Unmapped(0xBFE)));
+        assert_eq!(ram.read8(0xC00), Err(BusError::Unmapped(0xC00)));
+    }
+
+    #[test]
+    fn test_default_methods() {
+        // A byte-only bus gets the wider accesses from the trait
+        struct Bytes([u8; 4]);
+        impl Bus for Bytes {
+            fn read8(&mut self, addr: u32) -> Result<u8, BusError> {
+                self.0
+                    .get(addr as usize)
+                    .copied()
+                    .ok_or(BusError::Unmapped(addr))
+            }
+            fn write8(&mut self, addr: u32, value: u8) -> Result<(), BusError> {
+                let byte = self
+                    .0
+                    .get_mut(addr as usize)
+                    .ok_or(BusError::Unmapped(addr))?;
+                *byte = value;
+                Ok(())
+            }
+        }
+
+        let mut bus = Bytes([0; 4]);
+        bus.write32(0, 0xAABB_CCDD).unwrap();
+        assert_eq!(bus.0, [0xDD, 0xCC, 0xBB, 0xAA]);
+        assert_eq!(bus.read16(1), Ok(0xBBCC));
+        assert_eq!(bus.read32(1), Err(BusError::Unmapped(4)));
+    }
+
+    // Records every access so the tests can check what the bus forwarded
+    #[derive(Default)]
+    struct Recorder {
+        accesses: Vec<(u32, AccessSize, Option<u32>)>,
+    }
+
+    impl Device for Recorder {
+        fn read(&mut self, offset: u32, size: AccessSize) -> Result<u32, BusError> {
+            self.accesses.push((offset, size, None));
+            if offset == 0xC {
+                return Err(BusError::Device(offset));
+            }
+            Ok(0xA5)
+        }
+
+        fn write(&mut self, offset: u32, size: AccessSize, value: u32) -> Result<(), BusError> {
+            self.accesses.push((offset, size, Some(value)));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_system_bus_routing() {
+        let recorder = Rc::new(RefCell::new(Recorder::default()));
+        let mut bus = SystemBus::new();
+        bus.map_rom(Ram::from_words(0, &[0x0000_0013])).unwrap();
+        bus.map_ram(Ram::new(0x800, 1024)).unwrap();
+        bus.map_device(0x1000_0000, 0x10, Box::new(recorder.clone()))
+            .unwrap();
+
+        assert_eq!(bus.read32(0), Ok(0x0000_0013));
+        assert_eq!(bus.write8(0, 1), Err(BusError::ReadOnly(0)));
+
+        bus.write16(0x804, 0x1234).unwrap();
+        assert_eq!(bus.read16(0x804), Ok(0x1234));
+
+        bus.write8(0x1000_0004, 0x41).unwrap();
+        assert_eq!(bus.read16(0x1000_0008), Ok(0xA5));
+        assert_eq!(bus.read32(0x1000_000C), Err(BusError::Device(0x1000_000C)));
+        assert_eq!(
+            recorder.borrow().accesses,
+            vec![
+                (4, AccessSize::Byte, Some(0x41)),
+                (8, AccessSize::Half, None),
+                (0xC, AccessSize::Word, None),
+            ]
+        );
+
+        assert_eq!(bus.read8(0x400), Err(BusError::Unmapped(0x400)));
+        // A word straddling the end of the device is not forwarded
+        assert_eq!(
+            bus.read32(0x1000_000E),
+            Err(BusError::Unmapped(0x1000_000E))
+        );
+
+        // Dumps see the ROM and RAM but not the device
+        let regions = bus.memory_regions();
+        assert_eq!(regions.len(), 2);
+        assert_eq!((regions[0].0, regions[0].1.len()), (0, 4));
+        assert_eq!(
+            (regions[1].0, &regions[1].1[4..6]),
+            (0x800, &[0x34, 0x12][..])
+        );
+    }
+
+    #[test]
+    fn test_system_bus_overlap_and_load() {
+        let mut bus = SystemBus::new();
+        bus.map_rom(Ram::new(0, 2048)).unwrap();
+        assert_eq!(
+            bus.map_ram(Ram::new(0x7FC, 16)),
+            Err(BusError::Overlap(0x7FC))
+        );
+        bus.map_ram(Ram::new(0x800, 1024)).unwrap();
+
+        // Loading bypasses the ROM write protection
+        bus.load(0x10, &[0x93, 0x00, 0x50, 0x00]).unwrap();
+        assert_eq!(bus.read32(0x10), Ok(0x0050_0093));
+        assert_eq!(bus.load(0x7FE, &[0; 4]), Err(BusError::Unmapped(0x7FE)));
+    }
+}
@@ -0,0 +1,204 @@
+use crate::modules::rv32i_isa::Extensions;
+
+// Machine-mode CSR addresses
+pub const MSTATUS: u16 = 0x300;
+pub const MISA: u16 = 0x301;
+pub const MIE: u16 = 0x304;
+pub const MTVEC: u16 = 0x305;
+pub const MSCRATCH: u16 = 0x340;
+pub const MEPC: u16 = 0x341;
+pub const MCAUSE: u16 = 0x342;
+pub const MTVAL: u16 = 0x343;
+pub const MIP: u16 = 0x344;
+pub const MVENDORID: u16 = 0xF11;
+pub const MARCHID: u16 = 0xF12;
+pub const MIMPID: u16 = 0xF13;
+pub const MHARTID: u16 = 0xF14;
+pub const MCYCLE: u16 = 0xB00;
+pub const MINSTRET: u16 = 0xB02;
+pub const MCYCLEH: u16 = 0xB80;
+pub const MINSTRETH: u16 = 0xB82;
+
+// Zicntr unprivileged shadows (read-only)
+pub const CYCLE: u16 = 0xC00;
+pub const TIME: u16 = 0xC01;
+pub const INSTRET: u16 = 0xC02;
+pub const CYCLEH: u16 = 0xC80;
+pub const TIMEH: u16 = 0xC81;
+pub const INSTRETH: u16 = 0xC82;
+
+// mstatus fields. Only machine mode exists, so MPP always reads as M (0b11).
+pub const MSTATUS_MIE: u32 = 1 << 3;
+pub const MSTATUS_MPIE: u32 = 1 << 7;
+pub const MSTATUS_MPP: u32 = 0b11 << 11;
+
+// mie/mip bits for the machine software, timer and external interrupts
+pub const MSIP: u32 = 1 << 3;
+pub const MTIP: u32 = 1 << 7;
+pub const MEIP: u32 = 1 << 11;
+
+// misa value for `extensions`: MXL = 1 (32 bits) and one bit per letter.
+pub fn misa(extensions: Extensions) -> u32 {
+    let letter = |c: u8| 1u32 << (c - b'A');
+    let mut misa = 1 << 30 | letter(b'I');
+    if extensions.m {
+        misa |= letter(b'M');
+    }
+    misa
+}
+
+// CSRs with both top address bits set are read-only.
+pub fn is_read_only(addr: u16) -> bool {
+    (addr >> 10) & 0b11 == 0b11
+}
+
+// Storage for the machine-mode CSRs. Writes are WARL: bits that can't hold
+// the written value keep a legal one instead of raising an exception.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct CsrFile {
+    pub mstatus: u32,
+    pub mie: u32,
+    pub mtvec: u32,
+    pub mscratch: u32,
+    pub mepc: u32,
+    pub mcause: u32,
+    pub mtval: u32,
+    // Pending interrupts, driven by the interrupt sources rather than software
+    pub mip: u32,
+    pub mhartid: u32,
+    pub mcycle: u64,
+    pub minstret: u64,
+}
+
+impl CsrFile {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Returns None for CSRs that don't exist. misa lives with the processor
+    // because it depends on the enabled extensions.
+    pub fn read(&self, addr: u16) -> Option<u32> {
+        Some(match addr {
+            MSTATUS => self.mstatus | MSTATUS_MPP,
+            MIE => self.mie,
+            MTVEC => self.mtvec,
+            MSCRATCH => self.mscratch,
+            MEPC => self.mepc,
+            MCAUSE => self.mcause,
+            MTVAL => self.mtval,
+            MIP => self.mip,
+            MVENDORID | MARCHID | MIMPID => 0,
+            MHARTID => self.mhartid,
+            MCYCLE | CYCLE => self.mcycle as u32,
+            MCYCLEH | CYCLEH => (self.mcycle >> 32) as u32,
+            MINSTRET | INSTRET => self.minstret as u32,
+            MINSTRETH | INSTRETH => (self.minstret >> 32) as u32,
+            _ => return None,
+        })
+    }
+
+    // Returns false for CSRs that don't exist or are read-only.
+    pub fn write(&mut self, addr: u16, value: u32) -> bool {
+        match addr {
+            MSTATUS => self.mstatus = value & (MSTATUS_MIE | MSTATUS_MPIE),
+            // Extensions can't be switched at runtime, writes are ignored
+            MISA => {}
+            MIE => self.mie = value & (MSIP | MTIP | MEIP),
+            // MODE 0 is direct, 1 is vectored, anything else falls back to direct
+            MTVEC => {
+                self.mtvec = if value & 0b11 == 1 {
+                    value
+                } else {
+                    value & !0b11
+                }
+            }
+            MSCRATCH => self.mscratch = value,
+            // IALIGN = 32, so the two low bits are always zero
+            MEPC => self.mepc = value & !0b11,
+            MCAUSE => self.mcause = value,
+            MTVAL => self.mtval = value,
+            // MSIP, MTIP and MEIP are set by the CLINT/PLIC, not by software
+            MIP => {}
+            MCYCLE => self.mcycle = set_low(self.mcycle, value),
+            MCYCLEH => self.mcycle = set_high(self.mcycle, value),
+            MINSTRET => self.minstret = set_low(self.minstret, value),
+            MINSTRETH => self.minstret = set_high(self.minstret, value),
+            _ => return false,
+        }
+        true
+    }
+}
+
+fn set_low(counter: u64, value: u32) -> u64 {
+    counter & !0xFFFF_FFFF | value as u64
+}
+
+fn set_high(counter: u64, value: u32) -> u64 {
+    counter & 0xFFFF_FFFF | (value as u64) << 32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_misa() {
+        // MXL = 1, "I"
+        assert_eq!(misa(Extensions::rv32i()), 0x4000_0100);
+        // MXL = 1, "IM"
+        assert_eq!(misa(Extensions::rv32im()), 0x4000_1100);
+    }
+
+    #[test]
+    fn test_warl() {
+        let mut csr = CsrFile::new();
+
+        assert!(csr.write(MSTATUS, 0xFFFF_FFFF));
+        assert_eq!(
+            csr.read(MSTATUS),
+            Some(MSTATUS_MIE | MSTATUS_MPIE | MSTATUS_MPP)
+        );
+
+        assert!(csr.write(MTVEC, 0x8000_0103));
+        assert_eq!(csr.read(MTVEC), Some(0x8000_0100));
+        assert!(csr.write(MTVEC, 0x8000_0101));
+        assert_eq!(csr.read(MTVEC), Some(0x8000_0101));
+
+        assert!(csr.write(MEPC, 0x1003));
+        assert_eq!(csr.read(MEPC), Some(0x1000));
+
+        assert!(csr.write(MIE, 0xFFFF_FFFF));
+        assert_eq!(csr.read(MIE), Some(MSIP | MTIP | MEIP));
+        assert!(csr.write(MIP, 0xFFFF_FFFF));
+        assert_eq!(csr.read(MIP), Some(0));
+    }
+
+    #[test]
+    fn test_read_only_and_missing() {
+        let mut csr = CsrFile::new();
+
+        assert!(is_read_only(MHARTID));
+        assert!(!is_read_only(MSCRATCH));
+        assert_eq!(csr.read(MHARTID), Some(0));
+        assert!(!csr.write(MHARTID, 1));
+        assert_eq!(csr.read(0x7C0), None);
+        assert!(!csr.write(0x7C0, 1));
+    }
+
+    #[test]
+    fn test_counters() {
+        let mut csr = CsrFile::new();
+        csr.mcycle = 0x1_0000_0002;
+
+        assert_eq!(csr.read(CYCLE), Some(2));
+        assert_eq!(csr.read(CYCLEH), Some(1));
+        assert!(is_read_only(CYCLE));
+        assert!(is_read_only(INSTRETH));
+
+        assert!(csr.write(MINSTRETH, 7));
+        assert!(csr.write(MINSTRET, 9));
+        assert_eq!(csr.minstret, 0x7_0000_0009);
+        assert!(csr.write(MCYCLE, 0));
+        assert_eq!(csr.mcycle, 0x1_0000_0000);
+    }
+}
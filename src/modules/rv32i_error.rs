@@ -0,0 +1,92 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AccessKind {
+    Fetch,
+    Load,
+    Store,
+}
+
+impl fmt::Display for AccessKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessKind::Fetch => write!(f, "fetch"),
+            AccessKind::Load => write!(f, "load"),
+            AccessKind::Store => write!(f, "store"),
+        }
+    }
+}
+
+// Everything that can stop `Rv32iProcessor::step` from retiring an
+// instruction. `pc` is the address of the faulting instruction; the
+// processor state is left as it was before that instruction.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum CpuError {
+    FetchFault {
+        pc: u32,
+        addr: u32,
+    },
+    LoadAccessFault {
+        pc: u32,
+        addr: u32,
+    },
+    StoreAccessFault {
+        pc: u32,
+        addr: u32,
+    },
+    IllegalInstruction {
+        pc: u32,
+        instruction: u32,
+    },
+    MisalignedAccess {
+        pc: u32,
+        addr: u32,
+        kind: AccessKind,
+    },
+}
+
+impl CpuError {
+    pub fn pc(&self) -> u32 {
+        match *self {
+            CpuError::FetchFault { pc, .. }
+            | CpuError::LoadAccessFault { pc, .. }
+            | CpuError::StoreAccessFault { pc, .. }
+            | CpuError::IllegalInstruction { pc, .. }
+            | CpuError::MisalignedAccess { pc, .. } => pc,
+        }
+    }
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CpuError::FetchFault { pc, addr } => {
+                write!(
+                    f,
+                    "instruction fetch fault at {:#010x} (pc {:#010x})",
+                    addr, pc
+                )
+            }
+            CpuError::LoadAccessFault { pc, addr } => {
+                write!(f, "load access fault at {:#010x} (pc {:#010x})", addr, pc)
+            }
+            CpuError::StoreAccessFault { pc, addr } => {
+                write!(f, "store access fault at {:#010x} (pc {:#010x})", addr, pc)
+            }
+            CpuError::IllegalInstruction { pc, instruction } => {
+                write!(
+                    f,
+                    "illegal instruction {:#010x} at pc {:#010x}",
+                    instruction, pc
+                )
+            }
+            CpuError::MisalignedAccess { pc, addr, kind } => write!(
+                f,
+                "misaligned {} address {:#010x} (pc {:#010x})",
+                kind, addr, pc
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CpuError {}
@@ -0,0 +1,135 @@
+use crate::modules::rv32i_isa::Rv32iIsa;
+
+pub const DEFAULT_ICACHE_ENTRIES: usize = 4096;
+
+// Direct-mapped cache of decoded instructions, keyed by PC. Illegal
+// encodings are cached too, as `InstrType::Illegal`, so a hit skips fetch,
+// decode and the legality check altogether.
+//
+// Stores made by the processor invalidate the word they touch and fence.i
+// flushes everything. Code patched behind the processor's back (through
+// `bus` directly, or after changing `extensions`) needs a `flush()`.
+#[derive(Debug, Clone)]
+pub struct DecodeCache {
+    entries: Vec<Option<(u32, Rv32iIsa)>>,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl Default for DecodeCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_ICACHE_ENTRIES)
+    }
+}
+
+impl DecodeCache {
+    // `entries` must be a power of two; 0 disables the cache.
+    pub fn new(entries: usize) -> Self {
+        assert!(
+            entries == 0 || entries.is_power_of_two(),
+            "cache size must be a power of two"
+        );
+        Self {
+            entries: vec![None; entries],
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    pub fn disabled() -> Self {
+        Self::new(0)
+    }
+
+    pub fn get(&mut self, pc: u32) -> Option<Rv32iIsa> {
+        let hit = self
+            .slot(pc)
+            .and_then(|index| self.entries[index])
+            .filter(|(tag, _)| *tag == pc)
+            .map(|(_, isa)| isa);
+        match hit {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        hit
+    }
+
+    pub fn insert(&mut self, pc: u32, isa: Rv32iIsa) {
+        if let Some(index) = self.slot(pc) {
+            self.entries[index] = Some((pc, isa));
+        }
+    }
+
+    // Drops the instruction containing byte `addr`, if cached.
+    pub fn invalidate(&mut self, addr: u32) {
+        let pc = addr & !0x3;
+        if let Some(index) = self.slot(pc) {
+            if matches!(self.entries[index], Some((tag, _)) if tag == pc) {
+                self.entries[index] = None;
+            }
+        }
+    }
+
+    pub fn flush(&mut self) {
+        self.entries.fill(None);
+    }
+
+    fn slot(&self, pc: u32) -> Option<usize> {
+        if self.entries.is_empty() {
+            None
+        } else {
+            Some((pc >> 2) as usize & (self.entries.len() - 1))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoded(instruction: u32) -> Rv32iIsa {
+        let mut isa = Rv32iIsa::new(instruction);
+        isa.parse_instr();
+        isa
+    }
+
+    #[test]
+    fn test_hit_and_conflict() {
+        let mut cache = DecodeCache::new(4);
+        let addi = decoded(0x00500093);
+
+        assert_eq!(cache.get(0x100), None);
+        cache.insert(0x100, addi);
+        assert_eq!(cache.get(0x100), Some(addi));
+        // Same slot, different tag
+        assert_eq!(cache.get(0x110), None);
+        cache.insert(0x110, decoded(0x00000013));
+        assert_eq!(cache.get(0x100), None);
+        assert_eq!((cache.hits, cache.misses), (1, 3));
+    }
+
+    #[test]
+    fn test_invalidate() {
+        let mut cache = DecodeCache::new(16);
+        cache.insert(0x100, decoded(0x00500093));
+        cache.insert(0x104, decoded(0x00500093));
+
+        // A byte store in the middle of the word
+        cache.invalidate(0x102);
+        assert_eq!(cache.get(0x100), None);
+        assert!(cache.get(0x104).is_some());
+        // An address that maps to the same slot doesn't evict it
+        cache.invalidate(0x144);
+        assert!(cache.get(0x104).is_some());
+
+        cache.flush();
+        assert_eq!(cache.get(0x104), None);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut cache = DecodeCache::disabled();
+        cache.insert(0x100, decoded(0x00500093));
+        assert_eq!(cache.get(0x100), None);
+        cache.invalidate(0x100);
+    }
+}
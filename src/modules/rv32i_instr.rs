@@ -0,0 +1,428 @@
+use crate::modules::rv32i_isa::{Extensions, Rv32iIsa};
+
+// A fully decoded instruction. Unlike the `o_*` signals of `Rv32iIsa`, every
+// field here is meaningful: immediates are sign-extended (U-type ones are
+// already shifted into place) and funct3/funct7 have been folded into the
+// variant.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Instruction {
+    Lui { rd: u8, imm: u32 },
+    Auipc { rd: u8, imm: u32 },
+    Jal { rd: u8, offset: i32 },
+    Jalr { rd: u8, rs1: u8, offset: i32 },
+
+    Beq { rs1: u8, rs2: u8, offset: i32 },
+    Bne { rs1: u8, rs2: u8, offset: i32 },
+    Blt { rs1: u8, rs2: u8, offset: i32 },
+    Bge { rs1: u8, rs2: u8, offset: i32 },
+    Bltu { rs1: u8, rs2: u8, offset: i32 },
+    Bgeu { rs1: u8, rs2: u8, offset: i32 },
+
+    Lb { rd: u8, rs1: u8, offset: i32 },
+    Lh { rd: u8, rs1: u8, offset: i32 },
+    Lw { rd: u8, rs1: u8, offset: i32 },
+    Lbu { rd: u8, rs1: u8, offset: i32 },
+    Lhu { rd: u8, rs1: u8, offset: i32 },
+    Sb { rs1: u8, rs2: u8, offset: i32 },
+    Sh { rs1: u8, rs2: u8, offset: i32 },
+    Sw { rs1: u8, rs2: u8, offset: i32 },
+
+    Addi { rd: u8, rs1: u8, imm: i32 },
+    Slti { rd: u8, rs1: u8, imm: i32 },
+    Sltiu { rd: u8, rs1: u8, imm: i32 },
+    Xori { rd: u8, rs1: u8, imm: i32 },
+    Ori { rd: u8, rs1: u8, imm: i32 },
+    Andi { rd: u8, rs1: u8, imm: i32 },
+    Slli { rd: u8, rs1: u8, shamt: u8 },
+    Srli { rd: u8, rs1: u8, shamt: u8 },
+    Srai { rd: u8, rs1: u8, shamt: u8 },
+
+    Add { rd: u8, rs1: u8, rs2: u8 },
+    Sub { rd: u8, rs1: u8, rs2: u8 },
+    Sll { rd: u8, rs1: u8, rs2: u8 },
+    Slt { rd: u8, rs1: u8, rs2: u8 },
+    Sltu { rd: u8, rs1: u8, rs2: u8 },
+    Xor { rd: u8, rs1: u8, rs2: u8 },
+    Srl { rd: u8, rs1: u8, rs2: u8 },
+    Sra { rd: u8, rs1: u8, rs2: u8 },
+    Or { rd: u8, rs1: u8, rs2: u8 },
+    And { rd: u8, rs1: u8, rs2: u8 },
+
+    // M extension
+    Mul { rd: u8, rs1: u8, rs2: u8 },
+    Mulh { rd: u8, rs1: u8, rs2: u8 },
+    Mulhsu { rd: u8, rs1: u8, rs2: u8 },
+    Mulhu { rd: u8, rs1: u8, rs2: u8 },
+    Div { rd: u8, rs1: u8, rs2: u8 },
+    Divu { rd: u8, rs1: u8, rs2: u8 },
+    Rem { rd: u8, rs1: u8, rs2: u8 },
+    Remu { rd: u8, rs1: u8, rs2: u8 },
+
+    // pred and succ are the IORW bit sets (i = 8, o = 4, r = 2, w = 1)
+    Fence { pred: u8, succ: u8 },
+    // Zifencei
+    FenceI,
+
+    Ecall,
+    Ebreak,
+    Mret,
+    Wfi,
+
+    // Zicsr
+    Csrrw { rd: u8, rs1: u8, csr: u16 },
+    Csrrs { rd: u8, rs1: u8, csr: u16 },
+    Csrrc { rd: u8, rs1: u8, csr: u16 },
+    Csrrwi { rd: u8, uimm: u8, csr: u16 },
+    Csrrsi { rd: u8, uimm: u8, csr: u16 },
+    Csrrci { rd: u8, uimm: u8, csr: u16 },
+
+    // Any encoding that isn't one of the above (or needs a disabled extension)
+    Illegal(u32),
+}
+
+impl Instruction {
+    pub fn is_illegal(&self) -> bool {
+        matches!(self, Instruction::Illegal(_))
+    }
+}
+
+impl Rv32iIsa {
+    // Typed view of `i_instruction`, next to the signal-level one.
+    pub fn decode(&self, extensions: Extensions) -> Instruction {
+        decode_with(self.i_instruction, extensions)
+    }
+}
+
+// Decodes every instruction the emulator knows about (RV32IM + Zicsr +
+// Zifencei).
+pub fn decode(instruction: u32) -> Instruction {
+    decode_with(instruction, Extensions::rv32im())
+}
+
+// Like `decode`, but instructions from disabled extensions are illegal.
+pub fn decode_with(instruction: u32, extensions: Extensions) -> Instruction {
+    use Instruction::*;
+
+    let rd = ((instruction >> 7) & 0x1F) as u8;
+    let rs1 = ((instruction >> 15) & 0x1F) as u8;
+    let rs2 = ((instruction >> 20) & 0x1F) as u8;
+    let funct3 = (instruction >> 12) & 0x7;
+    let funct7 = instruction >> 25;
+
+    match instruction & 0x7F {
+        0b011_0111 => Lui {
+            rd,
+            imm: instruction & 0xFFFF_F000,
+        },
+        0b001_0111 => Auipc {
+            rd,
+            imm: instruction & 0xFFFF_F000,
+        },
+        0b110_1111 => Jal {
+            rd,
+            offset: imm_j(instruction),
+        },
+        0b110_0111 if funct3 == 0 => Jalr {
+            rd,
+            rs1,
+            offset: imm_i(instruction),
+        },
+        0b110_0011 => {
+            let offset = imm_b(instruction);
+            match funct3 {
+                0x0 => Beq { rs1, rs2, offset },
+                0x1 => Bne { rs1, rs2, offset },
+                0x4 => Blt { rs1, rs2, offset },
+                0x5 => Bge { rs1, rs2, offset },
+                0x6 => Bltu { rs1, rs2, offset },
+                0x7 => Bgeu { rs1, rs2, offset },
+                _ => Illegal(instruction),
+            }
+        }
+        0b000_0011 => {
+            let offset = imm_i(instruction);
+            match funct3 {
+                0x0 => Lb { rd, rs1, offset },
+                0x1 => Lh { rd, rs1, offset },
+                0x2 => Lw { rd, rs1, offset },
+                0x4 => Lbu { rd, rs1, offset },
+                0x5 => Lhu { rd, rs1, offset },
+                _ => Illegal(instruction),
+            }
+        }
+        0b010_0011 => {
+            let offset = imm_s(instruction);
+            match funct3 {
+                0x0 => Sb { rs1, rs2, offset },
+                0x1 => Sh { rs1, rs2, offset },
+                0x2 => Sw { rs1, rs2, offset },
+                _ => Illegal(instruction),
+            }
+        }
+        0b001_0011 => {
+            let imm = imm_i(instruction);
+            let shamt = rs2;
+            match (funct3, funct7) {
+                (0x0, _) => Addi { rd, rs1, imm },
+                (0x2, _) => Slti { rd, rs1, imm },
+                (0x3, _) => Sltiu { rd, rs1, imm },
+                (0x4, _) => Xori { rd, rs1, imm },
+                (0x6, _) => Ori { rd, rs1, imm },
+                (0x7, _) => Andi { rd, rs1, imm },
+                (0x1, 0x00) => Slli { rd, rs1, shamt },
+                (0x5, 0x00) => Srli { rd, rs1, shamt },
+                (0x5, 0x20) => Srai { rd, rs1, shamt },
+                _ => Illegal(instruction),
+            }
+        }
+        0b011_0011 => match (funct7, funct3) {
+            (0x00, 0x0) => Add { rd, rs1, rs2 },
+            (0x20, 0x0) => Sub { rd, rs1, rs2 },
+            (0x00, 0x1) => Sll { rd, rs1, rs2 },
+            (0x00, 0x2) => Slt { rd, rs1, rs2 },
+            (0x00, 0x3) => Sltu { rd, rs1, rs2 },
+            (0x00, 0x4) => Xor { rd, rs1, rs2 },
+            (0x00, 0x5) => Srl { rd, rs1, rs2 },
+            (0x20, 0x5) => Sra { rd, rs1, rs2 },
+            (0x00, 0x6) => Or { rd, rs1, rs2 },
+            (0x00, 0x7) => And { rd, rs1, rs2 },
+            (0x01, _) if extensions.m => match funct3 {
+                0x0 => Mul { rd, rs1, rs2 },
+                0x1 => Mulh { rd, rs1, rs2 },
+                0x2 => Mulhsu { rd, rs1, rs2 },
+                0x3 => Mulhu { rd, rs1, rs2 },
+                0x4 => Div { rd, rs1, rs2 },
+                0x5 => Divu { rd, rs1, rs2 },
+                0x6 => Rem { rd, rs1, rs2 },
+                _ => Remu { rd, rs1, rs2 },
+            },
+            _ => Illegal(instruction),
+        },
+        // The fm, rs1 and rd fields are reserved and ignored, so fence.tso
+        // and the hint encodings are plain fences
+        0b000_1111 => match funct3 {
+            0x0 => Fence {
+                pred: ((instruction >> 24) & 0xF) as u8,
+                succ: ((instruction >> 20) & 0xF) as u8,
+            },
+            0x1 => FenceI,
+            _ => Illegal(instruction),
+        },
+        0b111_0011 => {
+            let csr = (instruction >> 20) as u16;
+            let uimm = rs1;
+            match funct3 {
+                // The whole word is fixed for these
+                0x0 => match instruction {
+                    0x0000_0073 => Ecall,
+                    0x0010_0073 => Ebreak,
+                    0x3020_0073 => Mret,
+                    0x1050_0073 => Wfi,
+                    _ => Illegal(instruction),
+                },
+                0x1 => Csrrw { rd, rs1, csr },
+                0x2 => Csrrs { rd, rs1, csr },
+                0x3 => Csrrc { rd, rs1, csr },
+                0x5 => Csrrwi { rd, uimm, csr },
+                0x6 => Csrrsi { rd, uimm, csr },
+                0x7 => Csrrci { rd, uimm, csr },
+                _ => Illegal(instruction),
+            }
+        }
+        _ => Illegal(instruction),
+    }
+}
+
+fn imm_i(instruction: u32) -> i32 {
+    instruction as i32 >> 20
+}
+
+fn imm_s(instruction: u32) -> i32 {
+    (instruction as i32 >> 25) << 5 | ((instruction >> 7) & 0x1F) as i32
+}
+
+fn imm_b(instruction: u32) -> i32 {
+    (instruction as i32 >> 31) << 12
+        | (((instruction >> 7) & 0x1) << 11) as i32
+        | (((instruction >> 25) & 0x3F) << 5) as i32
+        | (((instruction >> 8) & 0xF) << 1) as i32
+}
+
+fn imm_j(instruction: u32) -> i32 {
+    (instruction as i32 >> 31) << 20
+        | (((instruction >> 12) & 0xFF) << 12) as i32
+        | (((instruction >> 20) & 0x1) << 11) as i32
+        | (((instruction >> 21) & 0x3FF) << 1) as i32
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::loader::ElfImage;
+    use Instruction::*;
+
+    #[test]
+    fn test_decode() {
+        // lui x8, 1339
+        assert_eq!(
+            decode(0x0053b437),
+            Lui {
+                rd: 8,
+                imm: 1339 << 12
+            }
+        );
+        // jal x8, 44
+        assert_eq!(decode(0x02c0046f), Jal { rd: 8, offset: 44 });
+        // j -16
+        assert_eq!(decode(0xff1ff06f), Jal { rd: 0, offset: -16 });
+        // beq x5, x2, 74
+        assert_eq!(
+            decode(0x04228563),
+            Beq {
+                rs1: 5,
+                rs2: 2,
+                offset: 74
+            }
+        );
+        // bne x1, x3, -4
+        assert_eq!(
+            decode(0xfe309ee3),
+            Bne {
+                rs1: 1,
+                rs2: 3,
+                offset: -4
+            }
+        );
+        // sw x5, 88(x2)
+        assert_eq!(
+            decode(0x04512c23),
+            Sw {
+                rs1: 2,
+                rs2: 5,
+                offset: 88
+            }
+        );
+        // lb x5, 1(x3)
+        assert_eq!(
+            decode(0x00118283),
+            Lb {
+                rd: 5,
+                rs1: 3,
+                offset: 1
+            }
+        );
+        // addi a2, x0, -1
+        assert_eq!(
+            decode(0xfff00613),
+            Addi {
+                rd: 12,
+                rs1: 0,
+                imm: -1
+            }
+        );
+        // srai x1, x2, 4
+        assert_eq!(
+            decode(0x40415093),
+            Srai {
+                rd: 1,
+                rs1: 2,
+                shamt: 4
+            }
+        );
+        // sub x5, x1, x2
+        assert_eq!(
+            decode(0x402082b3),
+            Sub {
+                rd: 5,
+                rs1: 1,
+                rs2: 2
+            }
+        );
+        // mul x3, x1, x2
+        assert_eq!(
+            decode(0x022081b3),
+            Mul {
+                rd: 3,
+                rs1: 1,
+                rs2: 2
+            }
+        );
+        // csrrsi x3, mscratch, 4
+        assert_eq!(
+            decode(0x340261f3),
+            Csrrsi {
+                rd: 3,
+                uimm: 4,
+                csr: 0x340
+            }
+        );
+        // fence iorw, iorw
+        assert_eq!(
+            decode(0x0ff0000f),
+            Fence {
+                pred: 0xF,
+                succ: 0xF
+            }
+        );
+        // fence r, rw
+        assert_eq!(decode(0x0230000f), Fence { pred: 2, succ: 3 });
+        assert_eq!(decode(0x0000100f), FenceI);
+        assert_eq!(decode(0x00000073), Ecall);
+        assert_eq!(decode(0x30200073), Mret);
+    }
+
+    #[test]
+    fn test_illegal() {
+        for instruction in [
+            0x0000_0000, // all zeroes
+            0xFFFF_FFFF, // all ones
+            0x0020_b183, // ld
+            0x0000_3023, // sd
+            0x0000_2063, // branch funct3 = 2
+            0x0000_1067, // jalr funct3 = 1
+            0x4020_92b3, // sll with funct7 = 0x20
+            0x4000_9093, // slli with funct7 = 0x20
+            0x0200_d093, // srli with funct7 = 0x01
+            0x0000_4073, // SYSTEM funct3 = 4
+            0x0000_00f3, // ecall with rd = 1
+            0x0000_200f, // MISC-MEM funct3 = 2 (cbo.*)
+        ] {
+            assert_eq!(decode(instruction), Illegal(instruction));
+        }
+
+        // mul is only legal with the M extension
+        assert!(decode_with(0x022081b3, Extensions::rv32i()).is_illegal());
+        assert!(!decode_with(0x022081b3, Extensions::rv32im()).is_illegal());
+    }
+
+    #[test]
+    fn test_matches_signal_view() {
+        // Every legal instruction in the example agrees with the o_* signals
+        let image = ElfImage::from_file("example/riscv_asm.elf").unwrap();
+        let text = &image.segments[0].data;
+        for word in text.chunks_exact(4) {
+            let word = u32::from_le_bytes(word.try_into().unwrap());
+            let mut isa = Rv32iIsa::new(word);
+            isa.parse_instr();
+
+            match isa.decode(Extensions::rv32i()) {
+                Addi { rd, rs1, imm }
+                | Lw {
+                    rd,
+                    rs1,
+                    offset: imm,
+                } => {
+                    assert_eq!((rd, rs1, imm as u32), (isa.o_rd, isa.o_rs1, isa.o_imm))
+                }
+                Sw { rs1, rs2, offset } => {
+                    assert_eq!((rs1, rs2, offset as u32), (isa.o_rs1, isa.o_rs2, isa.o_imm))
+                }
+                Beq { offset, .. } | Bne { offset, .. } | Jal { offset, .. } => {
+                    assert_eq!(offset as u32, isa.o_imm)
+                }
+                Lui { rd, imm } | Auipc { rd, imm } => assert_eq!((rd, imm), (isa.o_rd, isa.o_imm)),
+                _ => {}
+            }
+        }
+    }
+}
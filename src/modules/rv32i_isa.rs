@@ -1,5 +1,3 @@
-use crate::modules::utils;
-
 #[derive(Debug, PartialEq, Clone, Copy)]
 pub enum InstrType {
     AluRtype,
@@ -12,9 +10,29 @@ pub enum InstrType {
     LuiUtype,
     AuipcUtype,
     SystemItype,
+    // MISC-MEM: fence and fence.i
+    FenceItype,
     Illegal,
 }
 
+// Optional ISA extensions accepted by the decoder on top of RV32I.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub struct Extensions {
+    // Integer multiply/divide (funct7 = 0x01 on R-type ALU opcodes)
+    pub m: bool,
+}
+
+impl Extensions {
+    pub fn rv32i() -> Self {
+        Self::default()
+    }
+
+    pub fn rv32im() -> Self {
+        Self { m: true }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub struct Rv32iIsa {
     pub i_instruction: u32,
     pub o_instrtype: InstrType,
@@ -47,89 +65,69 @@ impl Rv32iIsa {
         }
     }
     pub fn parse_instr(&mut self) {
-        let bits_instruction = utils::u32_to_bitvec(self.i_instruction);
-
-        match utils::bitvec_to_u32(&bits_instruction[0..=6]) {
-            0b011_0011 => self.o_instrtype = InstrType::AluRtype,
-            0b001_0011 => self.o_instrtype = InstrType::AluItype,
-            0b000_0011 => self.o_instrtype = InstrType::LoadItype,
-            0b010_0011 => self.o_instrtype = InstrType::StoreStype,
-            0b110_0011 => self.o_instrtype = InstrType::BranchBtype,
-            0b110_1111 => self.o_instrtype = InstrType::JalJtype,
-            0b110_0111 => self.o_instrtype = InstrType::JalrItype,
-            0b011_0111 => self.o_instrtype = InstrType::LuiUtype,
-            0b001_0111 => self.o_instrtype = InstrType::AuipcUtype,
-            0b111_0011 => self.o_instrtype = InstrType::SystemItype,
-            _ => self.o_instrtype = InstrType::Illegal,
-        }
-
-        match self.o_instrtype {
+        let instruction = self.i_instruction;
+
+        self.o_instrtype = match instruction & 0x7F {
+            0b011_0011 => InstrType::AluRtype,
+            0b001_0011 => InstrType::AluItype,
+            0b000_0011 => InstrType::LoadItype,
+            0b010_0011 => InstrType::StoreStype,
+            0b110_0011 => InstrType::BranchBtype,
+            0b110_1111 => InstrType::JalJtype,
+            0b110_0111 => InstrType::JalrItype,
+            0b011_0111 => InstrType::LuiUtype,
+            0b001_0111 => InstrType::AuipcUtype,
+            0b111_0011 => InstrType::SystemItype,
+            0b000_1111 => InstrType::FenceItype,
+            _ => InstrType::Illegal,
+        };
+
+        self.o_imm = match self.o_instrtype {
             InstrType::AluItype
             | InstrType::LoadItype
             | InstrType::JalrItype
-            | InstrType::SystemItype => self.o_imm = Rv32iIsa::parse_imm_itype(&bits_instruction),
-            InstrType::StoreStype => self.o_imm = Rv32iIsa::parse_imm_stype(&bits_instruction),
-            InstrType::BranchBtype => self.o_imm = Rv32iIsa::parse_imm_btype(&bits_instruction),
-            InstrType::JalJtype => self.o_imm = Rv32iIsa::parse_imm_jtype(&bits_instruction),
-            InstrType::LuiUtype | InstrType::AuipcUtype => {
-                self.o_imm = Rv32iIsa::parse_imm_utype(&bits_instruction)
-            }
-            _ => self.o_imm = 0b0000_00000,
-        }
+            | InstrType::SystemItype
+            | InstrType::FenceItype => Rv32iIsa::parse_imm_itype(instruction),
+            InstrType::StoreStype => Rv32iIsa::parse_imm_stype(instruction),
+            InstrType::BranchBtype => Rv32iIsa::parse_imm_btype(instruction),
+            InstrType::JalJtype => Rv32iIsa::parse_imm_jtype(instruction),
+            InstrType::LuiUtype | InstrType::AuipcUtype => Rv32iIsa::parse_imm_utype(instruction),
+            _ => 0,
+        };
 
-        // TODO replace the as u8 to a more robust solution
-        self.o_rs1 = utils::bitvec_to_u32(&bits_instruction[15..=19]) as u8;
-        self.o_rs2 = utils::bitvec_to_u32(&bits_instruction[20..=24]) as u8;
-        self.o_rd = utils::bitvec_to_u32(&bits_instruction[7..=11]) as u8;
-        self.o_funct3 = utils::bitvec_to_u32(&bits_instruction[12..=14]) as u8;
-        self.o_funct7 = utils::bitvec_to_u32(&bits_instruction[25..=31]) as u8;
+        self.o_rs1 = ((instruction >> 15) & 0x1F) as u8;
+        self.o_rs2 = ((instruction >> 20) & 0x1F) as u8;
+        self.o_rd = ((instruction >> 7) & 0x1F) as u8;
+        self.o_funct3 = ((instruction >> 12) & 0x7) as u8;
+        self.o_funct7 = (instruction >> 25) as u8;
     }
 
-    fn parse_imm_itype(bits: &[u8]) -> u32 {
-        let mut imm_bitvec = Vec::new();
-        imm_bitvec.extend_from_slice(&bits[20..=30]);
-        imm_bitvec.extend_from_slice(&[bits[31]; 21]);
-
-        utils::bitvec_to_u32(&imm_bitvec)
+    // The immediates are sign-extended from instruction[31] and returned as
+    // raw 32-bit values, like the o_imm bus of the hardware decoder.
+    fn parse_imm_itype(instruction: u32) -> u32 {
+        (instruction as i32 >> 20) as u32
     }
 
-    fn parse_imm_stype(bits: &[u8]) -> u32 {
-        let mut imm_bitvec = Vec::new();
-        imm_bitvec.extend_from_slice(&bits[7..=11]);
-        imm_bitvec.extend_from_slice(&bits[25..=30]);
-        imm_bitvec.extend_from_slice(&[bits[31]; 21]);
-
-        utils::bitvec_to_u32(&imm_bitvec)
+    fn parse_imm_stype(instruction: u32) -> u32 {
+        ((instruction as i32 >> 25) << 5) as u32 | ((instruction >> 7) & 0x1F)
     }
 
-    fn parse_imm_btype(bits: &[u8]) -> u32 {
-        let mut imm_bitvec = Vec::new();
-        imm_bitvec.push(0u8);
-        imm_bitvec.extend_from_slice(&bits[8..=11]);
-        imm_bitvec.extend_from_slice(&bits[25..=30]);
-        imm_bitvec.push(bits[7]);
-        imm_bitvec.extend_from_slice(&[bits[31]; 20]);
-
-        utils::bitvec_to_u32(&imm_bitvec)
+    fn parse_imm_btype(instruction: u32) -> u32 {
+        ((instruction as i32 >> 31) << 12) as u32
+            | ((instruction >> 7) & 0x1) << 11
+            | ((instruction >> 25) & 0x3F) << 5
+            | ((instruction >> 8) & 0xF) << 1
     }
 
-    fn parse_imm_jtype(bits: &[u8]) -> u32 {
-        let mut imm_bitvec = Vec::new();
-        imm_bitvec.push(0u8);
-        imm_bitvec.extend_from_slice(&bits[21..=30]);
-        imm_bitvec.push(bits[20]);
-        imm_bitvec.extend_from_slice(&bits[12..=19]);
-        imm_bitvec.extend_from_slice(&[bits[31]; 12]);
-
-        utils::bitvec_to_u32(&imm_bitvec)
+    fn parse_imm_jtype(instruction: u32) -> u32 {
+        ((instruction as i32 >> 31) << 20) as u32
+            | ((instruction >> 12) & 0xFF) << 12
+            | ((instruction >> 20) & 0x1) << 11
+            | ((instruction >> 21) & 0x3FF) << 1
     }
 
-    fn parse_imm_utype(bits: &[u8]) -> u32 {
-        let mut imm_bitvec = Vec::new();
-        imm_bitvec.extend_from_slice(&[0u8; 12]);
-        imm_bitvec.extend_from_slice(&bits[12..=31]);
-
-        utils::bitvec_to_u32(&imm_bitvec)
+    fn parse_imm_utype(instruction: u32) -> u32 {
+        instruction & 0xFFFF_F000
     }
 }
 #[cfg(test)]
@@ -140,40 +138,35 @@ mod tests {
     fn test_parse_imm_itype() {
         // addi x5, x2, 125
         let instr: u32 = 0x07d10293;
-        let bits = utils::u32_to_bitvec(instr);
-        assert_eq!(Rv32iIsa::parse_imm_itype(&bits), 125u32);
+        assert_eq!(Rv32iIsa::parse_imm_itype(instr), 125u32);
     }
 
     #[test]
     fn test_parse_imm_jalr() {
         // jalr x5, 12(x0)
         let instr = 0x00c002e7;
-        let bits = utils::u32_to_bitvec(instr);
-        assert_eq!(Rv32iIsa::parse_imm_itype(&bits), 12u32);
+        assert_eq!(Rv32iIsa::parse_imm_itype(instr), 12u32);
     }
 
     #[test]
     fn test_parse_imm_stype() {
         // sw x5, 88(x2)
         let instr: u32 = 0x04512c23;
-        let bits = utils::u32_to_bitvec(instr);
-        assert_eq!(Rv32iIsa::parse_imm_stype(&bits), 88u32);
+        assert_eq!(Rv32iIsa::parse_imm_stype(instr), 88u32);
     }
 
     #[test]
     fn test_parse_imm_btype() {
         // beq x5, x2, 74
         let instr: u32 = 0x04228563;
-        let bits = utils::u32_to_bitvec(instr);
-        assert_eq!(Rv32iIsa::parse_imm_btype(&bits), 74u32);
+        assert_eq!(Rv32iIsa::parse_imm_btype(instr), 74u32);
     }
 
     #[test]
     fn test_parse_imm_jtype() {
         // jal x8, 44
         let instr: u32 = 0x02c0046f;
-        let bits = utils::u32_to_bitvec(instr);
-        assert_eq!(Rv32iIsa::parse_imm_jtype(&bits), 44u32);
+        assert_eq!(Rv32iIsa::parse_imm_jtype(instr), 44u32);
     }
 
     #[test]
@@ -181,18 +174,25 @@ mod tests {
         // lui x8, 1339
         // 1339 is the [31:12] immediate value
         let instr: u32 = 0x0053b437;
-        let bits = utils::u32_to_bitvec(instr);
-
-        let mut imm_bitvec = Vec::new();
-        imm_bitvec.extend_from_slice(&bits[12..=31]);
-        imm_bitvec.extend_from_slice(&[0u8; 12]);
 
         assert_eq!(
-            Rv32iIsa::parse_imm_utype(&bits),
+            Rv32iIsa::parse_imm_utype(instr),
             0b00000000_01010011_10110000_00000000u32
         );
     }
 
+    #[test]
+    fn test_parse_imm_negative() {
+        // addi sp, sp, -16
+        assert_eq!(Rv32iIsa::parse_imm_itype(0xff010113), -16i32 as u32);
+        // sw ra, -4(s0)
+        assert_eq!(Rv32iIsa::parse_imm_stype(0xfe142e23), -4i32 as u32);
+        // bne x1, x3, -4
+        assert_eq!(Rv32iIsa::parse_imm_btype(0xfe309ee3), -4i32 as u32);
+        // j -16
+        assert_eq!(Rv32iIsa::parse_imm_jtype(0xff1ff06f), -16i32 as u32);
+    }
+
     #[test]
     fn test_integration_test1() {
         // sub x5, x1, x2
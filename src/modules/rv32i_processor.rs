@@ -1,94 +1,431 @@
+use crate::modules::loader::{self, ElfImage, DEFAULT_MEMORY_SIZE};
+use crate::modules::readmemh;
 use crate::modules::rv32i_alu;
+use crate::modules::rv32i_bus::{Bus, BusError, Ram, SystemBus};
+use crate::modules::rv32i_csr::{self, CsrFile};
+use crate::modules::rv32i_error::{AccessKind, CpuError};
+use crate::modules::rv32i_icache::DecodeCache;
+use crate::modules::rv32i_instr::decode_with;
 use crate::modules::rv32i_isa;
-use crate::modules::utils;
+use crate::modules::rv32i_timer::{CycleTime, TimeSource};
+use crate::modules::rv32i_trap::{Interrupt, InterruptSource, TrapCause, MCAUSE_INTERRUPT};
+use crate::modules::trace::CommitLog;
 
 use super::rv32i_isa::InstrType;
 
-use object::{Object, ObjectSection};
-use std::fs;
-#[allow(dead_code)]
-#[derive(Default)]
+use std::collections::BTreeMap;
+
+// Value of a7 that turns an `ecall` into an exit request, as in the
+// Linux/newlib `exit` syscall. The exit code is taken from a0.
+pub const ECALL_EXIT: u32 = 93;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum StepOutcome {
+    // The instruction completed and `pc` moved on
+    Retired,
+    // An `ecall`/`ebreak` retired; the host decides what it means
+    Ecall,
+    Ebreak,
+    // The instruction raised an exception and the hart entered the trap
+    // handler (only with `trap_exceptions` enabled)
+    Trap(TrapCause),
+    // An interrupt was taken instead of executing an instruction
+    Interrupt(Interrupt),
+}
+
+// What makes `Rv32iProcessor::run` return.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StopConditions {
+    // `ecall` with a7 == ECALL_EXIT
+    pub exit_on_ecall: bool,
+    pub stop_on_ebreak: bool,
+    // An instruction that jumps to itself, like `loop {}` in a panic handler
+    pub stop_on_idle_loop: bool,
+    // Stops before executing an instruction at any of these addresses
+    pub breakpoints: Vec<u32>,
+    // Trap and interrupt entries count against it as well, so a handler that
+    // faults on its first instruction can't keep the run going forever
+    pub max_instructions: Option<u64>,
+}
+
+impl Default for StopConditions {
+    fn default() -> Self {
+        Self {
+            exit_on_ecall: true,
+            stop_on_ebreak: true,
+            stop_on_idle_loop: true,
+            breakpoints: Vec::new(),
+            max_instructions: None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    Exit(u32),
+    Ebreak,
+    IdleLoop,
+    Breakpoint(u32),
+    InstructionLimit,
+    Fault(CpuError),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RunSummary {
+    pub reason: StopReason,
+    pub retired: u64,
+}
+
 pub struct Rv32iProcessor {
     pub registers: Vec<u32>,
     pub pc: u32,
-    pub program: Vec<u32>,
-    pub memory: Vec<u32>,
+    pub bus: Box<dyn Bus>,
     pub isa: rv32i_isa::Rv32iIsa,
     pub alu: rv32i_alu::Rv32iAlu,
+    // Symbol table of the loaded ELF, empty for raw programs
+    pub symbols: BTreeMap<String, u32>,
+    // Extensions decoded on top of RV32I, plain RV32I by default
+    pub extensions: rv32i_isa::Extensions,
+    pub csr: CsrFile,
+    // Deliver exceptions, ecall and ebreak through mtvec like the hardware
+    // does, instead of reporting them from `step`
+    pub trap_exceptions: bool,
+    // Drives the `time` CSR, one tick per cycle by default
+    pub time_source: Box<dyn TimeSource>,
+    // Decoded instructions by PC, see `DecodeCache` for when to flush it
+    pub icache: DecodeCache,
+    // Spike-style commit log of every step, off by default
+    pub tracer: Option<CommitLog>,
+    // Devices driving mip (CLINT, PLIC), sampled before every step
+    pub interrupt_sources: Vec<Box<dyn InterruptSource>>,
+}
+
+impl Default for Rv32iProcessor {
+    fn default() -> Self {
+        Self::with_bus(Box::new(Ram::new(0, 0)))
+    }
 }
 
 #[allow(dead_code)]
 impl Rv32iProcessor {
+    // The program is placed at address 0 and the memory right after it,
+    // both on the same byte-addressed bus.
     pub fn new(program: Vec<u32>, memory: Vec<u32>) -> Self {
+        let mut image = program;
+        image.extend_from_slice(&memory);
+        Self::with_bus(Box::new(Ram::from_words(0, &image)))
+    }
+
+    // Runs on any bus, e.g. a `SystemBus` with RAM, ROM and devices mapped.
+    pub fn with_bus(bus: Box<dyn Bus>) -> Self {
         Self {
             registers: vec![0; 32], // Initialize all registers to 0
-            program,
-            memory,
             pc: 0,
-            ..Default::default()
+            bus,
+            isa: rv32i_isa::Rv32iIsa::default(),
+            alu: rv32i_alu::Rv32iAlu::default(),
+            symbols: BTreeMap::new(),
+            extensions: rv32i_isa::Extensions::rv32i(),
+            csr: CsrFile::new(),
+            trap_exceptions: false,
+            time_source: Box::new(CycleTime::default()),
+            icache: DecodeCache::default(),
+            tracer: None,
+            interrupt_sources: Vec::new(),
         }
     }
 
     pub fn new_from_elf(elf_path: &str) -> Result<Self, Box<dyn std::error::Error>> {
-        let binary_data = fs::read(elf_path)?;
-        let file = object::File::parse(&*binary_data)?;
-        let mut data_section: &[u8] = &[0u8];
-        let mut text_section: &[u8] = &[0u8];
-        for section in file.sections() {
-            match section.name().unwrap() {
-                ".text" => {
-                    text_section = section.data()?;
+        Self::new_from_elf_with_memory(elf_path, DEFAULT_MEMORY_SIZE)
+    }
+
+    // Loads the ELF into RAM mapped under its segments, the region holding
+    // the entry point being at least `memory_size` bytes.
+    pub fn new_from_elf_with_memory(
+        elf_path: &str,
+        memory_size: usize,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let image = ElfImage::from_file(elf_path)?;
+        let bus = image.default_bus(memory_size)?;
+        Ok(Self::from_elf_image(&image, bus)?)
+    }
+
+    // Loads an ELF, Intel HEX, S-record, readmemh or flat binary image, see
+    // `loader::load_image`. `base` places flat binaries and readmemh files.
+    pub fn new_from_image(
+        path: &str,
+        base: Option<u32>,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let image = loader::load_image(path, base)?;
+        let bus = image.default_bus(DEFAULT_MEMORY_SIZE)?;
+        Ok(Self::from_elf_image(&image, bus)?)
+    }
+
+    // Loads the `.prog` and `.mem` readmemh dumps of `make build_elf`, the
+    // images the FPGA design starts from: the program at address 0, where
+    // execution starts, and the data at `data_base` (0x800 for the example's
+    // DATARAM).
+    pub fn new_from_hex_files(
+        prog_path: &str,
+        mem_path: &str,
+        data_base: u32,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let mut image = readmemh::load(prog_path, 0)?;
+        image
+            .segments
+            .extend(readmemh::load(mem_path, data_base)?.segments);
+        let bus = image.default_bus(DEFAULT_MEMORY_SIZE)?;
+        Ok(Self::from_elf_image(&image, bus)?)
+    }
+
+    // Places every PT_LOAD segment of `image` on `bus` at its physical address
+    // and starts execution at the ELF entry point.
+    pub fn from_elf_image(image: &ElfImage, mut bus: SystemBus) -> Result<Self, BusError> {
+        image.load_into(&mut bus)?;
+
+        let mut processor = Self::with_bus(Box::new(bus));
+        processor.pc = image.entry;
+        processor.symbols = image.symbols.clone();
+        Ok(processor)
+    }
+
+    // Reads `len` bytes starting at `addr` through the bus, e.g. to dump a
+    // region after a run. Devices mapped in the range are read as well.
+    pub fn read_memory(&mut self, addr: u32, len: u32) -> Result<Vec<u8>, BusError> {
+        (0..len)
+            .map(|i| self.bus.read8(addr.wrapping_add(i)))
+            .collect()
+    }
+
+    // Steps until one of the `stop` conditions is met. A breakpoint at the
+    // current pc is ignored for the first instruction, so a run stopped on a
+    // breakpoint can be resumed by calling `run` again.
+    pub fn run(&mut self, stop: &StopConditions) -> RunSummary {
+        let mut retired = 0;
+        let mut steps = 0;
+
+        let reason = loop {
+            if stop.max_instructions.is_some_and(|max| steps >= max) {
+                break StopReason::InstructionLimit;
+            }
+            let pc = self.pc;
+            if steps > 0 && stop.breakpoints.contains(&pc) {
+                break StopReason::Breakpoint(pc);
+            }
+
+            let outcome = match self.step() {
+                Ok(outcome) => outcome,
+                Err(e) => break StopReason::Fault(e),
+            };
+            steps += 1;
+            // Trap and interrupt entries take a cycle, but retire nothing
+            if matches!(
+                outcome,
+                StepOutcome::Retired | StepOutcome::Ecall | StepOutcome::Ebreak
+            ) {
+                retired += 1;
+            }
+
+            match outcome {
+                // With `trap_exceptions` on, ecall and ebreak went to the
+                // firmware's trap handler instead, which keeps running
+                StepOutcome::Ecall if stop.exit_on_ecall && self.registers[17] == ECALL_EXIT => {
+                    break StopReason::Exit(self.registers[10]);
                 }
-                ".data" => {
-                    data_section = section.data()?;
+                StepOutcome::Ebreak if stop.stop_on_ebreak => break StopReason::Ebreak,
+                // A loop waiting for an interrupt isn't idle
+                _ if stop.stop_on_idle_loop && self.pc == pc && !self.can_be_interrupted() => {
+                    break StopReason::IdleLoop
                 }
                 _ => {}
             }
-        }
-        let mut memory_elf = Vec::new();
-        for i in (0..data_section.len()).step_by(4) {
-            let word = u32::from_le_bytes([
-                data_section[i],
-                data_section[i + 1],
-                data_section[i + 2],
-                data_section[i + 3],
-            ]);
-            memory_elf.push(word);
-        }
-        let mut program_elf = Vec::new();
-        for i in (0..text_section.len()).step_by(4) {
-            let word = u32::from_le_bytes([
-                text_section[i],
-                text_section[i + 1],
-                text_section[i + 2],
-                text_section[i + 3],
-            ]);
-            program_elf.push(word);
-        }
-
-        // This part is important, since the linker script used configures the memory as 3 pages of 1024 words each.
-        // Being a contiguous memory, we need to allocate a vector with the size of 3 * 1024 words.
-        // The program has a length of 2K and the memory initial state has a length of 1K.
-        // The sp is initialized to 3072 (3K), and it grows downwards to 2048 (2K).
-        let mut memory_map = vec![0; 1024 * 3];
-        memory_map[2048..2048 + memory_elf.len()].copy_from_slice(&memory_elf);
-        memory_map[..program_elf.len()].copy_from_slice(&program_elf);
-
-        Ok(Self {
-            registers: vec![0; 32],
-            pc: 0,
-            program: memory_map[..2048].to_vec(),
-            memory: memory_map,
-            ..Default::default()
-        })
+        };
+
+        RunSummary { reason, retired }
     }
 
+    // Runs one instruction and panics on any fault; see `step`.
     pub fn exec(&mut self) {
+        if let Err(e) = self.step() {
+            panic!("{}", e);
+        }
+    }
+
+    // Runs one instruction. With `trap_exceptions` off, errors are returned
+    // and nothing is written back, so `pc` still points at the faulting
+    // instruction. With it on, faults, ecall and ebreak enter the trap
+    // handler at mtvec instead.
+    pub fn step(&mut self) -> Result<StepOutcome, CpuError> {
+        let (cycle, instret) = (self.csr.mcycle, self.csr.minstret);
+        let outcome = if let Some(interrupt) = self.pending_interrupt() {
+            self.take_interrupt(interrupt);
+            StepOutcome::Interrupt(interrupt)
+        } else if self.tracer.is_some() {
+            self.execute_traced()?
+        } else {
+            self.execute_with_traps()?
+        };
+
+        // Every step takes one cycle, only completed instructions retire. A
+        // CSR write to a counter wins over its increment.
+        if self.csr.mcycle == cycle {
+            self.csr.mcycle = cycle.wrapping_add(1);
+        }
+        let retired = !matches!(outcome, StepOutcome::Trap(_) | StepOutcome::Interrupt(_));
+        if self.csr.minstret == instret && retired {
+            self.csr.minstret = instret.wrapping_add(1);
+        }
+        Ok(outcome)
+    }
+
+    fn execute_with_traps(&mut self) -> Result<StepOutcome, CpuError> {
+        let pc = self.pc;
+        let outcome = self.execute();
+        if !self.trap_exceptions {
+            return outcome;
+        }
+
+        let (cause, tval) = match outcome {
+            Ok(StepOutcome::Ecall) => (TrapCause::EcallFromM, 0),
+            Ok(StepOutcome::Ebreak) => (TrapCause::Breakpoint, pc),
+            Ok(outcome) => return Ok(outcome),
+            Err(e) => TrapCause::from_error(e),
+        };
+        self.take_trap(cause, tval, pc);
+        Ok(StepOutcome::Trap(cause))
+    }
+
+    #[inline(never)]
+    fn execute_traced(&mut self) -> Result<StepOutcome, CpuError> {
+        let pc = self.pc;
+        if let Some(tracer) = &mut self.tracer {
+            tracer.begin();
+        }
+        let result = self.execute_with_traps();
+        self.trace_step(pc, &result);
+        result
+    }
+
+    fn trace_step(&mut self, pc: u32, result: &Result<StepOutcome, CpuError>) {
+        let (cause, tval) = match *result {
+            Ok(StepOutcome::Trap(cause)) => (cause, self.csr.mtval),
+            Ok(_) => {
+                let instruction = self.isa.i_instruction;
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.retire(pc, instruction);
+                }
+                return;
+            }
+            Err(e) => TrapCause::from_error(e),
+        };
+        // Nothing was decoded if the fetch itself failed
+        let fetched = match cause {
+            TrapCause::InstructionAccessFault => false,
+            TrapCause::InstructionMisaligned => tval != pc,
+            _ => true,
+        };
+        let instruction = fetched.then_some(self.isa.i_instruction);
+        if let Some(tracer) = &mut self.tracer {
+            tracer.exception(pc, instruction, cause, tval);
+        }
+    }
+
+    // Updates mip from the interrupt sources and returns the highest
+    // priority interrupt that is both pending and enabled.
+    fn pending_interrupt(&mut self) -> Option<Interrupt> {
+        if !self.interrupt_sources.is_empty() {
+            let cycle = self.csr.mcycle;
+            self.csr.mip = self
+                .interrupt_sources
+                .iter_mut()
+                .fold(0, |mip, source| mip | source.pending(cycle))
+                & (rv32i_csr::MSIP | rv32i_csr::MTIP | rv32i_csr::MEIP);
+        }
+        if self.csr.mstatus & rv32i_csr::MSTATUS_MIE == 0 {
+            return None;
+        }
+        let enabled = self.csr.mip & self.csr.mie;
+        Interrupt::ALL
+            .into_iter()
+            .find(|interrupt| enabled & interrupt.mask() != 0)
+    }
+
+    // Whether an interrupt could still arrive: some are enabled and there is
+    // a source to raise them.
+    fn can_be_interrupted(&self) -> bool {
+        self.csr.mstatus & rv32i_csr::MSTATUS_MIE != 0
+            && self.csr.mie != 0
+            && !self.interrupt_sources.is_empty()
+    }
+
+    // Enters the handler between two instructions, mepc pointing at the one
+    // that hasn't run yet. In vectored mode the handler is at
+    // mtvec.BASE + 4 * cause.
+    fn take_interrupt(&mut self, interrupt: Interrupt) {
+        let epc = self.pc;
+        self.enter_trap(MCAUSE_INTERRUPT | interrupt.code(), 0, epc);
+        if self.csr.mtvec & 0b11 == 1 {
+            self.pc = self.pc.wrapping_add(4 * interrupt.code());
+        }
+        if let Some(tracer) = &mut self.tracer {
+            tracer.interrupt(epc, interrupt);
+        }
+    }
+
+    // Exceptions always use the mtvec base, even in vectored mode.
+    fn take_trap(&mut self, cause: TrapCause, tval: u32, epc: u32) {
+        self.enter_trap(cause.code(), tval, epc);
+    }
+
+    // Saves the trap state in mepc/mcause/mtval, disables interrupts and jumps
+    // to mtvec.BASE.
+    fn enter_trap(&mut self, mcause: u32, tval: u32, epc: u32) {
+        self.csr.mepc = epc;
+        self.csr.mcause = mcause;
+        self.csr.mtval = tval;
+
+        let mie = self.csr.mstatus & rv32i_csr::MSTATUS_MIE != 0;
+        self.csr.mstatus &= !(rv32i_csr::MSTATUS_MIE | rv32i_csr::MSTATUS_MPIE);
+        if mie {
+            self.csr.mstatus |= rv32i_csr::MSTATUS_MPIE;
+        }
+
+        self.pc = self.csr.mtvec & !0b11;
+    }
+
+    fn execute(&mut self) -> Result<StepOutcome, CpuError> {
+        let pc = self.pc;
+
         // Fetch
-        // This div_ceil is a function that divides the pc by 4 and rounds up
-        // May cause bugs if pc is not a multiple of 4
-        self.isa.i_instruction = self.program[(self.pc.div_ceil(4)) as usize];
-        self.isa.parse_instr();
+        if pc & 0x3 != 0 {
+            return Err(CpuError::MisalignedAccess {
+                pc,
+                addr: pc,
+                kind: AccessKind::Fetch,
+            });
+        }
+        match self.icache.get(pc) {
+            Some(isa) => self.isa = isa,
+            None => {
+                self.isa.i_instruction = self
+                    .bus
+                    .read32(pc)
+                    .map_err(|e| CpuError::FetchFault { pc, addr: e.addr() })?;
+                self.isa.parse_instr();
+                // The signals only look at the opcode, so the reserved
+                // funct3/funct7 encodings are folded in here, once per fetch
+                if !self.is_legal() {
+                    self.isa.o_instrtype = InstrType::Illegal;
+                }
+                self.icache.insert(pc, self.isa);
+            }
+        }
+        if self.isa.o_instrtype == InstrType::Illegal {
+            return Err(CpuError::IllegalInstruction {
+                pc,
+                instruction: self.isa.i_instruction,
+            });
+        }
 
         // Execute
         let in2 = if self.isa.o_instrtype == rv32i_isa::InstrType::AluRtype
@@ -107,171 +444,219 @@ impl Rv32iProcessor {
             self.isa.i_instruction,
         );
 
+        let takebranch = match self.isa.o_funct3 {
+            0x0 => self.alu.o_eq,
+            0x1 => !self.alu.o_eq,
+            0x4 => self.alu.o_lt,
+            0x5 => !self.alu.o_lt,
+            0x6 => self.alu.o_ltu,
+            0x7 => !self.alu.o_ltu,
+            _ => false,
+        };
+
+        let is_mret = self.isa.o_instrtype == InstrType::SystemItype
+            && self.isa.o_funct3 == 0x0
+            && self.isa.o_imm == 0x302;
+
+        let next_pc = if is_mret {
+            self.csr.mepc
+        } else if (InstrType::BranchBtype == self.isa.o_instrtype && takebranch)
+            || InstrType::JalJtype == self.isa.o_instrtype
+        {
+            pc.wrapping_add(self.isa.o_imm)
+        } else if InstrType::JalrItype == self.isa.o_instrtype {
+            // The target's lowest bit is cleared
+            self.alu.o_alu_add & !1
+        } else {
+            pc.wrapping_add(4u32)
+        };
+
+        // Jumping to a misaligned target faults on the jump itself
+        if next_pc & 0x3 != 0 {
+            return Err(CpuError::MisalignedAccess {
+                pc,
+                addr: next_pc,
+                kind: AccessKind::Fetch,
+            });
+        }
+
         // EndInstr
         let load_data = if InstrType::LoadItype == self.isa.o_instrtype
             || InstrType::StoreStype == self.isa.o_instrtype
         {
             let loadstore_addr =
                 self.registers[self.isa.o_rs1 as usize].wrapping_add(self.isa.o_imm);
+            let rs2 = self.registers[self.isa.o_rs2 as usize];
+            let is_store = self.isa.o_instrtype == InstrType::StoreStype;
 
-            let loadstore_addr_bytes = utils::u32_to_bitvec(loadstore_addr);
-
-            // This ommits the last 2 bits of the address
-            // with verilog: wire [29:0] ADDR = i_addr[31:2];
-            let loadstore_addr = loadstore_addr & !0x3;
-
-            let funct3_bytes = utils::u32_to_bitvec(self.isa.o_funct3 as u32);
-            // funct3_bytes[0..=1] == 0b00
-            let en_byte = matches!(self.isa.o_funct3, 0x0 | 0x4);
-            // funct3_bytes[0..=1] == 0b01
-            let en_hw = matches!(self.isa.o_funct3, 0x1 | 0x5);
-
-            let memory = &utils::u32_to_bitvec(self.memory[loadstore_addr as usize]);
-
-            let load_h = if loadstore_addr_bytes[1] == 1 {
-                &memory[16..=31]
-            } else {
-                &memory[0..=15]
-            };
-            let load_b = if loadstore_addr_bytes[0] == 1 {
-                &load_h[8..=15]
-            } else {
-                &memory[0..=7]
-            };
-
-            let load_sign = funct3_bytes[1] == 0
-                && (if en_byte {
-                    load_b[7] == 1
-                } else {
-                    load_h[15] == 1
+            // funct3[1:0] selects the access width, funct3[2] means unsigned for loads
+            let width = 1u32 << (self.isa.o_funct3 & 0x3);
+            if loadstore_addr & (width - 1) != 0 {
+                return Err(CpuError::MisalignedAccess {
+                    pc,
+                    addr: loadstore_addr,
+                    kind: if is_store {
+                        AccessKind::Store
+                    } else {
+                        AccessKind::Load
+                    },
                 });
+            }
 
-            let load_data = if en_byte {
-                let mut bitvec = Vec::new();
-                bitvec.extend_from_slice(load_b);
-                bitvec.extend_from_slice(&[load_sign as u8; 24]);
-
-                utils::bitvec_to_u32(&bitvec)
-            } else if en_hw {
-                let mut bitvec = Vec::new();
-                bitvec.extend_from_slice(load_h);
-                bitvec.extend_from_slice(&[load_sign as u8; 16]);
-
-                utils::bitvec_to_u32(&bitvec)
-            } else {
-                utils::bitvec_to_u32(&memory[0..=31])
-            };
-
-            // Store
-            let store_data = if self.isa.o_instrtype == InstrType::StoreStype {
-                let rs2_bytes = utils::u32_to_bitvec(self.registers[self.isa.o_rs2 as usize]);
-                let mut bitvec = Vec::new();
-                bitvec.extend_from_slice(&rs2_bytes[0..=7]);
-                if loadstore_addr_bytes[0] == 1 {
-                    bitvec.extend_from_slice(&rs2_bytes[0..=7]);
-                } else {
-                    bitvec.extend_from_slice(&rs2_bytes[8..=15]);
-                }
-                if loadstore_addr_bytes[1] == 1 {
-                    bitvec.extend_from_slice(&rs2_bytes[0..=7]);
-                } else {
-                    bitvec.extend_from_slice(&rs2_bytes[16..=23]);
+            if is_store {
+                match self.isa.o_funct3 & 0x3 {
+                    0x0 => self.bus.write8(loadstore_addr, rs2 as u8),
+                    0x1 => self.bus.write16(loadstore_addr, rs2 as u16),
+                    _ => self.bus.write32(loadstore_addr, rs2),
                 }
-                if loadstore_addr_bytes[0] == 1 {
-                    bitvec.extend_from_slice(&rs2_bytes[0..=7]);
-                } else if loadstore_addr_bytes[1] == 1 {
-                    bitvec.extend_from_slice(&rs2_bytes[8..=15]);
-                } else {
-                    bitvec.extend_from_slice(&rs2_bytes[24..=31]);
+                .map_err(|e| CpuError::StoreAccessFault { pc, addr: e.addr() })?;
+                self.icache.invalidate(loadstore_addr);
+                if let Some(tracer) = &mut self.tracer {
+                    let mask = u32::MAX >> (32 - 8 * width);
+                    tracer.store(loadstore_addr, rs2 & mask, width);
                 }
-                utils::bitvec_to_u32(&bitvec)
-            } else {
                 0
-            };
-
-            let store_mask: u32 = if self.isa.o_instrtype == InstrType::StoreStype {
-                if en_byte {
-                    if loadstore_addr_bytes[1] == 1 {
-                        if loadstore_addr_bytes[0] == 1 {
-                            0xFF00_0000
-                        } else {
-                            0x00FF_0000
-                        }
-                    } else if loadstore_addr_bytes[0] == 1 {
-                        0x0000_FF00
-                    } else {
-                        0x000_00FF
-                    }
-                } else if en_hw {
-                    if loadstore_addr_bytes[1] == 1 {
-                        0xFFFF_0000
-                    } else {
-                        0x0000_FFFF
-                    }
-                } else {
-                    0xFFFF_FFFF
-                }
             } else {
-                0
-            };
-
-            if self.isa.o_instrtype == InstrType::StoreStype {
-                let mut mem = self.memory[loadstore_addr as usize];
-                mem &= !store_mask;
-                mem |= store_mask & store_data;
-                self.memory[loadstore_addr as usize] = mem;
+                let data = match self.isa.o_funct3 {
+                    0x0 => self.bus.read8(loadstore_addr).map(|b| b as i8 as u32),
+                    0x1 => self.bus.read16(loadstore_addr).map(|h| h as i16 as u32),
+                    0x4 => self.bus.read8(loadstore_addr).map(|b| b as u32),
+                    0x5 => self.bus.read16(loadstore_addr).map(|h| h as u32),
+                    _ => self.bus.read32(loadstore_addr),
+                }
+                .map_err(|e| CpuError::LoadAccessFault { pc, addr: e.addr() })?;
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.load(loadstore_addr);
+                }
+                data
             }
+        } else {
+            0
+        };
 
-            load_data
+        // Zicsr
+        let csr_data = if InstrType::SystemItype == self.isa.o_instrtype && self.isa.o_funct3 != 0 {
+            self.exec_csr().ok_or(CpuError::IllegalInstruction {
+                pc,
+                instruction: self.isa.i_instruction,
+            })?
         } else {
             0
         };
 
         let write_destination_register = match self.isa.o_instrtype {
-            InstrType::JalJtype | InstrType::JalrItype => self.pc.wrapping_add(4),
+            InstrType::JalJtype | InstrType::JalrItype => pc.wrapping_add(4),
             InstrType::LuiUtype => self.isa.o_imm,
-            InstrType::AuipcUtype => self.pc.wrapping_add(self.isa.o_imm),
+            InstrType::AuipcUtype => pc.wrapping_add(self.isa.o_imm),
             InstrType::LoadItype => load_data,
-            InstrType::Illegal => 0,
+            InstrType::SystemItype => csr_data,
             _ => self.alu.o_out,
         };
 
         if self.isa.o_rd as usize != 0
             && InstrType::StoreStype != self.isa.o_instrtype
             && InstrType::BranchBtype != self.isa.o_instrtype
+            && InstrType::FenceItype != self.isa.o_instrtype
         {
             self.registers[self.isa.o_rd as usize] = write_destination_register;
+            if let Some(tracer) = &mut self.tracer {
+                tracer.register_write(self.isa.o_rd, write_destination_register);
+            }
         }
 
-        let takebranch = match self.isa.o_funct3 {
-            0x0 => self.alu.o_eq,
-            0x1 => !self.alu.o_eq,
-            0x4 => self.alu.o_lt,
-            0x5 => !self.alu.o_lt,
-            0x6 => self.alu.o_ltu,
-            0x7 => !self.alu.o_ltu,
-            _ => false,
-        };
+        self.pc = next_pc;
 
-        if (InstrType::BranchBtype == self.isa.o_instrtype && takebranch)
-            || InstrType::JalJtype == self.isa.o_instrtype
-        {
-            self.pc = self.pc.wrapping_add(self.isa.o_imm);
-        } else if InstrType::JalrItype == self.isa.o_instrtype {
-            let bitvec = utils::u32_to_bitvec(self.alu.o_alu_add);
-            let mut temp_bitvec = Vec::new();
-            temp_bitvec.push(0u8);
-            temp_bitvec.extend_from_slice(&bitvec[1..=31]);
-            self.pc = utils::bitvec_to_u32(&temp_bitvec);
+        // fence is a no-op on a single in-order hart, fence.i makes earlier
+        // stores visible to instruction fetch
+        if self.isa.o_instrtype == InstrType::FenceItype && self.isa.o_funct3 == 0x1 {
+            self.icache.flush();
+        }
+
+        // mret restores MIE from MPIE and sets MPIE
+        if is_mret {
+            let mpie = self.csr.mstatus & rv32i_csr::MSTATUS_MPIE != 0;
+            self.csr.mstatus |= rv32i_csr::MSTATUS_MPIE;
+            self.csr.mstatus &= !rv32i_csr::MSTATUS_MIE;
+            if mpie {
+                self.csr.mstatus |= rv32i_csr::MSTATUS_MIE;
+            }
+            if let Some(tracer) = &mut self.tracer {
+                tracer.csr_write(
+                    rv32i_csr::MSTATUS,
+                    self.csr.read(rv32i_csr::MSTATUS).unwrap(),
+                );
+            }
+        }
+
+        // ecall and ebreak only differ in imm[0]
+        if self.isa.o_instrtype == InstrType::SystemItype && self.isa.o_funct3 == 0x0 {
+            match self.isa.o_imm {
+                0x0 => return Ok(StepOutcome::Ecall),
+                0x1 => return Ok(StepOutcome::Ebreak),
+                _ => {}
+            }
+        }
+        Ok(StepOutcome::Retired)
+    }
+
+    pub fn read_csr(&self, addr: u16) -> Option<u32> {
+        match addr {
+            rv32i_csr::MISA => Some(rv32i_csr::misa(self.extensions)),
+            rv32i_csr::TIME => Some(self.time_source.time(self.csr.mcycle) as u32),
+            rv32i_csr::TIMEH => Some((self.time_source.time(self.csr.mcycle) >> 32) as u32),
+            _ => self.csr.read(addr),
+        }
+    }
+
+    // csrrw/csrrs/csrrc and their immediate forms. Returns the old value for
+    // rd, or None if the instruction is illegal (unknown CSR, or a write to a
+    // read-only one). Nothing is modified in that case.
+    fn exec_csr(&mut self) -> Option<u32> {
+        let addr = (self.isa.o_imm & 0xFFF) as u16;
+        // funct3[2] selects the 5-bit zero-extended immediate in the rs1 field
+        let operand = if self.isa.o_funct3 & 0x4 != 0 {
+            self.isa.o_rs1 as u32
         } else {
-            self.pc = self.pc.wrapping_add(4u32);
+            self.registers[self.isa.o_rs1 as usize]
+        };
+        // csrrs/csrrc with rs1 = x0 (or uimm = 0) only read
+        let writes = self.isa.o_funct3 & 0x3 == 0x1 || self.isa.o_rs1 != 0;
+
+        let old = self.read_csr(addr)?;
+        if writes {
+            if rv32i_csr::is_read_only(addr) {
+                return None;
+            }
+            let new = match self.isa.o_funct3 & 0x3 {
+                0x1 => operand,
+                0x2 => old | operand,
+                _ => old & !operand,
+            };
+            self.csr.write(addr, new);
+            if self.tracer.is_some() {
+                // Log the value that stuck, after the WARL rules
+                let written = self.read_csr(addr).unwrap_or(new);
+                if let Some(tracer) = &mut self.tracer {
+                    tracer.csr_write(addr, written);
+                }
+            }
         }
+        Some(old)
+    }
+
+    // Rejects the encodings RV32I leaves undefined within each opcode, and
+    // instructions from disabled extensions.
+    fn is_legal(&self) -> bool {
+        !decode_with(self.isa.i_instruction, self.extensions).is_illegal()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::modules::asm;
+    use std::cell::RefCell;
+    use std::rc::Rc;
 
     #[test]
     fn test_alu() {
@@ -367,4 +752,687 @@ mod tests {
         }
         assert_eq!(processor.registers[1], 4);
     }
+
+    #[test]
+    fn test_byte_addressed_memory() {
+        let program = vec![
+            0x00800093, // addi x1, x0, 8
+            0x0000a103, // lw x2, 0(x1)
+            0x0020a623, // sw x2, 12(x1)
+            0x00e0c183, // lbu x3, 14(x1)
+            0x00c0d203, // lhu x4, 12(x1)
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![0; 4]);
+
+        for _ in 0..5 {
+            processor.exec();
+        }
+        // Byte address 8 is the third word of the program, not word 8
+        assert_eq!(processor.registers[2], 0x0020a623);
+        // The memory starts right after the 5 instructions, at byte 20
+        assert_eq!(processor.bus.read32(20).unwrap(), 0x0020a623);
+        assert_eq!(processor.registers[3], 0x20);
+        assert_eq!(processor.registers[4], 0xa623);
+    }
+
+    #[test]
+    fn test_elf_layout() {
+        let mut processor = Rv32iProcessor::new_from_elf("example/riscv_asm.elf").unwrap();
+
+        // .text is linked at PROGROM (0x0) and .data at DATARAM (0x800)
+        assert_eq!(processor.bus.read32(0).unwrap(), 0x00001117); // auipc sp, 0x1
+        assert_eq!(processor.bus.read32(0x800).unwrap(), 1737);
+        assert_eq!(processor.pc, processor.symbols["_start"]);
+        // The stack at __sp = 3K is backed by RAM as well
+        assert_eq!(processor.bus.read32(0xBFC).unwrap(), 0);
+    }
+
+    #[test]
+    fn test_example_program() {
+        let mut processor = Rv32iProcessor::new_from_elf("example/riscv_asm.elf").unwrap();
+
+        // main() returns to _rust_entry, which panics; the panic handler then
+        // spins in `loop {}`, which is where the run stops.
+        let summary = processor.run(&StopConditions::default());
+        assert_eq!(summary.reason, StopReason::IdleLoop);
+
+        // The 10th number of the Fibonacci sequence (starting from 0, 1)
+        // ends up in x23, the factorial of 9 in x24
+        let mut fib = (0u32, 1u32);
+        for _ in 0..10 {
+            fib = (fib.1, fib.0 + fib.1);
+        }
+        assert_eq!(processor.registers[23], fib.1);
+        assert_eq!(processor.registers[24], (1..10).product::<u32>());
+        // Then the two global variables are loaded into x25 and x26
+        assert_eq!(processor.registers[25], 777);
+        assert_eq!(processor.registers[26], 1737);
+    }
+
+    #[test]
+    fn test_mmio_store() {
+        use crate::modules::rv32i_bus::{AccessSize, Device};
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        struct Port(Vec<u32>);
+        impl Device for Port {
+            fn read(&mut self, _offset: u32, _size: AccessSize) -> Result<u32, BusError> {
+                Ok(0x7F)
+            }
+            fn write(
+                &mut self,
+                _offset: u32,
+                _size: AccessSize,
+                value: u32,
+            ) -> Result<(), BusError> {
+                self.0.push(value);
+                Ok(())
+            }
+        }
+
+        let port = Rc::new(RefCell::new(Port(Vec::new())));
+        let mut bus = SystemBus::new();
+        bus.map_rom(Ram::from_words(
+            0,
+            &[
+                0x100000b7, // lui x1, 0x10000
+                0x04100113, // addi x2, x0, 65
+                0x00208023, // sb x2, 0(x1)
+                0x0000c183, // lbu x3, 0(x1)
+            ],
+        ))
+        .unwrap();
+        bus.map_device(0x1000_0000, 0x100, Box::new(port.clone()))
+            .unwrap();
+        let mut processor = Rv32iProcessor::with_bus(Box::new(bus));
+
+        for _ in 0..4 {
+            processor.exec();
+        }
+        assert_eq!(port.borrow().0, vec![65]);
+        assert_eq!(processor.registers[3], 0x7F);
+    }
+
+    #[test]
+    fn test_step_errors() {
+        let program = vec![
+            0x00000000, // illegal
+            0x7ff02083, // lw x1, 2047(x0)
+            0x00102123, // sw x1, 2(x0)
+            0x00a00067, // jalr x0, 10(x0)
+            0x0000f083, // funct3 = 7 is not a load
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![]);
+
+        assert_eq!(
+            processor.step(),
+            Err(CpuError::IllegalInstruction {
+                pc: 0,
+                instruction: 0
+            })
+        );
+        // The faulting instruction is not retired
+        assert_eq!(processor.pc, 0);
+
+        processor.pc = 4;
+        assert_eq!(
+            processor.step(),
+            Err(CpuError::MisalignedAccess {
+                pc: 4,
+                addr: 2047,
+                kind: AccessKind::Load
+            })
+        );
+        processor.pc = 8;
+        assert_eq!(
+            processor.step(),
+            Err(CpuError::MisalignedAccess {
+                pc: 8,
+                addr: 2,
+                kind: AccessKind::Store
+            })
+        );
+        processor.pc = 12;
+        assert_eq!(
+            processor.step(),
+            Err(CpuError::MisalignedAccess {
+                pc: 12,
+                addr: 10,
+                kind: AccessKind::Fetch
+            })
+        );
+        processor.pc = 16;
+        assert!(matches!(
+            processor.step(),
+            Err(CpuError::IllegalInstruction { pc: 16, .. })
+        ));
+
+        processor.pc = 20;
+        assert_eq!(
+            processor.step(),
+            Err(CpuError::FetchFault { pc: 20, addr: 20 })
+        );
+    }
+
+    #[test]
+    fn test_step_access_faults() {
+        let program = vec![
+            0x40002083, // lw x1, 1024(x0)
+            0x40102023, // sw x1, 1024(x0)
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![0; 2]);
+        processor.registers[1] = 0xDEAD;
+
+        assert_eq!(
+            processor.step(),
+            Err(CpuError::LoadAccessFault { pc: 0, addr: 1024 })
+        );
+        assert_eq!(processor.registers[1], 0xDEAD);
+
+        processor.pc = 4;
+        assert_eq!(
+            processor.step(),
+            Err(CpuError::StoreAccessFault { pc: 4, addr: 1024 })
+        );
+
+        // Everything in range still retires
+        let mut processor = Rv32iProcessor::new(vec![0x00500093], vec![]);
+        assert_eq!(processor.step(), Ok(StepOutcome::Retired));
+    }
+
+    #[test]
+    fn test_run_stop_conditions() {
+        let program = vec![
+            0x00100093, // addi x1, x0, 1
+            0x00100073, // ebreak
+            0x00208093, // addi x1, x1, 2
+            0x02a00513, // addi a0, x0, 42
+            0x05d00893, // addi a7, x0, 93
+            0x00000073, // ecall
+            0x0000006f, // j .
+        ];
+        let mut processor = Rv32iProcessor::new(program.clone(), vec![]);
+        let stop = StopConditions::default();
+
+        let summary = processor.run(&stop);
+        assert_eq!(summary.reason, StopReason::Ebreak);
+        assert_eq!(summary.retired, 2);
+        assert_eq!(processor.pc, 8);
+
+        let summary = processor.run(&stop);
+        assert_eq!(summary.reason, StopReason::Exit(42));
+        assert_eq!(summary.retired, 4);
+        assert_eq!(processor.registers[1], 3);
+
+        let summary = processor.run(&stop);
+        assert_eq!(summary.reason, StopReason::IdleLoop);
+        assert_eq!((summary.retired, processor.pc), (1, 24));
+
+        // Same program, ignoring ecall/ebreak and stopping on a breakpoint
+        let mut processor = Rv32iProcessor::new(program, vec![]);
+        let stop = StopConditions {
+            exit_on_ecall: false,
+            stop_on_ebreak: false,
+            breakpoints: vec![20],
+            ..Default::default()
+        };
+        let summary = processor.run(&stop);
+        assert_eq!(summary.reason, StopReason::Breakpoint(20));
+        assert_eq!(summary.retired, 5);
+
+        let summary = processor.run(&StopConditions {
+            max_instructions: Some(10),
+            stop_on_idle_loop: false,
+            ..stop
+        });
+        assert_eq!(summary.reason, StopReason::InstructionLimit);
+        assert_eq!(summary.retired, 10);
+        assert_eq!(processor.pc, 24);
+    }
+
+    #[test]
+    fn test_run_fault() {
+        let mut processor = Rv32iProcessor::new(vec![0x00500093], vec![]);
+
+        let summary = processor.run(&StopConditions::default());
+        assert_eq!(
+            summary.reason,
+            StopReason::Fault(CpuError::FetchFault { pc: 4, addr: 4 })
+        );
+        assert_eq!(summary.retired, 1);
+    }
+
+    #[test]
+    fn test_rv32m() {
+        let program = vec![
+            0xff900093, // addi x1, x0, -7
+            0x00200113, // addi x2, x0, 2
+            0x022081b3, // mul x3, x1, x2
+            0x0220c233, // div x4, x1, x2
+            0x0220e2b3, // rem x5, x1, x2
+        ];
+
+        // Plain RV32I rejects the M encodings
+        let mut processor = Rv32iProcessor::new(program.clone(), vec![]);
+        processor.step().unwrap();
+        processor.step().unwrap();
+        assert_eq!(
+            processor.step(),
+            Err(CpuError::IllegalInstruction {
+                pc: 8,
+                instruction: 0x022081b3
+            })
+        );
+
+        let mut processor = Rv32iProcessor::new(program, vec![]);
+        processor.extensions = rv32i_isa::Extensions::rv32im();
+        for _ in 0..5 {
+            processor.step().unwrap();
+        }
+        assert_eq!(processor.registers[3], -14i32 as u32);
+        assert_eq!(processor.registers[4], -3i32 as u32);
+        assert_eq!(processor.registers[5], -1i32 as u32);
+    }
+
+    #[test]
+    fn test_zicsr() {
+        let program = vec![
+            0x12300093, // addi x1, x0, 0x123
+            0x34009173, // csrrw x2, mscratch, x1
+            0x340261f3, // csrrsi x3, mscratch, 4
+            0x3400b273, // csrrc x4, mscratch, x1
+            0x340022f3, // csrr x5, mscratch
+            0x30102373, // csrr x6, misa
+            0xf14023f3, // csrr x7, mhartid
+            0x3000f473, // csrrci x8, mstatus, 1
+            0x30046073, // csrsi mstatus, 8
+            0x300024f3, // csrr x9, mstatus
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![]);
+        processor.csr.mscratch = 0x40;
+
+        for _ in 0..10 {
+            processor.step().unwrap();
+        }
+        assert_eq!(processor.registers[2], 0x40);
+        assert_eq!(processor.registers[3], 0x123);
+        assert_eq!(processor.registers[4], 0x127);
+        assert_eq!(processor.registers[5], 0x004);
+        assert_eq!(processor.registers[6], 0x4000_0100);
+        assert_eq!(processor.registers[7], 0);
+        assert_eq!(processor.registers[8], rv32i_csr::MSTATUS_MPP);
+        assert_eq!(
+            processor.registers[9],
+            rv32i_csr::MSTATUS_MPP | rv32i_csr::MSTATUS_MIE
+        );
+    }
+
+    #[test]
+    fn test_zicsr_illegal() {
+        let program = vec![
+            0xf1409073, // csrw mhartid, x1
+            0x7c0020f3, // csrr x1, 0x7c0
+            0x3400c0f3, // funct3 = 4
+            0xf1402073, // csrr x0, mhartid
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![]);
+
+        for pc in [0, 4, 8] {
+            processor.pc = pc;
+            assert!(matches!(
+                processor.step(),
+                Err(CpuError::IllegalInstruction { .. })
+            ));
+        }
+        // Reading a read-only CSR is fine
+        processor.pc = 12;
+        assert_eq!(processor.step(), Ok(StepOutcome::Retired));
+    }
+
+    #[test]
+    fn test_trap_and_mret() {
+        let program = vec![
+            0x02000093, // addi x1, x0, 32
+            0x30509073, // csrw mtvec, x1
+            0x30046073, // csrsi mstatus, 8
+            0x00000000, // illegal
+            0x00000073, // ecall
+            0x00302103, // lw x2, 3(x0)
+            0x0000006f, // j .
+            0x00000013, // nop
+            // handler at 32: save the trap CSRs, count the traps in a3 and
+            // skip the faulting instruction
+            0x34202573, // csrr a0, mcause
+            0x343025f3, // csrr a1, mtval
+            0x30002673, // csrr a2, mstatus
+            0x34102373, // csrr t1, mepc
+            0x00430313, // addi t1, t1, 4
+            0x34131073, // csrw mepc, t1
+            0x00168693, // addi a3, a3, 1
+            0x30200073, // mret
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![]);
+        processor.trap_exceptions = true;
+
+        for _ in 0..3 {
+            processor.step().unwrap();
+        }
+        assert_eq!(
+            processor.step(),
+            Ok(StepOutcome::Trap(TrapCause::IllegalInstruction))
+        );
+        assert_eq!(processor.pc, 32);
+        assert_eq!(processor.csr.mepc, 12);
+        for _ in 0..8 {
+            processor.step().unwrap();
+        }
+        assert_eq!(processor.registers[10], 2);
+        assert_eq!(processor.registers[11], 0);
+        // MIE moved to MPIE while in the handler, and back after mret
+        assert_eq!(
+            processor.registers[12],
+            rv32i_csr::MSTATUS_MPIE | rv32i_csr::MSTATUS_MPP
+        );
+        assert_eq!(processor.pc, 16);
+        assert_eq!(
+            processor.csr.mstatus,
+            rv32i_csr::MSTATUS_MIE | rv32i_csr::MSTATUS_MPIE
+        );
+
+        // ecall
+        assert_eq!(
+            processor.step(),
+            Ok(StepOutcome::Trap(TrapCause::EcallFromM))
+        );
+        for _ in 0..8 {
+            processor.step().unwrap();
+        }
+        assert_eq!(processor.registers[10], 11);
+        assert_eq!(processor.pc, 20);
+
+        // Misaligned load
+        processor.step().unwrap();
+        for _ in 0..8 {
+            processor.step().unwrap();
+        }
+        assert_eq!(processor.registers[10], 4);
+        assert_eq!(processor.registers[11], 3);
+        assert_eq!(processor.registers[13], 3);
+        assert_eq!(processor.pc, 24);
+    }
+
+    #[test]
+    fn test_trap_vectored_mode() {
+        let program = vec![
+            0x04100093, // addi x1, x0, 65 (base 64, vectored)
+            0x30509073, // csrw mtvec, x1
+            0x00100073, // ebreak
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![0; 32]);
+        processor.trap_exceptions = true;
+
+        processor.step().unwrap();
+        processor.step().unwrap();
+        assert_eq!(
+            processor.step(),
+            Ok(StepOutcome::Trap(TrapCause::Breakpoint))
+        );
+        // Exceptions ignore the vectored mode
+        assert_eq!(processor.pc, 64);
+        assert_eq!(processor.csr.mcause, 3);
+        assert_eq!(processor.csr.mtval, 8);
+    }
+
+    #[test]
+    fn test_run_with_trapped_ecall() {
+        // The exit ecall and the ebreak go to the handler, which records
+        // their causes and parks in an idle loop after the second one
+        let program = asm::assemble(
+            "
+                la t0, handler
+                csrw mtvec, t0
+                li a7, 93
+                ecall
+                ebreak
+            handler:
+                csrr t0, mcause
+                add s0, s0, t0
+                addi s1, s1, 1
+                li t1, 2
+                beq s1, t1, park
+                csrr t0, mepc
+                addi t0, t0, 4
+                csrw mepc, t0
+                mret
+            park:
+                j park
+            ",
+        )
+        .unwrap();
+        let mut processor = Rv32iProcessor::new(program.words(), vec![]);
+        processor.trap_exceptions = true;
+
+        let summary = processor.run(&StopConditions::default());
+        assert_eq!(summary.reason, StopReason::IdleLoop);
+        assert_eq!(processor.registers[8], 11 + 3);
+        // The two trap entries don't count as instructions
+        assert_eq!(summary.retired, processor.csr.minstret);
+        assert_eq!(processor.csr.mcycle, processor.csr.minstret + 2);
+
+        // They do count against the limit, which bounds steps
+        let mut processor = Rv32iProcessor::new(program.words(), vec![]);
+        processor.trap_exceptions = true;
+        let stop = StopConditions {
+            max_instructions: Some(8),
+            ..StopConditions::default()
+        };
+        let summary = processor.run(&stop);
+        assert_eq!(summary.reason, StopReason::InstructionLimit);
+        assert_eq!((summary.retired, processor.csr.minstret), (7, 7));
+        assert_eq!(processor.csr.mcycle, 8);
+    }
+
+    #[test]
+    fn test_run_trap_loop() {
+        // The handler isn't mapped, so every fetch there traps again and
+        // nothing ever retires
+        let program = asm::assemble(
+            "
+                li t0, 0x4000
+                csrw mtvec, t0
+            trap:
+                ecall
+            ",
+        )
+        .unwrap();
+        let mut processor = Rv32iProcessor::new(program.words(), vec![]);
+        processor.trap_exceptions = true;
+        let stop = StopConditions {
+            stop_on_idle_loop: false,
+            max_instructions: Some(100),
+            ..StopConditions::default()
+        };
+        let summary = processor.run(&stop);
+        assert_eq!(summary.reason, StopReason::InstructionLimit);
+        assert_eq!(summary.retired, 3);
+        assert_eq!(processor.csr.mcycle, 100);
+
+        // A breakpoint on the handler is hit even when the first step traps
+        processor.pc = program.symbol("trap").unwrap();
+        let stop = StopConditions {
+            breakpoints: vec![0x4000],
+            ..StopConditions::default()
+        };
+        let summary = processor.run(&stop);
+        assert_eq!(summary.reason, StopReason::Breakpoint(0x4000));
+        assert_eq!(summary.retired, 0);
+    }
+
+    struct InterruptLines(u32);
+
+    impl InterruptSource for InterruptLines {
+        fn pending(&mut self, _cycle: u64) -> u32 {
+            self.0
+        }
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let program = vec![
+            0x04100093, // addi x1, x0, 65 (base 64, vectored)
+            0x30509073, // csrw mtvec, x1
+            0x00000013, // nop
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![]);
+        let lines = Rc::new(RefCell::new(InterruptLines(rv32i_csr::MTIP)));
+        processor.interrupt_sources.push(Box::new(lines.clone()));
+        processor.csr.mie = rv32i_csr::MSIP | rv32i_csr::MTIP;
+
+        // Pending and enabled in mie, but masked by mstatus.MIE
+        assert_eq!(processor.step(), Ok(StepOutcome::Retired));
+        assert_eq!(processor.csr.mip, rv32i_csr::MTIP);
+        processor.step().unwrap();
+
+        processor.csr.mstatus = rv32i_csr::MSTATUS_MIE;
+        assert_eq!(
+            processor.step(),
+            Ok(StepOutcome::Interrupt(Interrupt::MachineTimer))
+        );
+        // Interrupts use the vectored mode, and take a cycle without retiring
+        assert_eq!(processor.pc, 64 + 4 * 7);
+        assert_eq!(processor.csr.mepc, 8);
+        assert_eq!(processor.csr.mcause, MCAUSE_INTERRUPT | 7);
+        assert_eq!(processor.csr.mstatus, rv32i_csr::MSTATUS_MPIE);
+        assert_eq!((processor.csr.mcycle, processor.csr.minstret), (3, 2));
+
+        // The software interrupt has priority over the timer
+        lines.borrow_mut().0 |= rv32i_csr::MSIP;
+        processor.pc = 8;
+        processor.csr.mstatus = rv32i_csr::MSTATUS_MIE;
+        assert_eq!(
+            processor.step(),
+            Ok(StepOutcome::Interrupt(Interrupt::MachineSoftware))
+        );
+        assert_eq!(processor.pc, 64 + 4 * 3);
+
+        // Nothing enabled in mie
+        processor.pc = 8;
+        processor.csr.mstatus = rv32i_csr::MSTATUS_MIE;
+        processor.csr.mie = rv32i_csr::MEIP;
+        assert_eq!(processor.step(), Ok(StepOutcome::Retired));
+        assert_eq!(processor.pc, 12);
+    }
+
+    #[test]
+    fn test_counters() {
+        let program = vec![
+            0x00000013, // nop
+            0xc0002573, // rdcycle a0
+            0xc02025f3, // rdinstret a1
+            0xc0102673, // rdtime a2
+            0xc81026f3, // rdtimeh a3
+            0xb0201073, // csrw minstret, x0
+            0x00000000, // illegal
+        ];
+        let mut processor = Rv32iProcessor::new(program, vec![]);
+        processor.time_source = Box::new(CycleTime { cycles_per_tick: 2 });
+
+        for _ in 0..6 {
+            processor.step().unwrap();
+        }
+        assert_eq!(processor.registers[10], 1);
+        assert_eq!(processor.registers[11], 2);
+        assert_eq!(processor.registers[12], 3 / 2);
+        assert_eq!(processor.registers[13], 0);
+        // The write to minstret replaces its own increment
+        assert_eq!(processor.csr.minstret, 0);
+        assert_eq!(processor.csr.mcycle, 6);
+
+        // A fault without traps doesn't count, a trap takes a cycle but
+        // doesn't retire
+        assert!(processor.step().is_err());
+        assert_eq!((processor.csr.mcycle, processor.csr.minstret), (6, 0));
+        processor.trap_exceptions = true;
+        processor.step().unwrap();
+        assert_eq!((processor.csr.mcycle, processor.csr.minstret), (7, 0));
+    }
+
+    #[test]
+    fn test_icache_store_invalidation() {
+        let program = asm::assemble(
+            "
+                la t1, target
+                li t2, 0x06450513   # addi a0, a0, 100
+                li t0, 2
+            target:
+                addi a0, a0, 1
+                sw t2, 0(t1)
+                addi t0, t0, -1
+                bnez t0, target
+                li a7, 93
+                ecall
+            ",
+        )
+        .unwrap();
+        let mut processor = Rv32iProcessor::new(program.words(), vec![]);
+
+        // The second pass runs the patched instruction, not the cached one
+        let summary = processor.run(&StopConditions::default());
+        assert_eq!(summary.reason, StopReason::Exit(101));
+        assert!(processor.icache.hits > 0);
+    }
+
+    #[test]
+    fn test_icache_illegal_instruction() {
+        // The handler returns to the illegal instruction over and over
+        let program = asm::assemble(
+            "
+                la t0, handler
+                csrw mtvec, t0
+                .word 0x0000707f    # fence with a reserved funct3
+            handler:
+                addi s0, s0, 1
+                mret
+            ",
+        )
+        .unwrap();
+        let mut processor = Rv32iProcessor::new(program.words(), vec![]);
+        processor.trap_exceptions = true;
+
+        let stop = StopConditions {
+            max_instructions: Some(20),
+            ..StopConditions::default()
+        };
+        processor.run(&stop);
+        assert_eq!(processor.registers[8], 6);
+        // Once per address: the illegal word is fetched and decoded only once
+        assert_eq!(processor.icache.misses, 6);
+    }
+
+    #[test]
+    fn test_fence_i() {
+        let program = asm::assemble(
+            "
+            top:
+                addi a0, a0, 1
+                fence.i
+                bnez a1, done
+                li a1, 1
+                fence
+                j top
+            done:
+                li a7, 93
+                ecall
+            ",
+        )
+        .unwrap();
+        let mut processor = Rv32iProcessor::new(program.words(), vec![]);
+        processor.step().unwrap();
+
+        // Patched behind the processor's back, only fence.i makes it visible
+        processor.bus.write32(0, 0x06450513).unwrap();
+        let summary = processor.run(&StopConditions::default());
+        assert_eq!(summary.reason, StopReason::Exit(101));
+    }
 }
@@ -0,0 +1,80 @@
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+// Drives the `time`/`timeh` CSRs. `cycle` is the current mcycle value, for
+// sources that derive time from the instruction stream.
+pub trait TimeSource {
+    fn time(&self, cycle: u64) -> u64;
+}
+
+// Lets a device that keeps time (e.g. the CLINT's mtime) drive the CSRs
+// while it is mapped on the bus.
+impl<T: TimeSource> TimeSource for Rc<RefCell<T>> {
+    fn time(&self, cycle: u64) -> u64 {
+        self.borrow().time(cycle)
+    }
+}
+
+// One tick every `cycles_per_tick` cycles. Deterministic, so it is the
+// default: two runs of the same program read the same times.
+pub struct CycleTime {
+    pub cycles_per_tick: u64,
+}
+
+impl Default for CycleTime {
+    fn default() -> Self {
+        Self { cycles_per_tick: 1 }
+    }
+}
+
+impl TimeSource for CycleTime {
+    fn time(&self, cycle: u64) -> u64 {
+        cycle / self.cycles_per_tick.max(1)
+    }
+}
+
+// Host wall-clock time since creation, at `ticks_per_second`.
+pub struct WallClockTime {
+    start: Instant,
+    pub ticks_per_second: u64,
+}
+
+impl WallClockTime {
+    pub fn new(ticks_per_second: u64) -> Self {
+        Self {
+            start: Instant::now(),
+            ticks_per_second,
+        }
+    }
+}
+
+impl TimeSource for WallClockTime {
+    fn time(&self, _cycle: u64) -> u64 {
+        let elapsed = self.start.elapsed();
+        (elapsed.as_nanos() * self.ticks_per_second as u128 / 1_000_000_000) as u64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cycle_time() {
+        let time = CycleTime {
+            cycles_per_tick: 10,
+        };
+        assert_eq!(time.time(9), 0);
+        assert_eq!(time.time(25), 2);
+        assert_eq!(CycleTime::default().time(25), 25);
+    }
+
+    #[test]
+    fn test_wall_clock_time() {
+        let time = WallClockTime::new(1_000_000);
+        let first = time.time(0);
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        assert!(time.time(0) >= first + 2_000);
+    }
+}
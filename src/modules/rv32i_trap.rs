@@ -0,0 +1,137 @@
+use crate::modules::rv32i_error::{AccessKind, CpuError};
+
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Set in mcause for interrupts, next to the interrupt code
+pub const MCAUSE_INTERRUPT: u32 = 1 << 31;
+
+// Synchronous exceptions a machine-mode only hart can take, with their
+// mcause codes from the privileged spec.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum TrapCause {
+    InstructionMisaligned,
+    InstructionAccessFault,
+    IllegalInstruction,
+    Breakpoint,
+    LoadMisaligned,
+    LoadAccessFault,
+    StoreMisaligned,
+    StoreAccessFault,
+    EcallFromM,
+}
+
+impl TrapCause {
+    // Value written to mcause
+    pub fn code(self) -> u32 {
+        match self {
+            TrapCause::InstructionMisaligned => 0,
+            TrapCause::InstructionAccessFault => 1,
+            TrapCause::IllegalInstruction => 2,
+            TrapCause::Breakpoint => 3,
+            TrapCause::LoadMisaligned => 4,
+            TrapCause::LoadAccessFault => 5,
+            TrapCause::StoreMisaligned => 6,
+            TrapCause::StoreAccessFault => 7,
+            TrapCause::EcallFromM => 11,
+        }
+    }
+
+    // The exception raised for `error`, along with the value for mtval:
+    // the faulting address, or the instruction bits for illegal instructions.
+    pub fn from_error(error: CpuError) -> (TrapCause, u32) {
+        match error {
+            CpuError::FetchFault { addr, .. } => (TrapCause::InstructionAccessFault, addr),
+            CpuError::LoadAccessFault { addr, .. } => (TrapCause::LoadAccessFault, addr),
+            CpuError::StoreAccessFault { addr, .. } => (TrapCause::StoreAccessFault, addr),
+            CpuError::IllegalInstruction { instruction, .. } => {
+                (TrapCause::IllegalInstruction, instruction)
+            }
+            CpuError::MisalignedAccess { addr, kind, .. } => match kind {
+                AccessKind::Fetch => (TrapCause::InstructionMisaligned, addr),
+                AccessKind::Load => (TrapCause::LoadMisaligned, addr),
+                AccessKind::Store => (TrapCause::StoreMisaligned, addr),
+            },
+        }
+    }
+}
+
+// Machine-mode interrupts, in decreasing priority order.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Interrupt {
+    MachineExternal,
+    MachineSoftware,
+    MachineTimer,
+}
+
+impl Interrupt {
+    pub const ALL: [Interrupt; 3] = [
+        Interrupt::MachineExternal,
+        Interrupt::MachineSoftware,
+        Interrupt::MachineTimer,
+    ];
+
+    // Value written to mcause, without the interrupt bit
+    pub fn code(self) -> u32 {
+        match self {
+            Interrupt::MachineSoftware => 3,
+            Interrupt::MachineTimer => 7,
+            Interrupt::MachineExternal => 11,
+        }
+    }
+
+    // The bit in mip and mie
+    pub fn mask(self) -> u32 {
+        1 << self.code()
+    }
+}
+
+// A device driving interrupt lines, like a CLINT (MSIP, MTIP) or a PLIC
+// (MEIP). The processor samples its sources before every step and mip is
+// the OR of what they return.
+pub trait InterruptSource {
+    // The mip bits asserted at `cycle`, the current mcycle value
+    fn pending(&mut self, cycle: u64) -> u32;
+}
+
+// Lets a source be mapped on the bus as well, see the same impl for Device.
+impl<S: InterruptSource> InterruptSource for Rc<RefCell<S>> {
+    fn pending(&mut self, cycle: u64) -> u32 {
+        self.borrow_mut().pending(cycle)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_csr::{MEIP, MSIP, MTIP};
+
+    #[test]
+    fn test_from_error() {
+        let error = CpuError::MisalignedAccess {
+            pc: 0x100,
+            addr: 0x202,
+            kind: AccessKind::Store,
+        };
+        assert_eq!(
+            TrapCause::from_error(error),
+            (TrapCause::StoreMisaligned, 0x202)
+        );
+
+        let error = CpuError::IllegalInstruction {
+            pc: 0x100,
+            instruction: 0xFFFF_FFFF,
+        };
+        let (cause, tval) = TrapCause::from_error(error);
+        assert_eq!((cause.code(), tval), (2, 0xFFFF_FFFF));
+    }
+
+    #[test]
+    fn test_interrupt_bits() {
+        assert_eq!(Interrupt::MachineSoftware.mask(), MSIP);
+        assert_eq!(Interrupt::MachineTimer.mask(), MTIP);
+        assert_eq!(Interrupt::MachineExternal.mask(), MEIP);
+        let all = Interrupt::ALL.iter().fold(0, |mask, i| mask | i.mask());
+        assert_eq!(all, MSIP | MTIP | MEIP);
+    }
+}
@@ -0,0 +1,139 @@
+// Motorola S-record images, as written by `objcopy -O srec`:
+//
+//   S0 06 0000 686472 BB       header, ignored
+//   S3 09 80000000 17110000 4E data with a 32-bit address (S1: 16, S2: 24)
+//   S5 03 0001 FB              count of the data records so far (S6: 24-bit)
+//   S7 05 80000000 7A          entry point, ends the file (S8: 24, S9: 16)
+//
+// (spaces added for clarity). The byte count covers the address, the data
+// and the checksum, which is the ones' complement of the sum of all of them.
+
+use crate::modules::loader::{parse_hex_bytes, push_bytes, ElfImage, ImageError};
+
+// The data records, in file order, with the S7/S8/S9 address as the entry
+// point. Anything after the termination record is ignored.
+pub fn parse(text: &str) -> Result<ElfImage, ImageError> {
+    let mut segments = Vec::new();
+    let mut data_records = 0u32;
+    let mut line_number = 0;
+
+    for (index, line) in text.lines().enumerate() {
+        line_number = index + 1;
+        let error = |message: String| ImageError::new(line_number, message);
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let record = line
+            .strip_prefix('S')
+            .ok_or_else(|| error("missing `S` start code".into()))?;
+        let kind = record.chars().next().unwrap_or(' ');
+        let address_len = match kind {
+            '0' | '1' | '5' | '9' => 2,
+            '2' | '6' | '8' => 3,
+            '3' | '7' => 4,
+            _ => return Err(error(format!("unknown record type S{}", kind))),
+        };
+        let bytes =
+            parse_hex_bytes(&record[1..]).ok_or_else(|| error("invalid hex digits".into()))?;
+        if bytes.len() < address_len + 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(error("record length doesn't match its byte count".into()));
+        }
+        let (body, found) = bytes.split_at(bytes.len() - 1);
+        let expected = !body.iter().fold(0u8, |sum, b| sum.wrapping_add(*b));
+        if found[0] != expected {
+            return Err(error(format!(
+                "checksum mismatch: expected 0x{:02X}, found 0x{:02X}",
+                expected, found[0]
+            )));
+        }
+
+        let (address, data) = body[1..].split_at(address_len);
+        let address = address.iter().fold(0u32, |value, b| value << 8 | *b as u32);
+        match kind {
+            '0' => {}
+            '1' | '2' | '3' => {
+                if address as u64 + data.len() as u64 > 1 << 32 {
+                    return Err(error("address out of range".into()));
+                }
+                push_bytes(&mut segments, address, data);
+                data_records += 1;
+            }
+            '5' | '6' if address != data_records => {
+                return Err(error(format!(
+                    "record count mismatch: {} data records, S{} says {}",
+                    data_records, kind, address
+                )));
+            }
+            '5' | '6' => {}
+            _ => return Ok(ElfImage::from_segments(segments, Some(address))),
+        }
+    }
+    Err(ImageError::new(
+        line_number,
+        "missing termination record (S7, S8 or S9)",
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let image = parse(
+            "\
+S0060000686472BB
+S30980000000171100004E
+S30980000004130101C09D
+S205010000AA4F
+S5030003F9
+S705800000007A
+",
+        )
+        .unwrap();
+
+        assert_eq!(image.entry, 0x8000_0000);
+        assert_eq!(image.segments.len(), 2);
+        assert_eq!(image.segments[0].addr, 0x8000_0000);
+        assert_eq!(
+            image.segments[0].data,
+            [0x17, 0x11, 0, 0, 0x13, 0x01, 0x01, 0xc0]
+        );
+        assert_eq!(image.segments[1].addr, 0x10000);
+        assert_eq!(image.segments[1].data, [0xAA]);
+
+        let image = parse("S1040100AA50\nS804010000FA").unwrap();
+        assert_eq!((image.segments[0].addr, image.entry), (0x100, 0x10000));
+    }
+
+    #[test]
+    fn test_errors() {
+        let error = |text| parse(text).unwrap_err().to_string();
+
+        assert_eq!(error(":00000001FF"), "line 1: missing `S` start code");
+        assert_eq!(error("S4030000FC"), "line 1: unknown record type S4");
+        assert_eq!(error("S1040100AA5"), "line 1: invalid hex digits");
+        assert_eq!(
+            error("S1050100AA50"),
+            "line 1: record length doesn't match its byte count"
+        );
+        assert_eq!(
+            error("S1040100AA51"),
+            "line 1: checksum mismatch: expected 0x50, found 0x51"
+        );
+        assert_eq!(
+            error("S309FFFFFFFE01020304F1"),
+            "line 1: address out of range"
+        );
+        assert_eq!(
+            error("S1040100AA50\n\nS5030002FA"),
+            "line 3: record count mismatch: 1 data records, S5 says 2"
+        );
+        assert_eq!(
+            error("S1040100AA50\n"),
+            "line 1: missing termination record (S7, S8 or S9)"
+        );
+    }
+}
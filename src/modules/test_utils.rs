@@ -0,0 +1,68 @@
+// Fixtures shared by the unit tests.
+
+use crate::modules::asm;
+use crate::modules::rv32i_bus::{Device, Ram, SystemBus};
+use crate::modules::rv32i_processor::{RunSummary, Rv32iProcessor, StopConditions};
+use std::cell::RefCell;
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::rc::Rc;
+
+// An output for a device to own while the test keeps a clone to look at
+// what was written, through `.0.borrow()`.
+#[derive(Clone, Default)]
+pub struct SharedBuffer(pub Rc<RefCell<Vec<u8>>>);
+
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+// A scratch directory under the system's temp dir, removed on drop. The
+// process id keeps concurrent test runs apart, `name` the tests of one run.
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        let dir = std::env::temp_dir().join(format!("rv32i-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        Self(dir)
+    }
+
+    // The path of `file` in the directory
+    pub fn path(&self, file: &str) -> String {
+        self.0.join(file).to_str().unwrap().to_string()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+// Assembles `source` into RAM at 0, maps each `(base, size, device)` next to
+// it and runs the program to its end. `setup` gets the processor first, to
+// hook up interrupt sources or a time source.
+pub fn run_with_devices(
+    source: &str,
+    devices: Vec<(u32, u32, Box<dyn Device>)>,
+    setup: impl FnOnce(&mut Rv32iProcessor),
+) -> (Rv32iProcessor, RunSummary) {
+    let program = asm::assemble(source).unwrap();
+    let mut bus = SystemBus::new();
+    bus.map_ram(Ram::from_bytes(0, program.bytes)).unwrap();
+    for (base, size, device) in devices {
+        bus.map_device(base, size, device).unwrap();
+    }
+    let mut processor = Rv32iProcessor::with_bus(Box::new(bus));
+    setup(&mut processor);
+    let summary = processor.run(&StopConditions::default());
+    (processor, summary)
+}
@@ -0,0 +1,256 @@
+// Instruction commit log in the format of Spike's `-l --log-commits`, to
+// diff runs against Spike or other simulators producing the same log:
+//
+//   core   0: 0x00000000 (0x00001117) auipc   sp, 0x1
+//   core   0: 3 0x00000000 (0x00001117) x2  0x00001000
+//
+// The first line is the disassembly, the second one lists what the
+// instruction committed: the destination register, CSR writes as
+// `c<number>_<name>`, and the memory access (`mem addr` for loads,
+// `mem addr value` for stores). Exceptions replace the commit line with
+// Spike's `exception` and `tval` lines.
+
+use crate::modules::disasm;
+use crate::modules::rv32i_trap::{Interrupt, TrapCause};
+
+use std::io::Write;
+
+// Machine mode, the only privilege level
+const PRIVILEGE: u32 = 3;
+
+pub struct CommitLog {
+    out: Box<dyn Write>,
+    pub hart: u32,
+    // Print the disassembly line before each commit line
+    pub disassembly: bool,
+    pending: Commit,
+}
+
+// What the instruction being executed has written so far.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+struct Commit {
+    register: Option<(u8, u32)>,
+    csrs: Vec<(u16, u32)>,
+    // Address, and the value and width in bytes for stores
+    memory: Option<(u32, Option<(u32, u32)>)>,
+}
+
+impl CommitLog {
+    pub fn new(out: Box<dyn Write>) -> Self {
+        Self {
+            out,
+            hart: 0,
+            disassembly: true,
+            pending: Commit::default(),
+        }
+    }
+
+    pub(crate) fn begin(&mut self) {
+        self.pending = Commit::default();
+    }
+
+    pub(crate) fn register_write(&mut self, rd: u8, value: u32) {
+        self.pending.register = Some((rd, value));
+    }
+
+    pub(crate) fn csr_write(&mut self, addr: u16, value: u32) {
+        self.pending.csrs.push((addr, value));
+    }
+
+    pub(crate) fn load(&mut self, addr: u32) {
+        self.pending.memory = Some((addr, None));
+    }
+
+    pub(crate) fn store(&mut self, addr: u32, value: u32, width: u32) {
+        self.pending.memory = Some((addr, Some((value, width))));
+    }
+
+    // Logs a retired instruction. A broken trace sink shouldn't stop the
+    // simulation, so write errors are ignored.
+    pub(crate) fn retire(&mut self, pc: u32, instruction: u32) {
+        self.disassemble(pc, instruction);
+
+        let mut line = format!(
+            "core {:>3}: {} {:#010x} ({:#010x})",
+            self.hart, PRIVILEGE, pc, instruction
+        );
+        if let Some((rd, value)) = self.pending.register {
+            line.push_str(&format!(" {:<3} {:#010x}", format!("x{}", rd), value));
+        }
+        for (addr, value) in &self.pending.csrs {
+            let name = disasm::csr_name(*addr).unwrap_or("unknown");
+            line.push_str(&format!(" c{}_{} {:#010x}", addr, name, value));
+        }
+        match self.pending.memory {
+            Some((addr, None)) => line.push_str(&format!(" mem {:#010x}", addr)),
+            Some((addr, Some((value, width)))) => line.push_str(&format!(
+                " mem {:#010x} 0x{:0digits$x}",
+                addr,
+                value,
+                digits = width as usize * 2
+            )),
+            None => {}
+        }
+        let _ = writeln!(self.out, "{}", line);
+    }
+
+    // Logs an instruction that raised an exception instead of retiring.
+    // `instruction` is None when it couldn't even be fetched.
+    pub(crate) fn exception(
+        &mut self,
+        pc: u32,
+        instruction: Option<u32>,
+        cause: TrapCause,
+        tval: u32,
+    ) {
+        if let Some(instruction) = instruction {
+            self.disassemble(pc, instruction);
+        }
+        let _ = writeln!(
+            self.out,
+            "core {:>3}: exception {}, epc {:#010x}",
+            self.hart,
+            trap_name(cause),
+            pc
+        );
+        let _ = writeln!(
+            self.out,
+            "core {:>3}:           tval {:#010x}",
+            self.hart, tval
+        );
+    }
+
+    // Logs an interrupt taken before the instruction at `pc`. Interrupts
+    // have no tval, so Spike prints a single line.
+    pub(crate) fn interrupt(&mut self, pc: u32, interrupt: Interrupt) {
+        let _ = writeln!(
+            self.out,
+            "core {:>3}: exception interrupt #{}, epc {:#010x}",
+            self.hart,
+            interrupt.code(),
+            pc
+        );
+    }
+
+    fn disassemble(&mut self, pc: u32, instruction: u32) {
+        if !self.disassembly {
+            return;
+        }
+        // Spike pads the mnemonic to 8 columns
+        let text = disasm::disassemble(instruction, pc);
+        let text = match text.split_once(' ') {
+            Some((mnemonic, operands)) => format!("{:<8}{}", mnemonic, operands),
+            None => text,
+        };
+        let _ = writeln!(
+            self.out,
+            "core {:>3}: {:#010x} ({:#010x}) {}",
+            self.hart, pc, instruction, text
+        );
+    }
+}
+
+fn trap_name(cause: TrapCause) -> &'static str {
+    match cause {
+        TrapCause::InstructionMisaligned => "trap_instruction_address_misaligned",
+        TrapCause::InstructionAccessFault => "trap_instruction_access_fault",
+        TrapCause::IllegalInstruction => "trap_illegal_instruction",
+        TrapCause::Breakpoint => "trap_breakpoint",
+        TrapCause::LoadMisaligned => "trap_load_address_misaligned",
+        TrapCause::LoadAccessFault => "trap_load_access_fault",
+        TrapCause::StoreMisaligned => "trap_store_address_misaligned",
+        TrapCause::StoreAccessFault => "trap_store_access_fault",
+        TrapCause::EcallFromM => "trap_machine_ecall",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::asm;
+    use crate::modules::rv32i_processor::Rv32iProcessor;
+    use crate::modules::test_utils::SharedBuffer;
+
+    fn traced(source: &str, steps: usize, trap_exceptions: bool) -> String {
+        let program = asm::assemble(source).unwrap();
+        let mut processor = Rv32iProcessor::new(program.words(), vec![0; 64]);
+        processor.trap_exceptions = trap_exceptions;
+        let buffer = SharedBuffer::default();
+        processor.tracer = Some(CommitLog::new(Box::new(buffer.clone())));
+
+        for _ in 0..steps {
+            let _ = processor.step();
+        }
+        let log = buffer.0.borrow().clone();
+        String::from_utf8(log).unwrap()
+    }
+
+    #[test]
+    fn test_commits() {
+        let log = traced(
+            "
+                li sp, 0x100
+                sw sp, 4(sp)
+                sb sp, 8(sp)
+                lw a0, 4(sp)
+                csrw mscratch, a0
+                beqz zero, 4
+                .word 0xffffffff
+            ",
+            7,
+            false,
+        );
+        assert_eq!(
+            log,
+            "\
+core   0: 0x00000000 (0x10000113) li      sp, 256
+core   0: 3 0x00000000 (0x10000113) x2  0x00000100
+core   0: 0x00000004 (0x00212223) sw      sp, 4(sp)
+core   0: 3 0x00000004 (0x00212223) mem 0x00000104 0x00000100
+core   0: 0x00000008 (0x00210423) sb      sp, 8(sp)
+core   0: 3 0x00000008 (0x00210423) mem 0x00000108 0x00
+core   0: 0x0000000c (0x00412503) lw      a0, 4(sp)
+core   0: 3 0x0000000c (0x00412503) x10 0x00000100 mem 0x00000104
+core   0: 0x00000010 (0x34051073) csrw    mscratch, a0
+core   0: 3 0x00000010 (0x34051073) c832_mscratch 0x00000100
+core   0: 0x00000014 (0x00000263) beqz    zero, 0x18
+core   0: 3 0x00000014 (0x00000263)
+core   0: 0x00000018 (0xffffffff) .word   0xffffffff
+core   0: exception trap_illegal_instruction, epc 0x00000018
+core   0:           tval 0xffffffff
+"
+        );
+    }
+
+    #[test]
+    fn test_traps() {
+        let log = traced(
+            "
+                la t0, handler
+                csrw mtvec, t0
+                ecall
+            handler:
+                li t1, 0x4000
+                jr t1
+            ",
+            8,
+            true,
+        );
+        let lines: Vec<&str> = log.lines().collect();
+
+        assert_eq!(lines[6], "core   0: 0x0000000c (0x00000073) ecall");
+        assert_eq!(
+            lines[7],
+            "core   0: exception trap_machine_ecall, epc 0x0000000c"
+        );
+        assert_eq!(lines[8], "core   0:           tval 0x00000000");
+        // The fetch fault at 0x4000 has no instruction to disassemble
+        assert_eq!(
+            &lines[15..],
+            &[
+                "core   0: exception trap_instruction_access_fault, epc 0x00004000",
+                "core   0:           tval 0x00004000",
+            ]
+        );
+    }
+}
@@ -0,0 +1,338 @@
+// A 16550-compatible UART, the register subset drivers actually use, so
+// guests can print through the same code as on QEMU's `virt` board or most
+// SoCs. Transmitted bytes go straight to `output`; received ones come from a
+// scripted buffer and/or the host's stdin. There is no baud rate: the
+// divisor latch is kept but ignored and the transmitter is always empty.
+//
+//   0  RBR (read) / THR (write)      DLL when LCR.DLAB is set
+//   1  IER                           DLM when LCR.DLAB is set
+//   2  IIR (read) / FCR (write)
+//   3  LCR     4  MCR     5  LSR     6  MSR     7  SCR
+//
+// The registers are `1 << reg_shift` bytes apart.
+
+use crate::modules::plic::InterruptLine;
+use crate::modules::rv32i_bus::{AccessSize, BusError, Device};
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+// Where QEMU's `virt` machine has its first UART
+pub const UART_BASE: u32 = 0x1000_0000;
+// and the PLIC source it is wired to
+pub const UART_IRQ: u32 = 10;
+
+const RBR_THR: u32 = 0;
+const IER: u32 = 1;
+const IIR_FCR: u32 = 2;
+const LCR: u32 = 3;
+const MCR: u32 = 4;
+const LSR: u32 = 5;
+const MSR: u32 = 6;
+const SCR: u32 = 7;
+
+const IER_RX_AVAILABLE: u8 = 0x01;
+const IER_TX_EMPTY: u8 = 0x02;
+const IIR_NONE: u8 = 0x01;
+const IIR_TX_EMPTY: u8 = 0x02;
+const IIR_RX_AVAILABLE: u8 = 0x04;
+const IIR_FIFO_ENABLED: u8 = 0xC0;
+const FCR_ENABLE: u8 = 0x01;
+const FCR_CLEAR_RX: u8 = 0x02;
+const LCR_DLAB: u8 = 0x80;
+const LSR_DATA_READY: u8 = 0x01;
+const LSR_TX_EMPTY: u8 = 0x20;
+const LSR_TX_IDLE: u8 = 0x40;
+// CTS, DSR and DCD: a terminal is always connected
+const MSR_CONNECTED: u8 = 0xB0;
+
+pub struct Uart {
+    output: Box<dyn Write>,
+    rx: VecDeque<u8>,
+    stdin: Option<Receiver<u8>>,
+    reg_shift: u32,
+    ier: u8,
+    lcr: u8,
+    mcr: u8,
+    scr: u8,
+    divisor: u16,
+    fifo_enabled: bool,
+    // The "THR empty" interrupt is raised by enabling it or by a write to
+    // THR, and acknowledged by reading IIR
+    tx_empty_pending: bool,
+}
+
+impl Uart {
+    pub fn new(output: Box<dyn Write>) -> Self {
+        Self {
+            output,
+            rx: VecDeque::new(),
+            stdin: None,
+            reg_shift: 0,
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scr: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            tx_empty_pending: false,
+        }
+    }
+
+    // Output to the host's stdout, input from its stdin.
+    pub fn stdio() -> Self {
+        let mut uart = Self::new(Box::new(io::stdout()));
+        uart.connect_stdin();
+        uart
+    }
+
+    // E.g. 2 for SoCs that give each register its own 32-bit word.
+    pub fn with_reg_shift(mut self, reg_shift: u32) -> Self {
+        self.reg_shift = reg_shift;
+        self
+    }
+
+    // Bytes to map on the bus
+    pub fn size(&self) -> u32 {
+        8 << self.reg_shift
+    }
+
+    // Queues bytes for the guest to receive, after anything already queued.
+    pub fn push_input(&mut self, bytes: &[u8]) {
+        self.rx.extend(bytes);
+    }
+
+    // Forwards the host's stdin to the receiver. A thread does the blocking
+    // reads, so a guest polling LSR never stalls the emulator.
+    pub fn connect_stdin(&mut self) {
+        let (sender, receiver) = mpsc::channel();
+        thread::spawn(move || {
+            for byte in io::stdin().lock().bytes() {
+                match byte {
+                    Ok(byte) if sender.send(byte).is_ok() => {}
+                    _ => break,
+                }
+            }
+        });
+        self.stdin = Some(receiver);
+    }
+
+    // The interrupt output, for an interrupt controller to sample.
+    pub fn interrupt_pending(&mut self) -> bool {
+        self.interrupt_id() != IIR_NONE
+    }
+
+    fn interrupt_id(&mut self) -> u8 {
+        self.poll_stdin();
+        if self.ier & IER_RX_AVAILABLE != 0 && !self.rx.is_empty() {
+            IIR_RX_AVAILABLE
+        } else if self.ier & IER_TX_EMPTY != 0 && self.tx_empty_pending {
+            IIR_TX_EMPTY
+        } else {
+            IIR_NONE
+        }
+    }
+
+    fn poll_stdin(&mut self) {
+        if let Some(stdin) = &self.stdin {
+            self.rx.extend(stdin.try_iter());
+        }
+    }
+
+    fn transmit(&mut self, byte: u8) {
+        send_to_host(&mut *self.output, &[byte]);
+        self.tx_empty_pending = true;
+    }
+
+    fn register(&self, offset: u32) -> Result<u32, BusError> {
+        if offset & ((1 << self.reg_shift) - 1) != 0 {
+            return Err(BusError::Device(offset));
+        }
+        Ok(offset >> self.reg_shift)
+    }
+}
+
+// Sends bytes from a UART to the host. A broken host sink (closed pipe,
+// full disk...) shouldn't stop the simulation, so errors are dropped.
+pub(crate) fn send_to_host(output: &mut dyn Write, bytes: &[u8]) {
+    let _ = output.write_all(bytes).and_then(|_| output.flush());
+}
+
+impl Device for Uart {
+    fn read(&mut self, offset: u32, _size: AccessSize) -> Result<u32, BusError> {
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let value = match self.register(offset)? {
+            RBR_THR if dlab => self.divisor as u8,
+            RBR_THR => {
+                self.poll_stdin();
+                self.rx.pop_front().unwrap_or(0)
+            }
+            IER if dlab => (self.divisor >> 8) as u8,
+            IER => self.ier,
+            IIR_FCR => {
+                let id = self.interrupt_id();
+                if id == IIR_TX_EMPTY {
+                    self.tx_empty_pending = false;
+                }
+                let fifo = if self.fifo_enabled {
+                    IIR_FIFO_ENABLED
+                } else {
+                    0
+                };
+                id | fifo
+            }
+            LCR => self.lcr,
+            MCR => self.mcr,
+            LSR => {
+                self.poll_stdin();
+                let ready = if self.rx.is_empty() {
+                    0
+                } else {
+                    LSR_DATA_READY
+                };
+                ready | LSR_TX_EMPTY | LSR_TX_IDLE
+            }
+            MSR => MSR_CONNECTED,
+            SCR => self.scr,
+            _ => return Err(BusError::Device(offset)),
+        };
+        Ok(value as u32)
+    }
+
+    fn write(&mut self, offset: u32, _size: AccessSize, value: u32) -> Result<(), BusError> {
+        let value = value as u8;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        match self.register(offset)? {
+            RBR_THR if dlab => self.divisor = self.divisor & 0xFF00 | value as u16,
+            RBR_THR => self.transmit(value),
+            IER if dlab => self.divisor = self.divisor & 0x00FF | (value as u16) << 8,
+            IER => {
+                if value & !self.ier & IER_TX_EMPTY != 0 {
+                    self.tx_empty_pending = true;
+                }
+                self.ier = value & 0x0F;
+            }
+            IIR_FCR => {
+                self.fifo_enabled = value & FCR_ENABLE != 0;
+                if value & FCR_CLEAR_RX != 0 {
+                    self.poll_stdin();
+                    self.rx.clear();
+                }
+            }
+            LCR => self.lcr = value,
+            MCR => self.mcr = value,
+            // LSR and MSR are read-only, writes are ignored like on the chip
+            LSR | MSR => {}
+            SCR => self.scr = value,
+            _ => return Err(BusError::Device(offset)),
+        }
+        Ok(())
+    }
+}
+
+impl InterruptLine for Uart {
+    fn level(&mut self) -> bool {
+        self.interrupt_pending()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::rv32i_processor::StopReason;
+    use crate::modules::test_utils::{run_with_devices, SharedBuffer};
+
+    #[test]
+    fn test_registers() {
+        let output = SharedBuffer::default();
+        let mut uart = Uart::new(Box::new(output.clone()));
+        let byte = AccessSize::Byte;
+
+        assert_eq!(uart.read(LSR, byte), Ok(0x60));
+        uart.write(RBR_THR, byte, b'A' as u32).unwrap();
+        assert_eq!(*output.0.borrow(), b"A");
+
+        // The divisor latch shadows RBR/THR and IER
+        uart.write(LCR, byte, 0x83).unwrap();
+        uart.write(RBR_THR, byte, 0x01).unwrap();
+        uart.write(IER, byte, 0x02).unwrap();
+        assert_eq!(uart.read(RBR_THR, byte), Ok(0x01));
+        uart.write(LCR, byte, 0x03).unwrap();
+        assert_eq!(uart.read(IER, byte), Ok(0));
+        assert_eq!(*output.0.borrow(), b"A");
+
+        uart.push_input(b"hi");
+        assert_eq!(uart.read(LSR, byte), Ok(0x61));
+        assert_eq!(uart.read(RBR_THR, byte), Ok(b'h' as u32));
+        uart.write(IIR_FCR, byte, 0x03).unwrap();
+        assert_eq!(uart.read(LSR, byte), Ok(0x60));
+        assert_eq!(uart.read(IIR_FCR, byte), Ok(0xC1));
+
+        uart.write(SCR, byte, 0x5A).unwrap();
+        assert_eq!(uart.read(SCR, byte), Ok(0x5A));
+        assert_eq!(uart.read(8, byte), Err(BusError::Device(8)));
+    }
+
+    #[test]
+    fn test_interrupts() {
+        let mut uart = Uart::new(Box::new(io::sink()));
+        let byte = AccessSize::Byte;
+        uart.push_input(b"x");
+        assert!(!uart.interrupt_pending());
+
+        uart.write(IER, byte, (IER_RX_AVAILABLE | IER_TX_EMPTY) as u32)
+            .unwrap();
+        // Received data has priority over the empty transmitter
+        assert_eq!(uart.read(IIR_FCR, byte), Ok(IIR_RX_AVAILABLE as u32));
+        uart.read(RBR_THR, byte).unwrap();
+        assert_eq!(uart.read(IIR_FCR, byte), Ok(IIR_TX_EMPTY as u32));
+        // Reading IIR acknowledged it, until the next transmitted byte
+        assert!(!uart.interrupt_pending());
+        uart.write(RBR_THR, byte, b'y' as u32).unwrap();
+        assert!(uart.interrupt_pending());
+    }
+
+    #[test]
+    fn test_reg_shift() {
+        let mut uart = Uart::new(Box::new(io::sink())).with_reg_shift(2);
+        assert_eq!(uart.size(), 32);
+        uart.write(SCR << 2, AccessSize::Word, 0x12).unwrap();
+        assert_eq!(uart.read(SCR << 2, AccessSize::Word), Ok(0x12));
+        assert_eq!(uart.read(1, AccessSize::Byte), Err(BusError::Device(1)));
+    }
+
+    #[test]
+    fn test_echo_program() {
+        // Echoes every received byte until a newline, then exits
+        let output = SharedBuffer::default();
+        let mut uart = Uart::new(Box::new(output.clone()));
+        uart.push_input(b"hello\n");
+
+        let (_, summary) = run_with_devices(
+            "
+                li s0, 0x10000000
+            wait:
+                lbu t0, 5(s0)
+                andi t0, t0, 1
+                beqz t0, wait
+                lbu a0, 0(s0)
+            busy:
+                lbu t0, 5(s0)
+                andi t0, t0, 0x20
+                beqz t0, busy
+                sb a0, 0(s0)
+                li t1, 10
+                bne a0, t1, wait
+                li a0, 0
+                li a7, 93
+                ecall
+            ",
+            vec![(UART_BASE, 8, Box::new(uart))],
+            |_| {},
+        );
+        assert_eq!(summary.reason, StopReason::Exit(0));
+        assert_eq!(*output.0.borrow(), b"hello\n");
+    }
+}